@@ -8,13 +8,22 @@ extern crate soundio;
 use std::collections::HashMap;
 use analysis::traits::Sourcable;
 use analysis::traits::Chainable;
+use analysis::graph::{Graph, GraphError};
+use analysis::stream::{StreamFormat, BlockContext};
+use analysis::reframer::Reframer;
 
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+// Chains are numbered so that sources can tell the chains attached to them apart.
+static CREATED_CHAINS: AtomicUsize = AtomicUsize::new(0);
 
 pub struct Arena {
-    pub sourcables: HashMap<u64, Arc<RwLock<Sourcable>>>,
-    pub chainables: HashMap<u64, Arc<RwLock<Chainable>>>,
+    pub sourcables: HashMap<u64, Arc<RwLock<dyn Sourcable>>>,
+    pub chainables: HashMap<u64, Arc<RwLock<dyn Chainable>>>,
 
     created_nodes: u64,
 }
@@ -28,23 +37,22 @@ impl Arena {
         }
     }
 
-    pub fn add_sourcable(&mut self, sourcable: Arc<RwLock<Sourcable>>) -> u64 {
+    pub fn add_sourcable(&mut self, sourcable: Arc<RwLock<dyn Sourcable>>) -> u64 {
         let id = self.created_nodes;
 
-        self.sourcables.clear();
         self.sourcables.insert(id, sourcable);
         self.created_nodes += 1;
 
-        return id;
+        id
     }
 
-    pub fn add_chainable(&mut self, chainable: Arc<RwLock<Chainable>>) -> u64 {
+    pub fn add_chainable(&mut self, chainable: Arc<RwLock<dyn Chainable>>) -> u64 {
         let id = self.created_nodes;
 
         self.chainables.insert(id, chainable);
         self.created_nodes += 1;
 
-        return id;
+        id
     }
 
     pub fn remove_sourcable(&mut self, id: u64) {
@@ -56,11 +64,134 @@ impl Arena {
     }
 }
 
+impl Default for Arena {
+    fn default() -> Arena {
+        Arena::new()
+    }
+}
+
+/// Chains attached to a source. Clones share the same chains, so a source can hand a clone to its audio callback
+/// and still attach and detach chains while the callback is running.
+#[derive(Clone)]
+pub struct Chains {
+    chains: Arc<RwLock<HashMap<u64, Arc<RwLock<Chain>>>>>,
+    // Format of the source's audio and the position of the next block in frames.
+    stream: Arc<RwLock<(Option<StreamFormat>, u64)>>,
+}
+
+impl Chains {
+    pub fn new() -> Chains {
+        Chains {
+            chains: Arc::new(RwLock::new(HashMap::new())),
+            stream: Arc::new(RwLock::new((None, 0))),
+        }
+    }
+
+    /// Sources call this when they start, before passing on any audio. Also starts counting frames over.
+    pub fn set_format(&self, format: StreamFormat) {
+        *self.stream.write().unwrap() = (Some(format), 0);
+    }
+
+    pub fn format(&self) -> Option<StreamFormat> {
+        self.stream.read().unwrap().0
+    }
+
+    /// For sources that don't start from the beginning, like a file resuming where it was paused.
+    pub fn set_position(&self, frame: u64) {
+        self.stream.write().unwrap().1 = frame;
+    }
+
+    // Context for the next block, moving the position past it.
+    fn next_context(&self, buffer: &[Vec<f32>], frames: usize, timestamp: Instant) -> BlockContext {
+        let frames = match buffer.first()
+        {
+            Some(channel) => channel.len(),
+            None => frames,
+        };
+
+        let mut stream = self.stream.write().unwrap();
+        let sample_rate = stream.0.map(|format| format.sample_rate).unwrap_or(0.0);
+        let mut context = BlockContext::new(sample_rate, stream.1);
+        context.timestamp = timestamp;
+        stream.1 += frames as u64;
+        context
+    }
+
+    pub fn attach(&self, chain_id: u64, chain: Arc<RwLock<Chain>>) {
+        self.chains.write().unwrap().insert(chain_id, chain);
+    }
+
+    pub fn detach(&self, chain_id: u64) {
+        self.chains.write().unwrap().remove(&chain_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.chains.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Copy of the attached chains, so that the lock isn't held while the chains run.
+    // Otherwise a chain being started, which holds its own lock while attaching, could deadlock with the callback.
+    fn snapshot(&self) -> Vec<Arc<RwLock<Chain>>> {
+        self.chains.read().unwrap().values().cloned().collect()
+    }
+
+    /// Passes a block of audio to every attached chain, timestamped now.
+    pub fn source_cb(&self, buffer: Vec<Vec<f32>>, frames: usize) {
+        self.source_cb_at(buffer, frames, Instant::now());
+    }
+
+    /// Like `source_cb`, for audio that arrived earlier, e.g. in an audio callback, and is only now passed on.
+    pub fn source_cb_at(&self, buffer: Vec<Vec<f32>>, frames: usize, timestamp: Instant) {
+        let context = self.next_context(&buffer, frames, timestamp);
+        for chain in self.snapshot()
+        {
+            chain.read().unwrap().source_cb(buffer.clone(), &context);
+        }
+    }
+
+    /// Like `source_cb`, but skips chains that are locked instead of waiting for them.
+    /// For audio callbacks that shouldn't block.
+    pub fn try_source_cb(&self, buffer: Vec<Vec<f32>>, frames: usize) {
+        let context = self.next_context(&buffer, frames, Instant::now());
+        for chain in self.snapshot()
+        {
+            match chain.try_read() {
+                Ok(lock) => lock.source_cb(buffer.clone(), &context),
+                Err(e) => println!("Error reading chain: {:?}", e)
+            }
+        }
+    }
+}
+
+impl Default for Chains {
+    fn default() -> Chains {
+        Chains::new()
+    }
+}
+
+// Signals a node passed on during a block, each with the context of the audio it came from.
+type Signals = Vec<(Vec<Vec<f32>>, BlockContext)>;
+
 pub struct Chain {
+    id: u64,
     arena: Arc<RwLock<Arena>>,
 
     source: Option<u64>,
-    nodes: Vec<u64>,
+    graph: Graph,
+    // Order the nodes are run in, kept up to date as nodes are added and connected.
+    order: Vec<u64>,
+    // Latest processor added with add_node, which the next node added with add_node reads from.
+    last_processor: Option<u64>,
+    // Audio waiting to be framed for the nodes that want fixed-size frames.
+    reframers: Mutex<HashMap<u64, Reframer>>,
+    // Update counts of the analyzers' outputs when they were last passed on, so that each output is passed on once.
+    passed_updates: Mutex<HashMap<u64, u64>>,
+    // Latest signal each node passed on, given again to nodes with several inputs for the inputs with nothing new.
+    held: Mutex<HashMap<u64, Vec<Vec<f32>>>>,
 
     pub running: bool,
 }
@@ -68,56 +199,368 @@ pub struct Chain {
 impl Chain {
     pub fn new(arena: Arc<RwLock<Arena>>) -> Chain {
         Chain {
-            arena: arena,
+            id: CREATED_CHAINS.fetch_add(1, Ordering::SeqCst) as u64,
+            arena,
 
             source: Option::None,
-            nodes: Vec::new(),
+            graph: Graph::new(),
+            order: Vec::new(),
+            last_processor: Option::None,
+            reframers: Mutex::new(HashMap::new()),
+            passed_updates: Mutex::new(HashMap::new()),
+            held: Mutex::new(HashMap::new()),
 
             running: false,
         }
     }
 
 
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Attaches the chain to its source, starting the source if it isn't running yet.
+    /// Once the source knows its format, the nodes are prepared for it.
     pub fn start(&mut self, self_ref: Arc<RwLock<Chain>>) {
+        if let Err(e) = self.validate()
+        {
+            println!("Not starting chain: {}", e);
+            return;
+        }
+
         match self.source {
             Some(source) =>
             {
                 let arena_borrow = self.arena.read().unwrap();
-                arena_borrow.sourcables[&source].write().unwrap().start(self_ref);
+                let mut sourcable = arena_borrow.sourcables[&source].write().unwrap();
+                sourcable.attach(self.id, self_ref);
+                if !sourcable.is_active()
+                {
+                    sourcable.start();
+                }
+
+                match sourcable.format()
+                {
+                    Some(format) => self.prepare(&arena_borrow, format),
+                    None => println!("Source {} has no format, nodes not prepared.", source),
+                }
                 self.running = true;
             },
             None => println!("No sourcable set."),
         }
     }
 
+    /// Detaches the chain from its source, stopping the source if no other chain is using it.
     pub fn stop(&mut self) {
         self.running = false;
         match self.source {
             Some(source) =>
             {
                 let arena_borrow = self.arena.read().unwrap();
-                arena_borrow.sourcables[&source].write().unwrap().stop();
-                self.running = true;
+                match arena_borrow.sourcables.get(&source)
+                {
+                    Some(sourcable) => {
+                        let mut sourcable = sourcable.write().unwrap();
+                        sourcable.detach(self.id);
+                        if sourcable.chains().is_empty()
+                        {
+                            sourcable.stop();
+                        }
+                    },
+                    None => println!("Sourcable {} no longer exists.", source),
+                }
             },
             None => println!("No sourcable set for chain."),
         }
     }
 
-    pub fn source_cb(&self, buffer: Vec<Vec<f32>>, _frames: usize) {
-        if self.running == true
+    // Tells each node the format of the audio it's going to get.
+    // Nodes are assumed to keep the amount of channels, as processors and analyzers with per-channel output do.
+    fn prepare(&self, arena: &Arena, format: StreamFormat) {
+        self.reframers.lock().unwrap().clear();
+        self.passed_updates.lock().unwrap().clear();
+        self.held.lock().unwrap().clear();
+        let mut channels: HashMap<u64, usize> = HashMap::new();
+
+        for &id in self.order.iter() {
+            let mut node = match arena.chainables.get(&id)
+            {
+                Some(node) => node.write().unwrap(),
+                None => continue,
+            };
+
+            let inputs = self.graph.inputs(id);
+            let input_channels = if inputs.is_empty()
+            {
+                format.channels
+            }
+            else
+            {
+                inputs.iter().map(|input| channels.get(input).cloned().unwrap_or(0)).sum()
+            };
+
+            node.prepare(&StreamFormat::new(format.sample_rate, input_channels));
+
+            channels.insert(id, input_channels);
+        }
+    }
+
+    pub fn source_cb(&self, buffer: Vec<Vec<f32>>, context: &BlockContext) {
+        if self.running
         {
-            for i in 0..self.nodes.len() {
-                let node = &self.arena.read().unwrap().chainables[&self.nodes[i]];
-                node.write().unwrap().update(&buffer);
+            let arena_borrow = self.arena.read().unwrap();
+
+            // What each node passes on to the nodes connected to it.
+            // Processors pass on their audio, analyzers their output as channels, see `Output::signal`.
+            // Analyzers only pass on output they haven't passed on yet, so that the nodes after them don't get e.g.
+            // the same spectrum for every block until the next frame is transformed.
+            // A node that runs on frames passes on what it has after each frame, so a block can give several signals,
+            // each with the context of the audio it came from. The nodes after it run once for each, in order.
+            // A node runs when one of its inputs passed on something new. Its other inputs give their latest signal
+            // again, so that each input keeps its place, and until every input passed something on the node waits.
+            let mut held = self.held.lock().unwrap();
+            let mut passed: HashMap<u64, Signals> = HashMap::new();
+
+            for &id in self.order.iter() {
+                let mut node = match arena_borrow.chainables.get(&id)
+                {
+                    Some(node) => node.write().unwrap(),
+                    None => continue,
+                };
+
+                let inputs = self.graph.inputs(id);
+                let steps = if inputs.is_empty()
+                {
+                    1
+                }
+                else
+                {
+                    inputs.iter().map(|input| passed.get(input).map_or(0, |signals| signals.len())).max().unwrap_or(0)
+                };
+                let has_outputs = self.graph.has_outputs(id);
+                let mut signals = Vec::new();
+
+                for step in 0..steps
+                {
+                    let (step_inputs, step_context) = if inputs.is_empty()
+                    {
+                        (vec![&buffer], *context)
+                    }
+                    else
+                    {
+                        // The timing comes from the input with the most signals, so that it only moves forward.
+                        let step_context = inputs.iter().filter_map(|input| passed.get(input))
+                            .find(|input_signals| input_signals.len() == steps)
+                            .map_or(*context, |input_signals| input_signals[step].1);
+
+                        let mut step_inputs = Vec::with_capacity(inputs.len());
+                        for input in inputs.iter()
+                        {
+                            match passed.get(input)
+                            {
+                                // Inputs that passed on fewer signals give their last one for the steps after it.
+                                Some(input_signals) => step_inputs.push(&input_signals[step.min(input_signals.len() - 1)].0),
+                                None => if let Some(signal) = held.get(input) { step_inputs.push(signal) },
+                            }
+                        }
+                        if step_inputs.len() < inputs.len()
+                        {
+                            continue;
+                        }
+                        (step_inputs, step_context)
+                    };
+
+                    if let Some((size, hop)) = node.framing()
+                    {
+                        // The channels of all inputs are framed together, so that the frames of each input line up.
+                        let merged: Vec<Vec<f32>> = step_inputs.iter().flat_map(|input| input.iter().cloned()).collect();
+                        let channel_counts: Vec<usize> = step_inputs.iter().map(|input| input.len()).collect();
+
+                        let frames = {
+                            let mut reframers = self.reframers.lock().unwrap();
+                            let stale = match reframers.get(&id)
+                            {
+                                Some(reframer) => reframer.size() != size || reframer.hop() != hop,
+                                None => true,
+                            };
+                            if stale
+                            {
+                                match Reframer::new(size, hop)
+                                {
+                                    Ok(reframer) => { reframers.insert(id, reframer); },
+                                    Err(e) => {
+                                        println!("Can't frame the audio of node {}: {}", id, e);
+                                        continue;
+                                    }
+                                }
+                            }
+                            reframers.get_mut(&id).unwrap().push(&merged, &step_context)
+                        };
+
+                        // Until the node gets a whole frame, it has nothing new to pass on.
+                        for (frame, frame_context) in frames.iter()
+                        {
+                            if inputs.len() > 1
+                            {
+                                let mut split = Vec::new();
+                                let mut start = 0;
+                                for &count in channel_counts.iter()
+                                {
+                                    split.push(frame[start..start + count].to_vec());
+                                    start += count;
+                                }
+                                node.update_inputs(&split, frame_context);
+                            }
+                            else
+                            {
+                                node.process(frame, frame_context);
+                            }
+
+                            if has_outputs
+                            {
+                                if let Some(signal) = self.new_signal(id, &*node)
+                                {
+                                    signals.push((signal, *frame_context));
+                                }
+                            }
+                        }
+                        continue;
+                    }
+
+                    if inputs.len() > 1
+                    {
+                        let split: Vec<Vec<Vec<f32>>> = step_inputs.iter().map(|&input| input.clone()).collect();
+                        node.update_inputs(&split, &step_context);
+                    }
+                    else
+                    {
+                        node.process(step_inputs[0], &step_context);
+                    }
+
+                    if has_outputs
+                    {
+                        if let Some(signal) = self.new_signal(id, &*node)
+                        {
+                            signals.push((signal, step_context));
+                        }
+                    }
+                }
+
+                if let Some((signal, _)) = signals.last()
+                {
+                    held.insert(id, signal.clone());
+                }
+                if !signals.is_empty()
+                {
+                    passed.insert(id, signals);
+                }
             }
         }
     }
 
+    // What a node that just ran passes on, or None for an analyzer whose output it has already passed on.
+    fn new_signal(&self, id: u64, node: &dyn Chainable) -> Option<Vec<Vec<f32>>> {
+        match node.processed()
+        {
+            Some(processed) => Some(processed.clone()),
+            None => {
+                let updates = node.output().updates();
+                if self.passed_updates.lock().unwrap().insert(id, updates) == Some(updates)
+                {
+                    return None;
+                }
+                Some(node.output().signal())
+            },
+        }
+    }
+
     pub fn set_source(&mut self, source: u64) {
         self.source = Option::Some(source);
     }
 
+    /// Adds a node to the end of the chain.
+    /// It gets the audio of the latest processor added this way, or the source's audio if there's none.
     pub fn add_node(&mut self, node: u64) {
-        self.nodes.push(node);
+        let is_processor = match self.arena.read().unwrap().chainables.get(&node)
+        {
+            Some(chainable) => chainable.read().unwrap().processed().is_some(),
+            None => false,
+        };
+
+        let result = match self.last_processor
+        {
+            Some(last) => self.add_connected_node(node, vec![last]),
+            None => self.add_connected_node(node, Vec::new()),
+        };
+
+        match result
+        {
+            Ok(()) => if is_processor { self.last_processor = Some(node) },
+            Err(e) => println!("Could not add node: {}", e),
+        }
     }
-}
+
+    /// Adds a node that reads from the given nodes, or from the source if `inputs` is empty.
+    /// With several inputs, the node gets all of them through `Chainable::update_inputs`.
+    pub fn add_connected_node(&mut self, node: u64, inputs: Vec<u64>) -> Result<(), GraphError> {
+        if !self.arena.read().unwrap().chainables.contains_key(&node)
+        {
+            return Err(GraphError::UnknownNode(node));
+        }
+
+        self.graph.add_node(node)?;
+        for input in inputs
+        {
+            if let Err(e) = self.graph.connect(input, node)
+            {
+                self.graph.remove_node(node);
+                return Err(e);
+            }
+        }
+
+        self.update_order()
+    }
+
+    /// Connects two nodes already in the chain, making `from` an input of `to`.
+    pub fn connect(&mut self, from: u64, to: u64) -> Result<(), GraphError> {
+        self.graph.connect(from, to)?;
+        self.update_order()
+    }
+
+    pub fn disconnect(&mut self, from: u64, to: u64) -> Result<(), GraphError> {
+        self.graph.disconnect(from, to);
+        self.update_order()
+    }
+
+    pub fn remove_node(&mut self, node: u64) -> Result<(), GraphError> {
+        self.graph.remove_node(node);
+        if self.last_processor == Some(node)
+        {
+            self.last_processor = None;
+        }
+        self.update_order()
+    }
+
+    pub fn graph(&self) -> &Graph {
+        &self.graph
+    }
+
+    /// Checks that all the nodes are still in the arena and the graph has no cycles.
+    pub fn validate(&self) -> Result<(), GraphError> {
+        let arena_borrow = self.arena.read().unwrap();
+        for &node in self.graph.nodes()
+        {
+            if !arena_borrow.chainables.contains_key(&node)
+            {
+                return Err(GraphError::UnknownNode(node));
+            }
+        }
+
+        self.graph.order().map(|_| ())
+    }
+
+    fn update_order(&mut self) -> Result<(), GraphError> {
+        self.order = self.graph.order()?;
+        Ok(())
+    }
+}
\ No newline at end of file
@@ -0,0 +1,467 @@
+use analysis::traits::Chainable;
+use analysis::parameters::{Parameters, ParameterError, ParameterValue};
+use analysis::output::{Output, Value};
+use analysis::stream::{StreamFormat, BlockContext};
+use analysis::onset::Onset;
+use analysis::interpolation::parabolic;
+
+use std::collections::VecDeque;
+
+// How much of the cumulative score comes from the past beats rather than the latest frame, Ellis 2007.
+const SCORE_MEMORY: f32 = 0.9;
+// How strictly past beats must be a beat period apart to count towards the score.
+const TIGHTNESS: f32 = 5.0;
+// Width of the preference for tempos around the preferred one, in octaves.
+const TEMPO_PRIOR_OCTAVES: f32 = 1.0;
+// Most beats kept for `take_beats`, so that they don't pile up if nobody takes them.
+const MAX_TAKEN_BEATS: usize = 1024;
+
+/// A beat.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BeatEvent {
+    /// Stream position of the start of the frame the beat fell on.
+    pub frame: u64,
+    /// The same in seconds from when the source started.
+    pub time: f64,
+    /// Tempo at the beat, in beats per minute.
+    pub bpm: f32,
+    /// How sure the tracker was of the tempo, from 0.0 to 1.0.
+    pub confidence: f32,
+    /// How far the beat fell from a beat period after the previous one, as a fraction of a beat.
+    /// Negative if it came early, positive if late, 0.0 for the first beat.
+    pub phase: f32,
+    /// Number of the beat since the tracker started.
+    pub index: u64,
+}
+
+// Weight of a past beat `distance` frames back, when beats are `period` frames apart.
+fn transition_weight(distance: f32, period: f32) -> f32 {
+    let octaves = (distance / period).ln();
+    (-0.5 * (TIGHTNESS * octaves) * (TIGHTNESS * octaves)).exp()
+}
+
+/// Tempo and beats of the audio, on top of an `Onset` node's detection function.
+/// The tempo is the strongest periodicity of the detection function over the latest "window" milliseconds, and beats
+/// are predicted half a beat ahead from a cumulative score of the detection function, as in Stark et al. 2009.
+/// Output is "bpm", "confidence" of the tempo from 0 to 1, "phase", how far the latest frame is into the current beat
+/// from 0 to 1, "beat", 1 if a beat fell on the latest frame and 0 otherwise, and "last_beat", the time in seconds of
+/// the latest beat so far or -1 before the first. The beats themselves are in `beats` and `take_beats`.
+/// Parameters are "min_bpm" and "max_bpm", "preferred_bpm", the tempo favoured when several fit, "window" in
+/// milliseconds, at least two of the longest beat periods, and "smoothing", the time constant in milliseconds the
+/// tempo follows changes with. The onset detection is set up through `onset_mut`.
+pub struct Beat {
+    parameters: Parameters,
+    sample_rate: f32,
+
+    onset: Onset,
+
+    // Detection function and cumulative score of the latest frames, newest last.
+    detection: VecDeque<f32>,
+    score: VecDeque<f32>,
+    // Frames analysed since the start, and the stream position of the latest.
+    frames: u64,
+    latest_frame: u64,
+
+    // Beat period in frames, 0 until the tempo is known.
+    period: f32,
+    confidence: f32,
+    // Frame counts of the latest beat and the predicted next one.
+    last_beat: Option<u64>,
+    next_beat: Option<u64>,
+    beat_count: u64,
+    // Time of the latest beat in seconds.
+    last_beat_time: Option<f64>,
+
+    phase: f32,
+    beats: Vec<BeatEvent>,
+    taken_beats: Vec<BeatEvent>,
+    output: Output,
+}
+
+impl Beat {
+    pub fn new() -> Beat {
+        let mut parameters = Parameters::new();
+        parameters.add_float("min_bpm", 60.0, 20.0, 400.0);
+        parameters.add_float("max_bpm", 200.0, 20.0, 400.0);
+        parameters.add_float("preferred_bpm", 120.0, 20.0, 400.0);
+        parameters.add_float("window", 6000.0, 1000.0, 30_000.0);
+        parameters.add_float("smoothing", 1000.0, 0.0, 30_000.0);
+
+        let mut onset = Onset::new(1024);
+        onset.set_hop(512).unwrap();
+
+        Beat {
+            parameters,
+            sample_rate: 44_100.0,
+
+            onset,
+
+            detection: VecDeque::new(),
+            score: VecDeque::new(),
+            frames: 0,
+            latest_frame: 0,
+
+            period: 0.0,
+            confidence: 0.0,
+            last_beat: None,
+            next_beat: None,
+            beat_count: 0,
+            last_beat_time: None,
+
+            phase: 0.0,
+            beats: Vec::new(),
+            taken_beats: Vec::new(),
+            output: Output::new(),
+        }
+    }
+
+    /// Both are checked before either is set, so that an error leaves the range as it was.
+    pub fn set_range(&mut self, min_bpm: f32, max_bpm: f32) -> Result<(), ParameterError> {
+        self.parameters.check("min_bpm", ParameterValue::Float(min_bpm))?;
+        self.parameters.check("max_bpm", ParameterValue::Float(max_bpm))?;
+        self.parameters.set_float("min_bpm", min_bpm)?;
+        self.parameters.set_float("max_bpm", max_bpm)
+    }
+
+    pub fn set_preferred_bpm(&mut self, bpm: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("preferred_bpm", bpm)
+    }
+
+    /// How much of the latest audio the tempo is estimated from. Longer is steadier but slower to follow changes.
+    /// Windows shorter than two beats at the lowest tempo count as that long, as the tempo can't be told from less.
+    pub fn set_window_ms(&mut self, window: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("window", window)
+    }
+
+    pub fn set_smoothing_ms(&mut self, smoothing: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("smoothing", smoothing)
+    }
+
+    pub fn onset(&self) -> &Onset {
+        &self.onset
+    }
+
+    /// The onset detection the beats are tracked from, e.g. to change its detection function.
+    pub fn onset_mut(&mut self) -> &mut Onset {
+        &mut self.onset
+    }
+
+    /// Tempo in beats per minute, 0.0 until there's been enough audio to tell.
+    pub fn bpm(&self) -> f32 {
+        if self.period > 0.0 { 60.0 * self.frame_rate() / self.period } else { 0.0 }
+    }
+
+    pub fn confidence(&self) -> f32 {
+        self.confidence
+    }
+
+    /// How far the latest frame is into the current beat, from 0.0 at a beat to 1.0 at the next.
+    pub fn phase(&self) -> f32 {
+        self.phase
+    }
+
+    /// Beats in the latest update.
+    pub fn beats(&self) -> &Vec<BeatEvent> {
+        &self.beats
+    }
+
+    /// Beats since they were last taken, so that whoever polls the node doesn't miss beats between polls.
+    /// Only the latest 1024 are kept.
+    pub fn take_beats(&mut self) -> Vec<BeatEvent> {
+        self.taken_beats.drain(..).collect()
+    }
+
+    /// Time of the latest beat so far in seconds from when the source started, if there's been one.
+    pub fn last_beat_time(&self) -> Option<f64> {
+        self.last_beat_time
+    }
+
+    /// Time of the latest frame analysed in seconds from when the source started.
+    pub fn time(&self) -> f64 {
+        self.latest_frame as f64 / self.sample_rate as f64
+    }
+
+    /// Predicted time of the next beat in seconds from when the source started, if the tempo is known.
+    pub fn next_beat_time(&self) -> Option<f64> {
+        let latest = self.frames.saturating_sub(1);
+        self.next_beat.map(|next| {
+            let frame = self.latest_frame + (next - latest) * self.onset.hop() as u64;
+            frame as f64 / self.sample_rate as f64
+        })
+    }
+
+    // Analysis frames per second.
+    fn frame_rate(&self) -> f32 {
+        self.sample_rate / self.onset.hop() as f32
+    }
+
+    fn reset(&mut self) {
+        self.detection.clear();
+        self.score.clear();
+        self.frames = 0;
+        self.latest_frame = 0;
+        self.period = 0.0;
+        self.confidence = 0.0;
+        self.last_beat = None;
+        self.next_beat = None;
+        self.beat_count = 0;
+        self.last_beat_time = None;
+        self.phase = 0.0;
+        self.taken_beats = Vec::new();
+    }
+
+    // Shortest and longest beat periods looked for, in frames.
+    fn period_range(&self) -> (usize, usize) {
+        let min_bpm = self.parameters.get_float("min_bpm");
+        let max_bpm = self.parameters.get_float("max_bpm").max(min_bpm);
+        let min_period = (60.0 * self.frame_rate() / max_bpm).floor() as usize;
+        let max_period = (60.0 * self.frame_rate() / min_bpm).ceil() as usize;
+        (min_period.max(2), max_period.max(3))
+    }
+
+    // Beat period in frames and confidence from the autocorrelation of the detection function, if there's enough of it.
+    fn estimate_period(&self) -> Option<(f32, f32)> {
+        let (min_period, max_period) = self.period_range();
+        let count = self.detection.len();
+        if count < 2 * max_period
+        {
+            return None;
+        }
+
+        let mean = self.detection.iter().sum::<f32>() / count as f32;
+        let centered: Vec<f32> = self.detection.iter().map(|value| value - mean).collect();
+        let energy: f32 = centered.iter().map(|value| value * value).sum::<f32>() / count as f32;
+        if energy <= 0.0
+        {
+            return None;
+        }
+
+        // Unbiased autocorrelation, weighted towards the preferred tempo.
+        let preferred = 60.0 * self.frame_rate() / self.parameters.get_float("preferred_bpm");
+        let mut correlation = vec![0.0f32; max_period + 2];
+        let mut weighted = vec![0.0f32; max_period + 2];
+        for lag in (min_period - 1)..(max_period + 2).min(count)
+        {
+            let sum: f32 = (0..count - lag).map(|i| centered[i] * centered[i + lag]).sum();
+            correlation[lag] = sum / (count - lag) as f32 / energy;
+            let octaves = (lag as f32 / preferred).log2() / TEMPO_PRIOR_OCTAVES;
+            weighted[lag] = correlation[lag] * (-0.5 * octaves * octaves).exp();
+        }
+
+        let best = (min_period..max_period + 1).fold(min_period, |best, lag| {
+            if weighted[lag] > weighted[best] { lag } else { best }
+        });
+        if correlation[best] <= 0.0
+        {
+            return None;
+        }
+
+        Some((parabolic(&correlation, best), correlation[best].min(1.0)))
+    }
+
+    // Best score of the frames before `index` that could be the beat before it, weighted by how close they are
+    // to a beat period before it.
+    fn past_score(&self, score: &[f32], index: usize) -> f32 {
+        let period = self.period;
+        let first = (2.0 * period).round() as usize;
+        let last = (0.5 * period).round().max(1.0) as usize;
+
+        let mut best = 0.0f32;
+        for distance in last..first + 1
+        {
+            if distance <= index
+            {
+                best = best.max(transition_weight(distance as f32, period) * score[index - distance]);
+            }
+        }
+        best
+    }
+
+    // Predicts the next beat from the score so far, projected a beat ahead with no more onsets.
+    fn predict(&mut self) {
+        let period = self.period;
+        let mut score: Vec<f32> = self.score.iter().cloned().collect();
+        let latest = score.len() - 1;
+        let ahead = period.round() as usize;
+        for _ in 0..ahead
+        {
+            let index = score.len();
+            let projected = SCORE_MEMORY * self.past_score(&score, index);
+            score.push(projected);
+        }
+
+        // Favour the frame a beat after the latest beat.
+        let expected = match self.last_beat
+        {
+            Some(last) => last as f32 + period - (self.frames - 1) as f32,
+            None => 0.5 * period,
+        };
+        let mut best = 1;
+        let mut best_value = -1.0f32;
+        for offset in 1..ahead + 1
+        {
+            let distance = (offset as f32 - expected) / (0.5 * period);
+            let value = score[latest + offset] * (-0.5 * distance * distance).exp();
+            if value > best_value
+            {
+                best = offset;
+                best_value = value;
+            }
+        }
+
+        self.next_beat = Some(self.frames - 1 + best as u64);
+    }
+
+    fn track(&mut self, frame: u64) {
+        let (_, max_period) = self.period_range();
+        let window = (self.parameters.get_float("window") / 1000.0 * self.frame_rate()).round() as usize;
+        let window = window.max(2 * max_period);
+
+        self.frames += 1;
+        self.latest_frame = frame;
+        let detection = self.onset.detection();
+        self.detection.push_back(detection);
+        while self.detection.len() > window
+        {
+            self.detection.pop_front();
+        }
+
+        // Follow the tempo, in log period so that speeding up and slowing down take as long.
+        if let Some((period, confidence)) = self.estimate_period()
+        {
+            let smoothing = self.parameters.get_float("smoothing") / 1000.0 * self.frame_rate();
+            let follow = if smoothing > 0.0 { 1.0 - (-1.0 / smoothing).exp() } else { 1.0 };
+            if self.period > 0.0
+            {
+                self.period = (self.period.ln() + follow * (period.ln() - self.period.ln())).exp();
+                self.confidence += follow * (confidence - self.confidence);
+            }
+            else
+            {
+                self.period = period;
+                self.confidence = confidence;
+            }
+        }
+
+        let score = if self.period > 0.0
+        {
+            let past: Vec<f32> = self.score.iter().cloned().collect();
+            (1.0 - SCORE_MEMORY) * detection + SCORE_MEMORY * self.past_score(&past, past.len())
+        }
+        else
+        {
+            detection
+        };
+        self.score.push_back(score);
+        while self.score.len() > window
+        {
+            self.score.pop_front();
+        }
+
+        if self.period <= 0.0
+        {
+            return;
+        }
+
+        let latest = self.frames - 1;
+        if self.next_beat.map_or(false, |next| latest >= next)
+        {
+            let phase = self.last_beat.map_or(0.0, |last| ((latest - last) as f32 - self.period) / self.period);
+            self.last_beat = Some(latest);
+            self.next_beat = None;
+            self.beat_count += 1;
+
+            let beat = BeatEvent {
+                frame,
+                time: frame as f64 / self.sample_rate as f64,
+                bpm: self.bpm(),
+                confidence: self.confidence,
+                phase,
+                index: self.beat_count,
+            };
+            self.last_beat_time = Some(beat.time);
+            self.beats.push(beat);
+            self.taken_beats.push(beat);
+            if self.taken_beats.len() > MAX_TAKEN_BEATS
+            {
+                self.taken_beats.remove(0);
+            }
+        }
+
+        // Predict the next beat half a beat after the latest, or as soon as the tempo is known.
+        let halfway = self.last_beat.map_or(true, |last| (latest - last) as f32 >= 0.5 * self.period);
+        if self.next_beat.is_none() && halfway
+        {
+            self.predict();
+        }
+
+        self.phase = match self.last_beat
+        {
+            Some(last) => ((latest - last) as f32 / self.period).min(1.0),
+            None => 0.0,
+        };
+    }
+
+    fn analyse(&mut self, frame: u64) {
+        self.beats = Vec::new();
+        self.track(frame);
+
+        self.output.set("bpm", Value::Scalar(self.bpm()));
+        self.output.set("confidence", Value::Scalar(self.confidence));
+        self.output.set("phase", Value::Scalar(self.phase));
+        self.output.set("beat", Value::Scalar(if self.beats.is_empty() { 0.0 } else { 1.0 }));
+        self.output.set("last_beat", Value::Scalar(self.last_beat_time().map_or(-1.0, |time| time as f32)));
+    }
+}
+
+impl Default for Beat {
+    fn default() -> Beat {
+        Beat::new()
+    }
+}
+
+impl Chainable for Beat {
+    fn prepare(&mut self, format: &StreamFormat) {
+        if format.sample_rate > 0.0
+        {
+            self.sample_rate = format.sample_rate;
+        }
+        self.onset.prepare(format);
+        self.reset();
+    }
+
+    fn process(&mut self, buffer: &Vec<Vec<f32>>, context: &BlockContext) {
+        if context.sample_rate > 0.0
+        {
+            self.sample_rate = context.sample_rate;
+        }
+        self.onset.process(buffer, context);
+
+        let length = buffer.iter().map(|channel| channel.len()).max().unwrap_or(0);
+        let frame = context.frame + length.saturating_sub(self.onset.size()) as u64;
+        self.analyse(frame);
+    }
+
+    /// Expects a frame of the onset node's size, e.g. from the chain's framing.
+    fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        self.onset.update(buffer);
+        let frame = self.frames * self.onset.hop() as u64;
+        self.analyse(frame);
+    }
+
+    fn output(&self) -> &Output {
+        &self.output
+    }
+
+    fn framing(&self) -> Option<(usize, usize)> {
+        self.onset.framing()
+    }
+
+    fn parameters(&self) -> Option<&Parameters> {
+        Some(&self.parameters)
+    }
+
+    fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        Some(&mut self.parameters)
+    }
+}
@@ -0,0 +1,440 @@
+use analysis::traits::Chainable;
+use analysis::parameters::{Parameters, ParameterError};
+use analysis::output::Output;
+use analysis::stream::StreamFormat;
+
+use std::f64::consts::PI;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FilterType {
+    LowPass,
+    HighPass,
+    /// Band-pass with 0 dB gain at the center frequency.
+    BandPass,
+    Notch,
+    LowShelf,
+    HighShelf,
+    Peaking,
+}
+
+/// Coefficients of a single biquad section, normalized so that a0 is 1.
+#[derive(Clone, Copy, Debug)]
+pub struct Coefficients {
+    pub b0: f64,
+    pub b1: f64,
+    pub b2: f64,
+    pub a1: f64,
+    pub a2: f64,
+}
+
+impl Coefficients {
+    /// Coefficients from Robert Bristow-Johnson's Audio EQ Cookbook.
+    /// `gain_db` is only used by the shelving and peaking filters.
+    pub fn new(filter_type: FilterType, sample_rate: f32, frequency: f32, q: f32, gain_db: f32) -> Coefficients {
+        let w0 = 2.0 * PI * frequency as f64 / sample_rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q as f64);
+        let a = 10f64.powf(gain_db as f64 / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match filter_type
+        {
+            FilterType::LowPass => ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0,
+                                    1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::HighPass => ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0,
+                                     1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::BandPass => (alpha, 0.0, -alpha,
+                                     1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::Notch => (1.0, -2.0 * cos, 1.0,
+                                  1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterType::Peaking => (1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a,
+                                    1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a),
+            FilterType::LowShelf => (a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                                     2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                                     a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                                     (a + 1.0) + (a - 1.0) * cos + shelf,
+                                     -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                                     (a + 1.0) + (a - 1.0) * cos - shelf),
+            FilterType::HighShelf => (a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                                      -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                                      a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                                      (a + 1.0) - (a - 1.0) * cos + shelf,
+                                      2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                                      (a + 1.0) - (a - 1.0) * cos - shelf),
+        };
+
+        Coefficients {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+
+    /// First order low or high-pass section, for odd order Butterworth filters.
+    pub fn first_order(filter_type: FilterType, sample_rate: f32, frequency: f32) -> Coefficients {
+        let k = (PI * frequency as f64 / sample_rate as f64).tan();
+        let a1 = (k - 1.0) / (k + 1.0);
+
+        let (b0, b1) = match filter_type
+        {
+            FilterType::HighPass => (1.0 / (1.0 + k), -1.0 / (1.0 + k)),
+            _ => (k / (1.0 + k), k / (1.0 + k)),
+        };
+
+        Coefficients {
+            b0,
+            b1,
+            b2: 0.0,
+            a1,
+            a2: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Stage {
+    FirstOrder,
+    SecondOrder(f32),
+}
+
+// Stages of a Butterworth filter of the given order. Each second order stage has its own Q.
+fn butterworth_stages(order: usize) -> Vec<Stage> {
+    let mut stages = Vec::new();
+    for k in 0..order / 2
+    {
+        // Angle of the pole pair from the negative real axis.
+        let angle = if order % 2 == 0 {
+            (2 * k + 1) as f64 * PI / (2 * order) as f64
+        } else {
+            (k + 1) as f64 * PI / order as f64
+        };
+        let q = 1.0 / (2.0 * angle.cos());
+        stages.push(Stage::SecondOrder(q as f32));
+    }
+    if order % 2 == 1
+    {
+        stages.push(Stage::FirstOrder);
+    }
+    stages
+}
+
+/// Biquad filter, or a cascade of them, applied to each channel separately.
+/// The filtered audio is passed on to the nodes after the filter, and is also available through `filtered()`.
+/// Parameters are "frequency", "q" and "gain" in dB.
+pub struct BiquadFilter {
+    filter_type: FilterType,
+    sample_rate: f32,
+    parameters: Parameters,
+
+    stages: Vec<Stage>,
+    coefficients: Vec<Coefficients>,
+
+    // Transposed direct form II state, [channel][stage].
+    states: Vec<Vec<[f64; 2]>>,
+
+    audio: Vec<Vec<f32>>,
+    output: Output,
+}
+
+impl BiquadFilter {
+    pub fn new(filter_type: FilterType, sample_rate: f32, frequency: f32, q: f32, gain_db: f32) -> BiquadFilter {
+        BiquadFilter::with_stages(filter_type, sample_rate, frequency, q, gain_db, vec![Stage::SecondOrder(q)])
+    }
+
+    /// Butterworth low or high-pass filter of any order.
+    pub fn butterworth(filter_type: FilterType, order: usize, sample_rate: f32, frequency: f32) -> Result<BiquadFilter, String> {
+        if filter_type != FilterType::LowPass && filter_type != FilterType::HighPass
+        {
+            return Err(format!("Butterworth filters are low or high-pass, not {:?}", filter_type));
+        }
+        if order == 0
+        {
+            return Err("Filter order must be at least 1".to_string());
+        }
+
+        let stages = butterworth_stages(order);
+        Ok(BiquadFilter::with_stages(filter_type, sample_rate, frequency, ::std::f32::consts::FRAC_1_SQRT_2, 0.0, stages))
+    }
+
+    /// Linkwitz-Riley low or high-pass filter, i.e. two cascaded Butterworth filters of half the order.
+    /// The order must be even. Low and high-pass filters of order 4, 8, ... with the same frequency sum to a flat response,
+    /// for order 2, 6, ... one of them has to be inverted in polarity first.
+    pub fn linkwitz_riley(filter_type: FilterType, order: usize, sample_rate: f32, frequency: f32) -> Result<BiquadFilter, String> {
+        if filter_type != FilterType::LowPass && filter_type != FilterType::HighPass
+        {
+            return Err(format!("Linkwitz-Riley filters are low or high-pass, not {:?}", filter_type));
+        }
+        if order == 0 || order % 2 != 0
+        {
+            return Err(format!("Linkwitz-Riley filter order must be even, not {}", order));
+        }
+
+        let mut stages = butterworth_stages(order / 2);
+        stages.extend(butterworth_stages(order / 2));
+        Ok(BiquadFilter::with_stages(filter_type, sample_rate, frequency, 0.5, 0.0, stages))
+    }
+
+    fn with_stages(filter_type: FilterType, sample_rate: f32, frequency: f32, q: f32, gain_db: f32, stages: Vec<Stage>) -> BiquadFilter {
+        let mut parameters = Parameters::new();
+        parameters.add_float("frequency", frequency, 1.0, sample_rate / 2.0);
+        parameters.add_float("q", q, 0.01, 100.0);
+        parameters.add_float("gain", gain_db, -48.0, 48.0);
+
+        let mut filter = BiquadFilter {
+            filter_type,
+            sample_rate,
+            parameters,
+
+            stages,
+            coefficients: Vec::new(),
+
+            states: Vec::new(),
+
+            audio: Vec::new(),
+            output: Output::new(),
+        };
+        filter.calculate_coefficients();
+        filter
+    }
+
+    fn calculate_coefficients(&mut self) {
+        let (filter_type, sample_rate, frequency, gain_db) = (self.filter_type, self.sample_rate, self.frequency(), self.gain_db());
+        // A single stage filter uses the filter's own Q, cascades use the Q of each stage.
+        let single_q = if self.stages.len() == 1 { Some(self.q()) } else { None };
+
+        self.coefficients = self.stages.iter().map(|stage| {
+            match *stage
+            {
+                Stage::FirstOrder => Coefficients::first_order(filter_type, sample_rate, frequency),
+                Stage::SecondOrder(q) => Coefficients::new(filter_type, sample_rate, frequency, single_q.unwrap_or(q), gain_db),
+            }
+        }).collect();
+    }
+
+    pub fn filter_type(&self) -> FilterType {
+        self.filter_type
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.parameters.get_float("frequency")
+    }
+
+    pub fn q(&self) -> f32 {
+        self.parameters.get_float("q")
+    }
+
+    pub fn gain_db(&self) -> f32 {
+        self.parameters.get_float("gain")
+    }
+
+    /// Amount of cascaded biquad sections.
+    pub fn stages(&self) -> usize {
+        self.stages.len()
+    }
+
+    /// Also limits the frequency to below the new Nyquist frequency.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.parameters.set_range("frequency", 1.0, sample_rate / 2.0);
+        self.calculate_coefficients();
+    }
+
+    /// Cutoff or center frequency in Hz.
+    pub fn set_frequency(&mut self, frequency: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("frequency", frequency)?;
+        self.calculate_coefficients();
+        Ok(())
+    }
+
+    /// Only affects single stage filters, the stages of cascaded filters have fixed Qs.
+    pub fn set_q(&mut self, q: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("q", q)?;
+        self.calculate_coefficients();
+        Ok(())
+    }
+
+    /// Gain of the shelving and peaking filters in dB.
+    pub fn set_gain_db(&mut self, gain_db: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("gain", gain_db)?;
+        self.calculate_coefficients();
+        Ok(())
+    }
+
+    /// Clears the filter state, as if the filter had only ever seen silence.
+    pub fn reset(&mut self) {
+        self.states.clear();
+    }
+
+    /// Audio filtered during the latest update.
+    pub fn filtered(&self) -> &Vec<Vec<f32>> {
+        &self.audio
+    }
+}
+
+impl Chainable for BiquadFilter {
+    /// Recalculates the coefficients for the stream's sample rate.
+    fn prepare(&mut self, format: &StreamFormat) {
+        if format.sample_rate > 0.0 && format.sample_rate != self.sample_rate
+        {
+            self.set_sample_rate(format.sample_rate);
+        }
+        self.reset();
+    }
+
+    fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        if !self.parameters.take_changed().is_empty()
+        {
+            self.calculate_coefficients();
+        }
+
+        if self.states.len() != buffer.len()
+        {
+            self.states = vec![vec![[0f64; 2]; self.coefficients.len()]; buffer.len()];
+        }
+
+        self.audio = Vec::with_capacity(buffer.len());
+        for (samples, states) in buffer.iter().zip(self.states.iter_mut())
+        {
+            let mut channel = Vec::with_capacity(samples.len());
+            for &sample in samples.iter()
+            {
+                let mut x = sample as f64;
+                for (c, state) in self.coefficients.iter().zip(states.iter_mut())
+                {
+                    let y = c.b0 * x + state[0];
+                    state[0] = c.b1 * x - c.a1 * y + state[1];
+                    state[1] = c.b2 * x - c.a2 * y;
+                    x = y;
+                }
+                channel.push(x as f32);
+            }
+            self.audio.push(channel);
+        }
+    }
+
+    /// Filters have no analysis results, the audio is in `filtered()`.
+    fn output(&self) -> &Output {
+        &self.output
+    }
+
+    fn processed(&self) -> Option<&Vec<Vec<f32>>> {
+        Some(&self.audio)
+    }
+
+    fn parameters(&self) -> Option<&Parameters> {
+        Some(&self.parameters)
+    }
+
+    fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        Some(&mut self.parameters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analysis::generator_source::{Generator, Signal, Waveform};
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn generate(waveform: Waveform, frequency: f32, frames: usize) -> Vec<Vec<f32>> {
+        let mut signal = Signal::new(waveform);
+        signal.frequency = frequency;
+        Generator::new(vec![signal], SAMPLE_RATE).unwrap().generate(frames)
+    }
+
+    fn prepared(mut filter: BiquadFilter) -> BiquadFilter {
+        filter.prepare(&StreamFormat::new(SAMPLE_RATE as f32, 1));
+        filter
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    // Gain in dB of a sine through the filter, measured after it settled.
+    fn gain_db(filter: &mut BiquadFilter, frequency: f32) -> f32 {
+        let input = generate(Waveform::Sine, frequency, SAMPLE_RATE as usize);
+        filter.update(&input);
+        let settled = SAMPLE_RATE as usize / 2;
+        20.0 * (rms(&filter.filtered()[0][settled..]) / rms(&input[0][settled..])).log10()
+    }
+
+    #[test]
+    fn butterworth_is_3_db_down_at_the_cutoff() {
+        for &order in [1, 2, 3, 4].iter()
+        {
+            for &filter_type in [FilterType::LowPass, FilterType::HighPass].iter()
+            {
+                let mut filter = prepared(BiquadFilter::butterworth(filter_type, order, SAMPLE_RATE as f32, 1000.0).unwrap());
+                let gain = gain_db(&mut filter, 1000.0);
+                assert!((gain + 3.01).abs() < 0.05, "{:?} order {}: {} dB", filter_type, order, gain);
+            }
+        }
+    }
+
+    #[test]
+    fn single_low_pass_is_3_db_down_at_the_cutoff() {
+        let mut filter = prepared(BiquadFilter::new(FilterType::LowPass, SAMPLE_RATE as f32, 2000.0, ::std::f32::consts::FRAC_1_SQRT_2, 0.0));
+        let gain = gain_db(&mut filter, 2000.0);
+        assert!((gain + 3.01).abs() < 0.05, "{} dB", gain);
+    }
+
+    #[test]
+    fn linkwitz_riley_4_pair_sums_flat() {
+        let input = generate(Waveform::WhiteNoise, 0.0, SAMPLE_RATE as usize);
+        let mut low = prepared(BiquadFilter::linkwitz_riley(FilterType::LowPass, 4, SAMPLE_RATE as f32, 1000.0).unwrap());
+        let mut high = prepared(BiquadFilter::linkwitz_riley(FilterType::HighPass, 4, SAMPLE_RATE as f32, 1000.0).unwrap());
+        low.update(&input);
+        high.update(&input);
+
+        let sum: Vec<f32> = low.filtered()[0].iter().zip(high.filtered()[0].iter()).map(|(l, h)| l + h).collect();
+        // An LR4 pair sums to an all-pass, so the level of the sum is the level of the input.
+        let gain = 20.0 * (rms(&sum) / rms(&input[0])).log10();
+        assert!(gain.abs() < 0.05, "{} dB", gain);
+
+        for &frequency in [100.0, 1000.0, 10000.0].iter()
+        {
+            let input = generate(Waveform::Sine, frequency, SAMPLE_RATE as usize);
+            low.reset();
+            high.reset();
+            low.update(&input);
+            high.update(&input);
+            let sum: Vec<f32> = low.filtered()[0].iter().zip(high.filtered()[0].iter()).map(|(l, h)| l + h).collect();
+            let settled = SAMPLE_RATE as usize / 2;
+            let gain = 20.0 * (rms(&sum[settled..]) / rms(&input[0][settled..])).log10();
+            assert!(gain.abs() < 0.01, "{} Hz: {} dB", frequency, gain);
+        }
+    }
+
+    #[test]
+    fn linkwitz_riley_2_pair_sums_flat_with_one_band_inverted() {
+        let input = generate(Waveform::WhiteNoise, 0.0, SAMPLE_RATE as usize);
+        let mut low = prepared(BiquadFilter::linkwitz_riley(FilterType::LowPass, 2, SAMPLE_RATE as f32, 1000.0).unwrap());
+        let mut high = prepared(BiquadFilter::linkwitz_riley(FilterType::HighPass, 2, SAMPLE_RATE as f32, 1000.0).unwrap());
+        low.update(&input);
+        high.update(&input);
+
+        let difference: Vec<f32> = low.filtered()[0].iter().zip(high.filtered()[0].iter()).map(|(l, h)| l - h).collect();
+        let gain = 20.0 * (rms(&difference) / rms(&input[0])).log10();
+        assert!(gain.abs() < 0.05, "{} dB", gain);
+    }
+
+    #[test]
+    fn linkwitz_riley_is_6_db_down_at_the_crossover() {
+        let mut filter = prepared(BiquadFilter::linkwitz_riley(FilterType::LowPass, 4, SAMPLE_RATE as f32, 1000.0).unwrap());
+        let gain = gain_db(&mut filter, 1000.0);
+        assert!((gain + 6.02).abs() < 0.05, "{} dB", gain);
+    }
+
+    #[test]
+    fn rejects_invalid_designs() {
+        assert!(BiquadFilter::butterworth(FilterType::LowPass, 0, SAMPLE_RATE as f32, 1000.0).is_err());
+        assert!(BiquadFilter::butterworth(FilterType::Notch, 2, SAMPLE_RATE as f32, 1000.0).is_err());
+        assert!(BiquadFilter::linkwitz_riley(FilterType::HighPass, 3, SAMPLE_RATE as f32, 1000.0).is_err());
+        assert!(BiquadFilter::linkwitz_riley(FilterType::HighPass, 0, SAMPLE_RATE as f32, 1000.0).is_err());
+        assert!(BiquadFilter::linkwitz_riley(FilterType::Peaking, 4, SAMPLE_RATE as f32, 1000.0).is_err());
+    }
+}
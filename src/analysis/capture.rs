@@ -0,0 +1,206 @@
+use analysis::analysis::Chains;
+use analysis::ring_buffer::{ring_buffer, Producer, Consumer};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long the analysis thread sleeps when there's no audio waiting.
+const POLL_MILLIS: u64 = 1;
+
+/// Audio lost between the device and the chains, counted from when the source started.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct StreamStats {
+    /// Times audio was lost on the way in: the analysis fell behind and the ring buffer was full,
+    /// or the backend couldn't keep up with the device.
+    pub overruns: u64,
+    /// Times audio stopped coming in: the backend reported it had no audio to give,
+    /// or none arrived for twice as long as the latest block lasted.
+    pub underruns: u64,
+    /// Frames dropped because the ring buffer was full.
+    pub dropped_frames: u64,
+}
+
+/// Counters shared by the audio callback, the analysis thread and the source.
+pub struct StreamCounters {
+    overruns: AtomicUsize,
+    underruns: AtomicUsize,
+    dropped_frames: AtomicUsize,
+}
+
+impl StreamCounters {
+    fn new() -> StreamCounters {
+        StreamCounters {
+            overruns: AtomicUsize::new(0),
+            underruns: AtomicUsize::new(0),
+            dropped_frames: AtomicUsize::new(0),
+        }
+    }
+
+    /// For backends that report overflows of their own buffers.
+    pub fn overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> StreamStats {
+        StreamStats {
+            overruns: self.overruns.load(Ordering::Relaxed) as u64,
+            underruns: self.underruns.load(Ordering::Relaxed) as u64,
+            dropped_frames: self.dropped_frames.load(Ordering::Relaxed) as u64,
+        }
+    }
+}
+
+/// The audio callback's end of a capture. Writing never blocks or allocates.
+pub struct CaptureWriter {
+    producer: Producer,
+    // Frame count and time of each write, so that the chains know when their audio arrived.
+    timestamps: Producer<(usize, Option<Instant>)>,
+    channels: usize,
+    counters: Arc<StreamCounters>,
+}
+
+impl CaptureWriter {
+    /// Writes `frames` frames, getting each sample with `sample(frame, channel)`.
+    /// The frames are timestamped with the time of the call, so this should be called from the audio callback.
+    /// Frames that don't fit into the ring buffer are dropped and counted as an overrun.
+    pub fn write_frames<F>(&mut self, frames: usize, mut sample: F) where F: FnMut(usize, usize) -> f32 {
+        let timestamp = Instant::now();
+        let channels = self.channels;
+        if channels == 0
+        {
+            return;
+        }
+
+        let fitting = frames.min(self.producer.free() / channels);
+        self.producer.write_with(fitting * channels, |i| sample(i / channels, i % channels));
+        // Each write of at least a frame takes a frame of space, so there's always room for its timestamp.
+        if fitting > 0
+        {
+            self.timestamps.write(&[(fitting, Some(timestamp))]);
+        }
+
+        if fitting < frames
+        {
+            self.counters.overruns.fetch_add(1, Ordering::Relaxed);
+            self.counters.dropped_frames.fetch_add(frames - fitting, Ordering::Relaxed);
+        }
+    }
+
+    pub fn counters(&self) -> &Arc<StreamCounters> {
+        &self.counters
+    }
+}
+
+/// Moves audio from a real-time callback to a thread of its own, where the chains are run.
+/// That way the callback never waits for the locks the chains take.
+pub struct Capture {
+    counters: Arc<StreamCounters>,
+    running: Arc<AtomicBool>,
+}
+
+impl Capture {
+    /// Starts the analysis thread. The ring buffer holds `capacity` frames of `channels` channels.
+    pub fn start(chains: Chains, channels: usize, capacity: usize) -> (Capture, CaptureWriter) {
+        let (producer, consumer) = ring_buffer((capacity * channels).max(1));
+        let (timestamp_producer, timestamp_consumer) = ring_buffer(capacity.max(1));
+        let counters = Arc::new(StreamCounters::new());
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread_running = running.clone();
+        let thread_counters = counters.clone();
+        thread::spawn(move || {
+            analyse(chains, consumer, timestamp_consumer, channels, thread_counters, thread_running);
+        });
+
+        let writer = CaptureWriter {
+            producer,
+            timestamps: timestamp_producer,
+            channels,
+            counters: counters.clone(),
+        };
+
+        (Capture { counters, running }, writer)
+    }
+
+    /// Lets the analysis thread finish. Doesn't wait for it, as it may be waiting for a chain that is stopping.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Release);
+    }
+
+    pub fn counters(&self) -> &Arc<StreamCounters> {
+        &self.counters
+    }
+
+    pub fn stats(&self) -> StreamStats {
+        self.counters.stats()
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Passes the frames of each write on to the chains, de-interleaved, with the time they were written.
+// Counts an underrun when the ring buffer stays empty for twice as long as the latest write lasted.
+fn analyse(chains: Chains, mut consumer: Consumer, mut timestamps: Consumer<(usize, Option<Instant>)>, channels: usize,
+           counters: Arc<StreamCounters>, running: Arc<AtomicBool>) {
+    let mut interleaved = vec![0f32; consumer.capacity()];
+    // When the latest write is due to be followed by the next one, and whether it's overdue already.
+    let mut next_due: Option<Instant> = None;
+    let mut overdue = false;
+
+    while running.load(Ordering::Acquire)
+    {
+        // Timestamps are written after their frames, so the frames of a timestamp are always there.
+        let mut timestamp = [(0, None)];
+        if timestamps.read(&mut timestamp) == 0
+        {
+            if let Some(due) = next_due
+            {
+                if !overdue && Instant::now() > due
+                {
+                    counters.underrun();
+                    overdue = true;
+                }
+            }
+            thread::sleep(Duration::from_millis(POLL_MILLIS));
+            continue;
+        }
+        let (frames, timestamp) = timestamp[0];
+        let timestamp = timestamp.unwrap_or_else(Instant::now);
+
+        let sample_rate = chains.format().map(|format| format.sample_rate).unwrap_or(0.0);
+        next_due = if sample_rate > 0.0
+        {
+            let millis = (2000.0 * frames as f64 / sample_rate as f64).ceil() as u64;
+            Some(timestamp + Duration::from_millis(millis.max(2 * POLL_MILLIS)))
+        }
+        else
+        {
+            None
+        };
+        overdue = false;
+
+        let read = consumer.read(&mut interleaved[..frames * channels]);
+        let frames = read / channels;
+
+        let mut buffer: Vec<Vec<f32>> = vec![Vec::with_capacity(frames); channels];
+        for f in 0..frames
+        {
+            for ch in 0..channels
+            {
+                buffer[ch].push(interleaved[f * channels + ch]);
+            }
+        }
+
+        chains.source_cb_at(buffer, frames, timestamp);
+    }
+}
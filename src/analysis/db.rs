@@ -0,0 +1,162 @@
+use analysis::traits::Chainable;
+use analysis::parameters::{Parameters, ParameterError};
+use analysis::output::{Output, Value};
+use analysis::reframer::check_framing;
+
+/// RMS and peak level in dBFS. Output is "rms" and "peak" of all channels together,
+/// followed by "channel_rms" and "channel_peak", the levels of each channel.
+/// Parameters are "floor" and "reference".
+pub struct DB {
+    parameters: Parameters,
+    framing: Option<(usize, usize)>,
+
+    rms_db: f32,
+    peak_db: f32,
+    channel_rms_db: Vec<f32>,
+    channel_peak_db: Vec<f32>,
+
+    output: Output,
+}
+
+impl DB {
+    pub fn new() -> DB {
+        let mut parameters = Parameters::new();
+        parameters.add_float("floor", -96.0, -200.0, 0.0);
+        parameters.add_float("reference", 0.0, -100.0, 0.0);
+
+        DB {
+            parameters,
+            framing: None,
+
+            rms_db: -96.0,
+            peak_db: -96.0,
+            channel_rms_db: Vec::new(),
+            channel_peak_db: Vec::new(),
+
+            output: Output::new(),
+        }
+    }
+
+    /// Lowest level reported, in dB. Silence is reported as the floor instead of negative infinity.
+    pub fn set_floor(&mut self, floor: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("floor", floor)
+    }
+
+    /// Level in dBFS that is reported as 0 dB, e.g. -18.0 to read levels relative to -18 dBFS.
+    pub fn set_reference(&mut self, reference: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("reference", reference)
+    }
+
+    /// Measures frames of `size` frames, a new one every `hop` frames, instead of whatever blocks the source gives.
+    /// Both must be at least 1.
+    pub fn set_framing(&mut self, size: usize, hop: usize) -> Result<(), ParameterError> {
+        check_framing(size, hop)?;
+        self.framing = Some((size, hop));
+        Ok(())
+    }
+
+    pub fn floor(&self) -> f32 {
+        self.parameters.get_float("floor")
+    }
+
+    pub fn reference(&self) -> f32 {
+        self.parameters.get_float("reference")
+    }
+
+    pub fn rms_db(&self) -> f32 {
+        self.rms_db
+    }
+
+    pub fn peak_db(&self) -> f32 {
+        self.peak_db
+    }
+
+    pub fn channel_rms_db(&self) -> &Vec<f32> {
+        &self.channel_rms_db
+    }
+
+    pub fn channel_peak_db(&self) -> &Vec<f32> {
+        &self.channel_peak_db
+    }
+
+    /// Converts a linear level to dB relative to the reference, clamped to the floor.
+    pub fn to_db(&self, level: f32) -> f32 {
+        let floor = self.floor();
+        if level <= 0.0
+        {
+            return floor;
+        }
+
+        let db = 20.0 * level.log10() - self.reference();
+        if db < floor { floor } else { db }
+    }
+}
+
+impl Default for DB {
+    fn default() -> DB {
+        DB::new()
+    }
+}
+
+impl Chainable for DB {
+    fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        let mut square_sum = 0.0f32;
+        let mut samples = 0;
+        let mut peak = 0.0f32;
+
+        let mut channel_rms_db = Vec::with_capacity(buffer.len());
+        let mut channel_peak_db = Vec::with_capacity(buffer.len());
+
+        for channel in buffer.iter()
+        {
+            let mut channel_square_sum = 0.0f32;
+            let mut channel_peak = 0.0f32;
+            for &sample in channel.iter() {
+                channel_square_sum += sample * sample;
+                if sample.abs() > channel_peak
+                {
+                    channel_peak = sample.abs();
+                }
+            }
+
+            let channel_rms = if channel.is_empty() { 0.0 } else { (channel_square_sum / channel.len() as f32).sqrt() };
+            channel_rms_db.push(self.to_db(channel_rms));
+            channel_peak_db.push(self.to_db(channel_peak));
+
+            square_sum += channel_square_sum;
+            samples += channel.len();
+            peak = peak.max(channel_peak);
+        }
+
+        if samples == 0
+        {
+            return;
+        }
+
+        self.rms_db = self.to_db((square_sum / samples as f32).sqrt());
+        self.peak_db = self.to_db(peak);
+        self.channel_rms_db = channel_rms_db;
+        self.channel_peak_db = channel_peak_db;
+
+        self.output.set("rms", Value::Scalar(self.rms_db));
+        self.output.set("peak", Value::Scalar(self.peak_db));
+        self.output.set("channel_rms", Value::Channels(self.channel_rms_db.clone()));
+        self.output.set("channel_peak", Value::Channels(self.channel_peak_db.clone()));
+    }
+
+    fn output(&self) -> &Output {
+        &self.output
+    }
+
+    fn framing(&self) -> Option<(usize, usize)> {
+        self.framing
+    }
+
+    fn parameters(&self) -> Option<&Parameters> {
+        Some(&self.parameters)
+    }
+
+    fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        Some(&mut self.parameters)
+    }
+}
@@ -0,0 +1,260 @@
+/// Sample formats a device can deliver. The values are used on the wire, so they must not change.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SampleFormat {
+    S8 = 0,
+    U8 = 1,
+    S16LE = 2,
+    S16BE = 3,
+    U16LE = 4,
+    U16BE = 5,
+    S24LE = 6,
+    S24BE = 7,
+    U24LE = 8,
+    U24BE = 9,
+    S32LE = 10,
+    S32BE = 11,
+    U32LE = 12,
+    U32BE = 13,
+    F32LE = 14,
+    F32BE = 15,
+    F64LE = 16,
+    F64BE = 17,
+}
+
+const SAMPLE_FORMATS: [SampleFormat; 18] = [
+    SampleFormat::S8, SampleFormat::U8,
+    SampleFormat::S16LE, SampleFormat::S16BE, SampleFormat::U16LE, SampleFormat::U16BE,
+    SampleFormat::S24LE, SampleFormat::S24BE, SampleFormat::U24LE, SampleFormat::U24BE,
+    SampleFormat::S32LE, SampleFormat::S32BE, SampleFormat::U32LE, SampleFormat::U32BE,
+    SampleFormat::F32LE, SampleFormat::F32BE, SampleFormat::F64LE, SampleFormat::F64BE,
+];
+
+impl SampleFormat {
+    pub fn from_id(id: u8) -> Option<SampleFormat> {
+        SAMPLE_FORMATS.iter().find(|format| **format as u8 == id).cloned()
+    }
+
+    pub fn from_name(name: &str) -> Option<SampleFormat> {
+        SAMPLE_FORMATS.iter().find(|format| format.name() == name).cloned()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self
+        {
+            SampleFormat::S8 => "S8",
+            SampleFormat::U8 => "U8",
+            SampleFormat::S16LE => "S16LE",
+            SampleFormat::S16BE => "S16BE",
+            SampleFormat::U16LE => "U16LE",
+            SampleFormat::U16BE => "U16BE",
+            SampleFormat::S24LE => "S24LE",
+            SampleFormat::S24BE => "S24BE",
+            SampleFormat::U24LE => "U24LE",
+            SampleFormat::U24BE => "U24BE",
+            SampleFormat::S32LE => "S32LE",
+            SampleFormat::S32BE => "S32BE",
+            SampleFormat::U32LE => "U32LE",
+            SampleFormat::U32BE => "U32BE",
+            SampleFormat::F32LE => "F32LE",
+            SampleFormat::F32BE => "F32BE",
+            SampleFormat::F64LE => "F64LE",
+            SampleFormat::F64BE => "F64BE",
+        }
+    }
+}
+
+/// A way the device's channels can be arranged, e.g. "Stereo" with channels "FrontLeft" and "FrontRight".
+#[derive(Clone, PartialEq, Debug)]
+pub struct ChannelLayout {
+    pub name: String,
+    pub channels: Vec<String>,
+}
+
+impl ChannelLayout {
+    /// Layout with channels simply numbered from 1, for sources that don't name their channels.
+    pub fn numbered(channels: i32) -> ChannelLayout {
+        ChannelLayout {
+            name: format!("{} channels", channels),
+            channels: (0..channels).map(|ch| format!("Channel {}", ch + 1)).collect(),
+        }
+    }
+}
+
+/// Description of an input device, for picking a device and the settings to open it with.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DeviceInfo {
+    pub name: String,
+    /// Most channels any of the layouts has.
+    pub channels: i32,
+
+    /// Ranges of supported sample rates, both ends included.
+    pub sample_rates: Vec<(i32, i32)>,
+    /// Sample rate the device currently runs at, or would by default.
+    pub default_sample_rate: i32,
+    pub formats: Vec<SampleFormat>,
+    /// Best layout first.
+    pub layouts: Vec<ChannelLayout>,
+
+    /// True for the system's default input device.
+    pub is_default: bool,
+    /// True if the device is opened directly instead of through a sound server, e.g. ALSA hw devices under PulseAudio.
+    pub is_raw: bool,
+    /// Shortest and longest supported latency in seconds.
+    pub latency: (f64, f64),
+    /// Audio API the device belongs to, e.g. "PulseAudio".
+    pub backend: String,
+}
+
+impl DeviceInfo {
+    /// Device with a numbered layout of the given amount of channels, and nothing else known about it.
+    pub fn new(name: String, channels: i32) -> DeviceInfo {
+        DeviceInfo {
+            name,
+            channels,
+
+            sample_rates: Vec::new(),
+            default_sample_rate: 0,
+            formats: Vec::new(),
+            layouts: vec![ChannelLayout::numbered(channels)],
+
+            is_default: false,
+            is_raw: false,
+            latency: (0.0, 0.0),
+            backend: "".to_string(),
+        }
+    }
+
+    pub fn supports_sample_rate(&self, sample_rate: i32) -> bool {
+        self.sample_rates.iter().any(|&(min, max)| sample_rate >= min && sample_rate <= max)
+    }
+
+    /// Supported sample rate closest to the given one, or None if the device lists no rates.
+    pub fn nearest_sample_rate(&self, sample_rate: i32) -> Option<i32> {
+        self.sample_rates.iter()
+            .map(|&(min, max)| sample_rate.max(min).min(max))
+            .min_by_key(|&rate| (rate - sample_rate).abs())
+    }
+}
+
+/// Settings to open a live source with. Zero means the backend's or device's default.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SourceConfig {
+    pub sample_rate: i32,
+    /// None for the device's best format.
+    pub format: Option<SampleFormat>,
+    /// Frames per buffer. libsoundio decides its buffer sizes itself, and uses the latency instead.
+    pub frames: u32,
+    /// Latency in seconds, within what the device supports.
+    pub latency: f64,
+}
+
+impl SourceConfig {
+    pub fn new() -> SourceConfig {
+        SourceConfig {
+            sample_rate: 0,
+            format: None,
+            frames: 0,
+            latency: 0.05,
+        }
+    }
+
+    /// Fills in the defaults from the device and checks that it supports the settings.
+    /// Settings it doesn't support are errors, instead of being replaced with ones it does.
+    pub fn negotiate(&self, device: &DeviceInfo) -> Result<SourceConfig, String> {
+        let mut config = self.clone();
+
+        // Devices that don't know their current rate get the one closest to CD quality.
+        if config.sample_rate == 0
+        {
+            config.sample_rate = device.default_sample_rate;
+        }
+        if config.sample_rate == 0
+        {
+            config.sample_rate = device.nearest_sample_rate(44_100).unwrap_or(44_100);
+        }
+        if !device.sample_rates.is_empty() && !device.supports_sample_rate(config.sample_rate)
+        {
+            return Err(format!("Device {} doesn't support sample rate {}, supported rates are {:?}.",
+                               device.name, config.sample_rate, device.sample_rates));
+        }
+
+        config.format = match config.format
+        {
+            Some(format) if device.formats.contains(&format) => Some(format),
+            Some(format) => {
+                let formats: Vec<&str> = device.formats.iter().map(|format| format.name()).collect();
+                return Err(format!("Device {} doesn't support sample format {}, supported formats are {:?}.",
+                                   device.name, format.name(), formats));
+            },
+            None => device.formats.first().cloned(),
+        };
+        if config.format.is_none()
+        {
+            return Err(format!("Device {} has no supported sample formats.", device.name));
+        }
+
+        // Backends still round the latency to what they can do, but one outside the device's range is a mistake.
+        let (min_latency, max_latency) = device.latency;
+        if config.latency == 0.0
+        {
+            config.latency = min_latency;
+        }
+        else if max_latency > 0.0 && (config.latency < min_latency || config.latency > max_latency)
+        {
+            return Err(format!("Device {} doesn't support a latency of {} s, supported latencies are {} to {} s.",
+                               device.name, config.latency, min_latency, max_latency));
+        }
+
+        Ok(config)
+    }
+}
+
+impl Default for SourceConfig {
+    fn default() -> SourceConfig {
+        SourceConfig::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device() -> DeviceInfo {
+        let mut device = DeviceInfo::new("Line In".to_string(), 2);
+        device.sample_rates = vec![(44_100, 48_000)];
+        device.default_sample_rate = 48_000;
+        device.formats = vec![SampleFormat::F32LE, SampleFormat::S16LE];
+        device.latency = (0.01, 1.0);
+        device
+    }
+
+    #[test]
+    fn negotiate_fills_in_the_defaults() {
+        let config = SourceConfig::new().negotiate(&device()).unwrap();
+        assert_eq!(config.sample_rate, 48_000);
+        assert_eq!(config.format, Some(SampleFormat::F32LE));
+        assert_eq!(config.latency, 0.05);
+
+        let mut requested = SourceConfig::new();
+        requested.format = Some(SampleFormat::S16LE);
+        requested.latency = 0.0;
+        let config = requested.negotiate(&device()).unwrap();
+        assert_eq!(config.format, Some(SampleFormat::S16LE));
+        assert_eq!(config.latency, 0.01);
+    }
+
+    #[test]
+    fn negotiate_rejects_what_the_device_doesnt_support() {
+        let mut requested = SourceConfig::new();
+        requested.sample_rate = 96_000;
+        assert!(requested.negotiate(&device()).unwrap_err().contains("96000"));
+
+        let mut requested = SourceConfig::new();
+        requested.format = Some(SampleFormat::S24LE);
+        assert!(requested.negotiate(&device()).unwrap_err().contains("S24LE"));
+
+        let mut requested = SourceConfig::new();
+        requested.latency = 2.0;
+        assert!(requested.negotiate(&device()).unwrap_err().contains("latency of 2 s"));
+    }
+}
@@ -0,0 +1,319 @@
+use analysis::traits::Chainable;
+use analysis::stream::{StreamFormat, BlockContext};
+use analysis::output::{Output, Value};
+use analysis::parameters::ParameterError;
+use analysis::reframer::check_framing;
+
+use std::f32::consts::PI;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+    BlackmanHarris,
+    FlatTop,
+}
+
+impl Window {
+    /// Window coefficients for a frame of `size` samples. Periodic, as is usual for spectral analysis.
+    pub fn coefficients(&self, size: usize) -> Vec<f32> {
+        let mut coefficients = Vec::with_capacity(size);
+        for n in 0..size
+        {
+            let x = 2.0 * PI * n as f32 / size as f32;
+            let value = match *self
+            {
+                Window::Rectangular => 1.0,
+                Window::Hann => 0.5 - 0.5 * x.cos(),
+                Window::Hamming => 0.54 - 0.46 * x.cos(),
+                Window::BlackmanHarris => 0.35875 - 0.48829 * x.cos() + 0.14128 * (2.0 * x).cos() - 0.01168 * (3.0 * x).cos(),
+                Window::FlatTop => 0.21557895 - 0.41663158 * x.cos() + 0.27726316 * (2.0 * x).cos()
+                                   - 0.083578947 * (3.0 * x).cos() + 0.006947368 * (4.0 * x).cos(),
+            };
+            coefficients.push(value);
+        }
+        coefficients
+    }
+}
+
+/// In-place radix-2 FFT. Both slices must have the same, power of two, length.
+pub fn transform(real: &mut [f32], imag: &mut [f32]) {
+    let n = real.len();
+    assert!(n.is_power_of_two() && imag.len() == n, "FFT size must be a power of two");
+
+    // Bit reversal permutation
+    let mut j = 0;
+    for i in 1..n
+    {
+        let mut bit = n >> 1;
+        while j & bit != 0
+        {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+
+        if i < j
+        {
+            real.swap(i, j);
+            imag.swap(i, j);
+        }
+    }
+
+    // Butterflies
+    let mut length = 2;
+    while length <= n
+    {
+        let angle = -2.0 * std::f64::consts::PI / length as f64;
+        for start in (0..n).step_by(length)
+        {
+            for k in 0..length / 2
+            {
+                let (w_imag, w_real) = (angle * k as f64).sin_cos();
+                let (w_real, w_imag) = (w_real as f32, w_imag as f32);
+
+                let a = start + k;
+                let b = a + length / 2;
+                let t_real = real[b] * w_real - imag[b] * w_imag;
+                let t_imag = real[b] * w_imag + imag[b] * w_real;
+
+                real[b] = real[a] - t_real;
+                imag[b] = imag[a] - t_imag;
+                real[a] += t_real;
+                imag[a] += t_imag;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+/// Magnitude and phase spectrum of each channel.
+/// The chain collects incoming audio into frames of `size` samples, a new frame every `hop` samples, for the FFT to
+/// transform. Magnitudes are scaled so that a full-scale sine wave peaks at 1.0 (0 dB).
+pub struct FFT {
+    size: usize,
+    hop: usize,
+    window: Window,
+    window_coefficients: Vec<f32>,
+    // Inverse of the window's coherent gain, so that magnitudes don't depend on the window.
+    scale: f32,
+
+    // Stream position of the latest transformed frame.
+    latest_frame: u64,
+    sample_rate: f32,
+
+    magnitudes: Vec<Vec<f32>>,
+    phases: Vec<Vec<f32>>,
+
+    output: Output,
+}
+
+impl FFT {
+    pub fn new(size: usize) -> FFT {
+        assert!(size.is_power_of_two() && size >= 2, "FFT size must be a power of two");
+
+        let mut fft = FFT {
+            size,
+            hop: size,
+            window: Window::Hann,
+            window_coefficients: Vec::new(),
+            scale: 1.0,
+
+            latest_frame: 0,
+            sample_rate: 0.0,
+
+            magnitudes: Vec::new(),
+            phases: Vec::new(),
+
+            output: Output::new(),
+        };
+        fft.set_window(Window::Hann);
+        fft
+    }
+
+    pub fn set_window(&mut self, window: Window) {
+        self.window = window;
+        self.window_coefficients = window.coefficients(self.size);
+        let sum: f32 = self.window_coefficients.iter().sum();
+        self.scale = 1.0 / sum;
+    }
+
+    /// Amount of new samples between consecutive frames, at least 1. Smaller than the size for overlapping frames.
+    pub fn set_hop(&mut self, hop: usize) -> Result<(), ParameterError> {
+        check_framing(self.size, hop)?;
+        self.hop = hop;
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn hop(&self) -> usize {
+        self.hop
+    }
+
+    pub fn window(&self) -> Window {
+        self.window
+    }
+
+    /// Amount of frequency bins per channel, from DC to Nyquist.
+    pub fn bins(&self) -> usize {
+        self.size / 2 + 1
+    }
+
+    /// Center frequency of a bin in Hz. Zero until the chain has told the FFT the sample rate.
+    pub fn bin_frequency(&self, bin: usize) -> f32 {
+        bin as f32 * self.sample_rate / self.size as f32
+    }
+
+    /// Start of the latest frame in seconds from when the source started.
+    pub fn time(&self) -> f64 {
+        if self.sample_rate > 0.0
+        {
+            self.latest_frame as f64 / self.sample_rate as f64
+        }
+        else
+        {
+            0.0
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.magnitudes.len()
+    }
+
+    /// Linear magnitude of each bin of the latest frame.
+    pub fn magnitudes(&self, channel: usize) -> &Vec<f32> {
+        &self.magnitudes[channel]
+    }
+
+    /// Magnitude of each bin of the latest frame in dB, floored at -200 dB.
+    pub fn magnitudes_db(&self, channel: usize) -> Vec<f32> {
+        self.magnitudes[channel].iter().map(|m| 20.0 * m.max(1e-10).log10()).collect()
+    }
+
+    /// Phase of each bin of the latest frame in radians.
+    pub fn phases(&self, channel: usize) -> &Vec<f32> {
+        &self.phases[channel]
+    }
+
+    fn transform_frame(&mut self, channel: usize, frame: &[f32]) {
+        // Frames shorter than the size, only given when updating the FFT directly, are padded with silence.
+        let mut real: Vec<f32> = self.window_coefficients.iter().enumerate()
+            .map(|(n, w)| frame.get(n).cloned().unwrap_or(0.0) * w)
+            .collect();
+        let mut imag = vec![0f32; self.size];
+
+        transform(&mut real, &mut imag);
+
+        let bins = self.bins();
+        let magnitudes = &mut self.magnitudes[channel];
+        let phases = &mut self.phases[channel];
+        for k in 0..bins
+        {
+            // Energy of all but DC and Nyquist is split between positive and negative frequencies.
+            let factor = if k == 0 || k == bins - 1 { 1.0 } else { 2.0 };
+            magnitudes[k] = (real[k] * real[k] + imag[k] * imag[k]).sqrt() * factor * self.scale;
+            phases[k] = imag[k].atan2(real[k]);
+        }
+    }
+}
+
+impl Chainable for FFT {
+    fn prepare(&mut self, format: &StreamFormat) {
+        self.sample_rate = format.sample_rate;
+        self.magnitudes = Vec::new();
+        self.phases = Vec::new();
+    }
+
+    fn framing(&self) -> Option<(usize, usize)> {
+        Some((self.size, self.hop))
+    }
+
+    fn process(&mut self, buffer: &Vec<Vec<f32>>, context: &BlockContext) {
+        self.sample_rate = context.sample_rate;
+        self.latest_frame = context.frame;
+        self.update(buffer);
+    }
+
+    /// Transforms a frame of `size` samples per channel.
+    fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        if self.magnitudes.len() != buffer.len()
+        {
+            let bins = self.bins();
+            self.magnitudes = vec![vec![0f32; bins]; buffer.len()];
+            self.phases = vec![vec![0f32; bins]; buffer.len()];
+        }
+
+        for (ch, frame) in buffer.iter().enumerate()
+        {
+            self.transform_frame(ch, frame);
+        }
+
+        self.output.set("magnitudes", Value::ChannelVectors(self.magnitudes.clone()));
+    }
+
+    /// "magnitudes", `bins()` values per channel.
+    fn output(&self) -> &Output {
+        &self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analysis::generator_source::{Generator, Signal, Waveform};
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn generate(waveform: Waveform, amplitude: f32, frequency: f32, frames: usize) -> Vec<Vec<f32>> {
+        let mut signal = Signal::new(waveform);
+        signal.amplitude = amplitude;
+        signal.frequency = frequency;
+        Generator::new(vec![signal], SAMPLE_RATE).unwrap().generate(frames)
+    }
+
+    fn peak_bin(magnitudes: &[f32]) -> usize {
+        (0..magnitudes.len()).fold(0, |best, bin| if magnitudes[bin] > magnitudes[best] { bin } else { best })
+    }
+
+    #[test]
+    fn sine_peaks_in_its_bin_at_its_amplitude() {
+        let mut fft = FFT::new(1024);
+        fft.prepare(&StreamFormat::new(SAMPLE_RATE as f32, 1));
+        // 3 kHz is exactly bin 64.
+        fft.process(&generate(Waveform::Sine, 0.5, 3000.0, 1024), &BlockContext::new(SAMPLE_RATE as f32, 0));
+
+        let magnitudes = fft.magnitudes(0);
+        assert_eq!(peak_bin(magnitudes), 64);
+        assert_eq!(fft.bin_frequency(64), 3000.0);
+        assert!((magnitudes[64] - 0.5).abs() < 1e-3, "{}", magnitudes[64]);
+    }
+
+    #[test]
+    fn sine_between_bins_peaks_in_the_nearest() {
+        let mut fft = FFT::new(1024);
+        fft.set_window(Window::BlackmanHarris);
+        fft.prepare(&StreamFormat::new(SAMPLE_RATE as f32, 1));
+        // Bin 100.3.
+        fft.process(&generate(Waveform::Sine, 1.0, 4701.5625, 1024), &BlockContext::new(SAMPLE_RATE as f32, 0));
+        assert_eq!(peak_bin(fft.magnitudes(0)), 100);
+    }
+
+    #[test]
+    fn transform_keeps_energy() {
+        // Parseval's theorem: the energy of the signal is the energy of its spectrum over its length.
+        let mut real = generate(Waveform::WhiteNoise, 1.0, 0.0, 1024).remove(0);
+        let mut imag = vec![0f32; real.len()];
+        let energy: f64 = real.iter().map(|&x| x as f64 * x as f64).sum();
+
+        transform(&mut real, &mut imag);
+        let spectrum_energy: f64 = real.iter().zip(imag.iter())
+            .map(|(&re, &im)| re as f64 * re as f64 + im as f64 * im as f64)
+            .sum::<f64>() / real.len() as f64;
+
+        assert!((spectrum_energy - energy).abs() < 1e-4 * energy, "{} {}", spectrum_energy, energy);
+    }
+}
@@ -0,0 +1,79 @@
+use analysis::traits::Chainable;
+use analysis::parameters::{Parameters, ParameterError};
+use analysis::output::Output;
+
+/// Amplifies or attenuates the audio for the nodes after it.
+/// Parameters are "gain" in dB and "mute".
+pub struct Gain {
+    parameters: Parameters,
+    factor: f32,
+
+    audio: Vec<Vec<f32>>,
+    output: Output,
+}
+
+impl Gain {
+    pub fn new(gain_db: f32) -> Gain {
+        let mut parameters = Parameters::new();
+        parameters.add_float("gain", gain_db, -100.0, 24.0);
+        parameters.add_boolean("mute", false);
+
+        let mut gain = Gain {
+            parameters,
+            factor: 1.0,
+
+            audio: Vec::new(),
+            output: Output::new(),
+        };
+        gain.calculate_factor();
+        gain
+    }
+
+    pub fn gain_db(&self) -> f32 {
+        self.parameters.get_float("gain")
+    }
+
+    pub fn set_gain_db(&mut self, gain_db: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("gain", gain_db)
+    }
+
+    pub fn set_mute(&mut self, mute: bool) -> Result<(), ParameterError> {
+        self.parameters.set_boolean("mute", mute)
+    }
+
+    fn calculate_factor(&mut self) {
+        self.factor = if self.parameters.get_boolean("mute") {
+            0.0
+        } else {
+            10f32.powf(self.parameters.get_float("gain") / 20.0)
+        };
+    }
+}
+
+impl Chainable for Gain {
+    fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        if !self.parameters.take_changed().is_empty()
+        {
+            self.calculate_factor();
+        }
+
+        let factor = self.factor;
+        self.audio = buffer.iter().map(|channel| channel.iter().map(|x| x * factor).collect()).collect();
+    }
+
+    fn output(&self) -> &Output {
+        &self.output
+    }
+
+    fn processed(&self) -> Option<&Vec<Vec<f32>>> {
+        Some(&self.audio)
+    }
+
+    fn parameters(&self) -> Option<&Parameters> {
+        Some(&self.parameters)
+    }
+
+    fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        Some(&mut self.parameters)
+    }
+}
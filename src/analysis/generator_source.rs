@@ -0,0 +1,394 @@
+use analysis::traits::Sourcable;
+use analysis::analysis::Chains;
+use analysis::device::{DeviceInfo, SampleFormat};
+use analysis::stream::StreamFormat;
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use std::sync::Arc;
+use std::sync::RwLock;
+
+const SAMPLE_RATE: u32 = 44_100;
+const FRAMES: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Waveform {
+    Sine,
+    Square,
+    /// Exponential sweep from the signal's frequency up or down to `to` Hz, lasting `seconds` and then starting over.
+    Sweep { to: f32, seconds: f32 },
+    WhiteNoise,
+    PinkNoise,
+    /// A single sample at full amplitude once per period. With a frequency of 0, only the very first sample.
+    Impulse,
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name
+        {
+            "sine" => Some(Waveform::Sine),
+            "square" => Some(Waveform::Square),
+            "sweep" => Some(Waveform::Sweep { to: 20_000.0, seconds: 10.0 }),
+            "white" => Some(Waveform::WhiteNoise),
+            "pink" => Some(Waveform::PinkNoise),
+            "impulse" => Some(Waveform::Impulse),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for the signal of a single channel.
+#[derive(Clone, Copy, Debug)]
+pub struct Signal {
+    pub waveform: Waveform,
+    pub amplitude: f32,
+    pub frequency: f32,
+    /// Seed for the noise waveforms. The same seed always gives the same noise.
+    pub seed: u32,
+}
+
+impl Signal {
+    pub fn new(waveform: Waveform) -> Signal {
+        Signal {
+            waveform,
+            amplitude: 1.0,
+            frequency: 1000.0,
+            seed: 1,
+        }
+    }
+
+    // Whether the signal can be synthesized. An exponential sweep can't start or end at 0 Hz.
+    fn validate(&self) -> Result<(), String> {
+        if !self.frequency.is_finite() || self.frequency < 0.0
+        {
+            return Err(format!("Invalid frequency: {}", self.frequency));
+        }
+        if let Waveform::Sweep { to, seconds } = self.waveform
+        {
+            if self.frequency <= 0.0 || !to.is_finite() || to <= 0.0
+            {
+                return Err(format!("Sweeps need frequencies above 0 Hz, got {} to {}", self.frequency, to));
+            }
+            if !seconds.is_finite()
+            {
+                return Err(format!("Invalid sweep length: {}", seconds));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Per-channel state of the generator.
+struct Oscillator {
+    signal: Signal,
+
+    // Phase in cycles, from 0 to 1.
+    phase: f64,
+    frame: u64,
+
+    random: u32,
+    pink: [f32; 7],
+}
+
+impl Oscillator {
+    fn new(signal: Signal) -> Oscillator {
+        Oscillator {
+            signal,
+
+            phase: 0.0,
+            frame: 0,
+
+            // Xorshift gets stuck on zero.
+            random: if signal.seed == 0 { 0x9E37_79B9 } else { signal.seed },
+            pink: [0.0; 7],
+        }
+    }
+
+    fn white(&mut self) -> f32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+
+        (self.random as f64 / u32::MAX as f64 * 2.0 - 1.0) as f32
+    }
+
+    // Paul Kellet's refined pink noise filter.
+    fn pink(&mut self) -> f32 {
+        let white = self.white();
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+
+        pink * 0.11
+    }
+
+    fn next(&mut self, sample_rate: f64) -> f32 {
+        let frequency = self.signal.frequency as f64;
+
+        let value = match self.signal.waveform
+        {
+            Waveform::Sine => {
+                let value = (2.0 * PI * self.phase).sin() as f32;
+                self.phase = (self.phase + frequency / sample_rate).fract();
+                value
+            },
+            Waveform::Square => {
+                let value = if self.phase < 0.5 { 1.0 } else { -1.0 };
+                self.phase = (self.phase + frequency / sample_rate).fract();
+                value
+            },
+            Waveform::Sweep { to, seconds } => {
+                let length = (seconds as f64 * sample_rate).max(1.0) as u64;
+                let t = (self.frame % length) as f64 / length as f64;
+                if t == 0.0
+                {
+                    self.phase = 0.0;
+                }
+                let value = (2.0 * PI * self.phase).sin() as f32;
+                let current = frequency * (to as f64 / frequency).powf(t);
+                self.phase = (self.phase + current / sample_rate).fract();
+                value
+            },
+            Waveform::WhiteNoise => self.white(),
+            Waveform::PinkNoise => self.pink(),
+            Waveform::Impulse => {
+                let period = if frequency > 0.0 { (sample_rate / frequency).round().max(1.0) as u64 } else { 0 };
+                let is_impulse = if period > 0 { self.frame % period == 0 } else { self.frame == 0 };
+                if is_impulse { 1.0 } else { 0.0 }
+            },
+        };
+
+        self.frame += 1;
+
+        value * self.signal.amplitude
+    }
+}
+
+/// Synthesizes deterministic test signals, one per channel.
+/// Can be used on its own to produce blocks for testing nodes, or through GeneratorSource to drive a chain.
+pub struct Generator {
+    sample_rate: u32,
+    oscillators: Vec<Oscillator>,
+}
+
+impl Generator {
+    /// Fails for a sample rate of 0 and for signals that can't be synthesized, like sweeps from 0 Hz.
+    pub fn new(signals: Vec<Signal>, sample_rate: u32) -> Result<Generator, String> {
+        if sample_rate == 0
+        {
+            return Err("Sample rate must be above 0".to_string());
+        }
+        for signal in signals.iter()
+        {
+            signal.validate()?;
+        }
+
+        Ok(Generator {
+            sample_rate,
+            oscillators: signals.into_iter().map(Oscillator::new).collect(),
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Generates the next `frames` frames, de-interleaved.
+    pub fn generate(&mut self, frames: usize) -> Vec<Vec<f32>> {
+        let sample_rate = self.sample_rate as f64;
+
+        let mut buffer: Vec<Vec<f32>> = Vec::new();
+        for oscillator in self.oscillators.iter_mut()
+        {
+            let mut channel = Vec::with_capacity(frames);
+            for _ in 0..frames
+            {
+                channel.push(oscillator.next(sample_rate));
+            }
+            buffer.push(channel);
+        }
+
+        buffer
+    }
+
+    /// Starts all signals over from the beginning.
+    pub fn reset(&mut self) {
+        for oscillator in self.oscillators.iter_mut()
+        {
+            *oscillator = Oscillator::new(oscillator.signal);
+        }
+    }
+}
+
+/// Passes synthesized signals to the chain at the generator's sample rate, like a live device would.
+pub struct GeneratorSource {
+    generator: Arc<RwLock<Generator>>,
+    block_size: usize,
+    chains: Chains,
+
+    // Bumped on every start and stop, so that a thread from an earlier start knows to quit.
+    generation: Arc<RwLock<u64>>,
+    active: bool,
+}
+
+impl GeneratorSource {
+    /// Fails for signals the generator can't synthesize.
+    pub fn new(signals: Vec<Signal>) -> Result<GeneratorSource, String> {
+        Ok(GeneratorSource {
+            generator: Arc::new(RwLock::new(Generator::new(signals, SAMPLE_RATE)?)),
+            block_size: FRAMES,
+            chains: Chains::new(),
+
+            generation: Arc::new(RwLock::new(0)),
+            active: false,
+        })
+    }
+
+    /// Changes the sample rate and starts the signals over. The sample rate must be above 0.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), String> {
+        let mut generator = self.generator.write().unwrap();
+        let signals = generator.oscillators.iter().map(|oscillator| oscillator.signal).collect();
+        *generator = Generator::new(signals, sample_rate)?;
+        Ok(())
+    }
+
+    /// Frames passed to the chain at a time, at least 1.
+    pub fn set_block_size(&mut self, block_size: usize) -> Result<(), String> {
+        if block_size == 0
+        {
+            return Err("Block size must be above 0".to_string());
+        }
+        self.block_size = block_size;
+        Ok(())
+    }
+}
+
+impl Sourcable for GeneratorSource {
+    fn start(&mut self) {
+        if self.active
+        {
+            return;
+        }
+
+        let generation = {
+            let mut generation = self.generation.write().unwrap();
+            *generation += 1;
+            *generation
+        };
+        self.active = true;
+
+        {
+            let generator = self.generator.read().unwrap();
+            self.chains.set_format(StreamFormat::new(generator.sample_rate() as f32, generator.oscillators.len()));
+        }
+
+        let current_generation = self.generation.clone();
+        let generator = self.generator.clone();
+        let chains = self.chains.clone();
+        let block_size = self.block_size;
+        thread::spawn(move || {
+            let started = Instant::now();
+            let mut frames_played = 0u64;
+
+            while *current_generation.read().unwrap() == generation
+            {
+                let (block, sample_rate) = {
+                    let mut generator = generator.write().unwrap();
+                    (generator.generate(block_size), generator.sample_rate() as f64)
+                };
+
+                chains.source_cb(block, block_size);
+
+                // Sleep until the wall clock catches up with the audio we've generated so far.
+                frames_played += block_size as u64;
+                let target = Duration::from_millis((frames_played as f64 * 1000.0 / sample_rate) as u64);
+                let elapsed = started.elapsed();
+                if target > elapsed
+                {
+                    thread::sleep(target - elapsed);
+                }
+            }
+        });
+    }
+
+    fn stop(&mut self) {
+        *self.generation.write().unwrap() += 1;
+        self.active = false;
+    }
+
+    fn chains(&self) -> &Chains {
+        &self.chains
+    }
+
+    fn is_active(&self) -> bool
+    {
+        self.active
+    }
+
+    fn get_devices() -> Result<HashMap<String, DeviceInfo>, ()> where Self: Sized
+    {
+        let mut devices = HashMap::new();
+
+        for &(name, description) in [("sine", "Sine generator"),
+                                     ("square", "Square generator"),
+                                     ("sweep", "Sweep generator"),
+                                     ("white", "White noise generator"),
+                                     ("pink", "Pink noise generator"),
+                                     ("impulse", "Impulse generator")].iter()
+        {
+            let mut info = DeviceInfo::new(description.to_string(), 2);
+            info.sample_rates = vec![(SAMPLE_RATE as i32, SAMPLE_RATE as i32)];
+            info.default_sample_rate = SAMPLE_RATE as i32;
+            info.formats = vec![SampleFormat::F32LE];
+            let latency = FRAMES as f64 / SAMPLE_RATE as f64;
+            info.latency = (latency, latency);
+            info.backend = "Generator".to_string();
+            devices.insert(format!("generator:{}", name), info);
+        }
+
+        Ok(devices)
+    }
+
+    fn get_and_clear_error(&self) -> Option<String>
+    {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_what_it_cant_synthesize() {
+        let sweep = Signal::new(Waveform::Sweep { to: 20_000.0, seconds: 1.0 });
+        assert!(Generator::new(vec![sweep], 0).is_err());
+
+        let mut from_zero = sweep;
+        from_zero.frequency = 0.0;
+        assert!(Generator::new(vec![from_zero], 48_000).is_err());
+        assert!(GeneratorSource::new(vec![from_zero]).is_err());
+
+        let mut source = GeneratorSource::new(vec![sweep]).unwrap();
+        assert!(source.set_sample_rate(0).is_err());
+        assert!(source.set_block_size(0).is_err());
+    }
+
+    #[test]
+    fn sweep_stays_finite() {
+        let mut signal = Signal::new(Waveform::Sweep { to: 20.0, seconds: 0.01 });
+        signal.frequency = 20_000.0;
+        let mut generator = Generator::new(vec![signal], 48_000).unwrap();
+        assert!(generator.generate(2_000)[0].iter().all(|sample| sample.is_finite() && sample.abs() <= 1.0));
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum GraphError {
+    /// The node isn't in the graph, or isn't in the arena.
+    UnknownNode(u64),
+    DuplicateNode(u64),
+    DuplicateEdge(u64, u64),
+    /// A node can't be its own input.
+    SelfLoop(u64),
+    /// The nodes that are part of a cycle or depend on one.
+    Cycle(Vec<u64>),
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self
+        {
+            GraphError::UnknownNode(id) => write!(f, "Unknown node {}", id),
+            GraphError::DuplicateNode(id) => write!(f, "Node {} is already in the graph", id),
+            GraphError::DuplicateEdge(from, to) => write!(f, "Node {} is already connected to node {}", from, to),
+            GraphError::SelfLoop(id) => write!(f, "Node {} can't be connected to itself", id),
+            GraphError::Cycle(ref ids) => write!(f, "Connection would create a cycle through nodes {:?}", ids),
+        }
+    }
+}
+
+/// Connections between nodes, by their arena IDs.
+/// Nodes without inputs read from the source. Nodes with several inputs merge them.
+pub struct Graph {
+    // In the order they were added, which is also the order unrelated nodes are run in.
+    nodes: Vec<u64>,
+    inputs: HashMap<u64, Vec<u64>>,
+}
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph {
+            nodes: Vec::new(),
+            inputs: HashMap::new(),
+        }
+    }
+
+    pub fn add_node(&mut self, node: u64) -> Result<(), GraphError> {
+        if self.contains(node)
+        {
+            return Err(GraphError::DuplicateNode(node));
+        }
+
+        self.nodes.push(node);
+        self.inputs.insert(node, Vec::new());
+        Ok(())
+    }
+
+    /// Removes the node along with all of its connections.
+    pub fn remove_node(&mut self, node: u64) {
+        self.nodes.retain(|&id| id != node);
+        self.inputs.remove(&node);
+        for inputs in self.inputs.values_mut()
+        {
+            inputs.retain(|&id| id != node);
+        }
+    }
+
+    /// Makes `from` an input of `to`. Fails without changing the graph if that would create a cycle.
+    pub fn connect(&mut self, from: u64, to: u64) -> Result<(), GraphError> {
+        if !self.contains(from)
+        {
+            return Err(GraphError::UnknownNode(from));
+        }
+        if !self.contains(to)
+        {
+            return Err(GraphError::UnknownNode(to));
+        }
+        if from == to
+        {
+            return Err(GraphError::SelfLoop(from));
+        }
+        if self.inputs[&to].contains(&from)
+        {
+            return Err(GraphError::DuplicateEdge(from, to));
+        }
+
+        self.inputs.get_mut(&to).unwrap().push(from);
+
+        if let Err(e) = self.order()
+        {
+            self.disconnect(from, to);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self, from: u64, to: u64) {
+        if let Some(inputs) = self.inputs.get_mut(&to)
+        {
+            inputs.retain(|&id| id != from);
+        }
+    }
+
+    pub fn contains(&self, node: u64) -> bool {
+        self.inputs.contains_key(&node)
+    }
+
+    pub fn nodes(&self) -> &Vec<u64> {
+        &self.nodes
+    }
+
+    /// Inputs of the node in the order they were connected. Empty for nodes that read from the source.
+    pub fn inputs(&self, node: u64) -> &[u64] {
+        match self.inputs.get(&node)
+        {
+            Some(inputs) => inputs,
+            None => &[],
+        }
+    }
+
+    /// True if some other node uses this node as an input.
+    pub fn has_outputs(&self, node: u64) -> bool {
+        self.inputs.values().any(|inputs| inputs.contains(&node))
+    }
+
+    /// Nodes in an order where each node comes after all of its inputs.
+    pub fn order(&self) -> Result<Vec<u64>, GraphError> {
+        let mut missing_inputs: HashMap<u64, usize> = HashMap::new();
+        for &node in self.nodes.iter()
+        {
+            missing_inputs.insert(node, self.inputs[&node].len());
+        }
+
+        let mut order = Vec::with_capacity(self.nodes.len());
+        // Repeatedly pick the nodes whose inputs have all been run, keeping the order nodes were added in.
+        while order.len() < self.nodes.len()
+        {
+            let ready: Vec<u64> = self.nodes.iter()
+                .filter(|node| missing_inputs.get(node) == Some(&0))
+                .cloned()
+                .collect();
+
+            if ready.is_empty()
+            {
+                let remaining = self.nodes.iter().filter(|node| missing_inputs.contains_key(node)).cloned().collect();
+                return Err(GraphError::Cycle(remaining));
+            }
+
+            for node in ready
+            {
+                missing_inputs.remove(&node);
+                for (other, inputs) in self.inputs.iter()
+                {
+                    let count = inputs.iter().filter(|&&id| id == node).count();
+                    if let Some(missing) = missing_inputs.get_mut(other)
+                    {
+                        *missing -= count;
+                    }
+                }
+                order.push(node);
+            }
+        }
+
+        Ok(order)
+    }
+}
+
+impl Default for Graph {
+    fn default() -> Graph {
+        Graph::new()
+    }
+}
@@ -0,0 +1,19 @@
+/// Refines the position of an extremum from its neighbours by fitting a parabola through the three.
+/// At the ends of the values there's nothing to fit, and the index is returned as it is.
+pub fn parabolic(values: &[f32], index: usize) -> f32 {
+    if index == 0 || index + 1 >= values.len()
+    {
+        return index as f32;
+    }
+
+    let (a, b, c) = (values[index - 1], values[index], values[index + 1]);
+    let denominator = a - 2.0 * b + c;
+    if denominator == 0.0
+    {
+        index as f32
+    }
+    else
+    {
+        index as f32 + 0.5 * (a - c) / denominator
+    }
+}
@@ -0,0 +1,635 @@
+use analysis::traits::Chainable;
+use analysis::parameters::{Parameters, ParameterError};
+use analysis::output::{Output, Value};
+use analysis::stream::StreamFormat;
+use analysis::biquad::Coefficients;
+use analysis::device::ChannelLayout;
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// Measurements are made of 100 ms sub-blocks. Momentary loudness covers 4 of them, short-term loudness 30.
+const SUB_BLOCK_SECONDS: f64 = 0.1;
+const MOMENTARY_SUB_BLOCKS: usize = 4;
+const SHORT_TERM_SUB_BLOCKS: usize = 30;
+
+// Gates of ITU-R BS.1770 and EBU Tech 3342.
+const ABSOLUTE_GATE: f64 = -70.0;
+const INTEGRATED_RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+const RANGE_LOW_PERCENTILE: f64 = 0.10;
+const RANGE_HIGH_PERCENTILE: f64 = 0.95;
+
+// Gated blocks are kept in a histogram from the absolute gate up, so that measuring doesn't get slower the longer it runs.
+const HISTOGRAM_STEP: f64 = 0.1;
+const HISTOGRAM_BINS: usize = 1000;
+
+// Weight of surround channels, +1.5 dB.
+const SURROUND_WEIGHT: f32 = 1.41;
+
+fn loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// BS.1770 weight of a channel by its name, as named by the device's `ChannelLayout`.
+/// Low frequency effects channels aren't measured and side and back channels are weighted +1.5 dB.
+pub fn channel_weight(name: &str) -> f32 {
+    let name = name.to_lowercase();
+    if name.contains("lfe") || name.contains("lowfrequency")
+    {
+        0.0
+    }
+    else if name.starts_with("side") || name.starts_with("back") || name.starts_with("rear") || name.contains("surround")
+    {
+        SURROUND_WEIGHT
+    }
+    else
+    {
+        1.0
+    }
+}
+
+/// Weights of the channels of a layout, for `Loudness::set_channel_weights`.
+pub fn layout_weights(layout: &ChannelLayout) -> Vec<f32> {
+    layout.channels.iter().map(|name| channel_weight(name)).collect()
+}
+
+// The two stages of the K-weighting filter for any sample rate, as in BS.1770 for 48 kHz.
+fn k_weighting(sample_rate: f32) -> [Coefficients; 2] {
+    let sample_rate = sample_rate as f64;
+
+    // Shelf modelling the acoustic effect of the head.
+    let frequency = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * frequency / sample_rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Coefficients {
+        b0: (vh + vb * k / q + k * k) / a0,
+        b1: 2.0 * (k * k - vh) / a0,
+        b2: (vh - vb * k / q + k * k) / a0,
+        a1: 2.0 * (k * k - 1.0) / a0,
+        a2: (1.0 - k / q + k * k) / a0,
+    };
+
+    // RLB high-pass.
+    let frequency = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * frequency / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Coefficients {
+        b0: 1.0,
+        b1: -2.0,
+        b2: 1.0,
+        a1: 2.0 * (k * k - 1.0) / a0,
+        a2: (1.0 - k / q + k * k) / a0,
+    };
+
+    [shelf, high_pass]
+}
+
+// Sums and counts of gated blocks by their loudness, in bins of `HISTOGRAM_STEP` LU from the absolute gate up.
+struct Histogram {
+    sums: Vec<f64>,
+    counts: Vec<u64>,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            sums: vec![0.0; HISTOGRAM_BINS],
+            counts: vec![0; HISTOGRAM_BINS],
+        }
+    }
+
+    fn bin(loudness: f64) -> usize {
+        (((loudness - ABSOLUTE_GATE) / HISTOGRAM_STEP).max(0.0) as usize).min(HISTOGRAM_BINS - 1)
+    }
+
+    // First bin entirely above the threshold.
+    fn first_bin_above(loudness: f64) -> usize {
+        (((loudness - ABSOLUTE_GATE) / HISTOGRAM_STEP).ceil().max(0.0) as usize).min(HISTOGRAM_BINS)
+    }
+
+    fn add(&mut self, power: f64) {
+        let loudness = loudness(power);
+        if loudness > ABSOLUTE_GATE
+        {
+            let bin = Histogram::bin(loudness);
+            self.sums[bin] += power;
+            self.counts[bin] += 1;
+        }
+    }
+
+    // Mean power of the blocks from the given bin up, if there are any.
+    fn mean(&self, from: usize) -> Option<f64> {
+        let sum: f64 = self.sums[from..].iter().sum();
+        let count: u64 = self.counts[from..].iter().sum();
+        if count > 0 { Some(sum / count as f64) } else { None }
+    }
+
+    // Threshold of the relative gate, `gate` LU below the blocks above the absolute gate.
+    fn relative_threshold(&self, gate: f64) -> Option<usize> {
+        self.mean(0).map(|mean| Histogram::first_bin_above(loudness(mean) + gate))
+    }
+
+    fn gated_loudness(&self) -> Option<f64> {
+        self.relative_threshold(INTEGRATED_RELATIVE_GATE)
+            .and_then(|threshold| self.mean(threshold))
+            .map(loudness)
+    }
+
+    // Difference of the percentiles of the blocks above the relative gate.
+    fn range(&self) -> Option<f64> {
+        let threshold = self.relative_threshold(RANGE_RELATIVE_GATE)?;
+
+        let count: u64 = self.counts[threshold..].iter().sum();
+        if count == 0
+        {
+            return None;
+        }
+
+        let percentile = |fraction: f64| -> usize {
+            let rank = ((count - 1) as f64 * fraction).round() as u64;
+            let mut seen = 0;
+            for bin in threshold..HISTOGRAM_BINS
+            {
+                seen += self.counts[bin];
+                if seen > rank
+                {
+                    return bin;
+                }
+            }
+            HISTOGRAM_BINS - 1
+        };
+
+        Some((percentile(RANGE_HIGH_PERCENTILE) - percentile(RANGE_LOW_PERCENTILE)) as f64 * HISTOGRAM_STEP)
+    }
+}
+
+/// Loudness per ITU-R BS.1770 and EBU R128, in LUFS.
+/// Output is "momentary" (400 ms), "short_term" (3 s), "integrated", gated over everything since the start or
+/// the latest `reset`, "range", the loudness range in LU per EBU Tech 3342, and "momentary_max" and "short_term_max".
+/// Each channel is weighted as set with `set_channel_weights`, 1.0 by default. Parameters are "floor".
+pub struct Loudness {
+    parameters: Parameters,
+    sample_rate: f32,
+
+    weights: Vec<f32>,
+    filter: [Coefficients; 2],
+    // Transposed direct form II state, [channel][stage].
+    states: Vec<[[f64; 2]; 2]>,
+
+    // Squared K-weighted samples of the current sub-block, per channel.
+    sub_block_frames: usize,
+    sub_block_position: usize,
+    sub_block_sums: Vec<f64>,
+    // Weighted mean square of the latest sub-blocks, newest last.
+    sub_blocks: VecDeque<f64>,
+
+    integrated_blocks: Histogram,
+    range_blocks: Histogram,
+
+    momentary: f32,
+    short_term: f32,
+    integrated: f32,
+    range: f32,
+    momentary_max: f32,
+    short_term_max: f32,
+
+    output: Output,
+}
+
+impl Loudness {
+    pub fn new() -> Loudness {
+        let mut parameters = Parameters::new();
+        parameters.add_float("floor", -96.0, -200.0, 0.0);
+        let floor = parameters.get_float("floor");
+
+        let mut meter = Loudness {
+            parameters,
+            sample_rate: 48_000.0,
+
+            weights: Vec::new(),
+            filter: k_weighting(48_000.0),
+            states: Vec::new(),
+
+            sub_block_frames: 0,
+            sub_block_position: 0,
+            sub_block_sums: Vec::new(),
+            sub_blocks: VecDeque::new(),
+
+            integrated_blocks: Histogram::new(),
+            range_blocks: Histogram::new(),
+
+            momentary: floor,
+            short_term: floor,
+            integrated: floor,
+            range: 0.0,
+            momentary_max: floor,
+            short_term_max: floor,
+
+            output: Output::new(),
+        };
+        meter.set_sample_rate(48_000.0);
+        meter
+    }
+
+    /// Lowest loudness reported. Silence is reported as the floor instead of negative infinity.
+    pub fn set_floor(&mut self, floor: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("floor", floor)
+    }
+
+    /// Weight of each channel, e.g. from `layout_weights`. Channels without a weight are weighted 1.0.
+    pub fn set_channel_weights(&mut self, weights: Vec<f32>) {
+        self.weights = weights;
+    }
+
+    pub fn momentary(&self) -> f32 {
+        self.momentary
+    }
+
+    pub fn short_term(&self) -> f32 {
+        self.short_term
+    }
+
+    pub fn integrated(&self) -> f32 {
+        self.integrated
+    }
+
+    /// Loudness range in LU.
+    pub fn range(&self) -> f32 {
+        self.range
+    }
+
+    pub fn momentary_max(&self) -> f32 {
+        self.momentary_max
+    }
+
+    pub fn short_term_max(&self) -> f32 {
+        self.short_term_max
+    }
+
+    /// Starts measuring the integrated loudness, loudness range and maximums anew.
+    pub fn reset(&mut self) {
+        let floor = self.parameters.get_float("floor");
+
+        self.sub_block_position = 0;
+        for sum in self.sub_block_sums.iter_mut()
+        {
+            *sum = 0.0;
+        }
+        self.sub_blocks.clear();
+
+        self.integrated_blocks = Histogram::new();
+        self.range_blocks = Histogram::new();
+
+        self.momentary = floor;
+        self.short_term = floor;
+        self.integrated = floor;
+        self.range = 0.0;
+        self.momentary_max = floor;
+        self.short_term_max = floor;
+
+        self.output.clear();
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.filter = k_weighting(sample_rate);
+        self.sub_block_frames = ((sample_rate as f64 * SUB_BLOCK_SECONDS).round() as usize).max(1);
+    }
+
+    fn to_lufs(&self, power: f64) -> f32 {
+        let floor = self.parameters.get_float("floor");
+        if power <= 0.0
+        {
+            return floor;
+        }
+
+        (loudness(power) as f32).max(floor)
+    }
+
+    fn weight(&self, channel: usize) -> f64 {
+        self.weights.get(channel).cloned().unwrap_or(1.0) as f64
+    }
+
+    fn mean_of_latest(&self, count: usize) -> f64 {
+        self.sub_blocks.iter().rev().take(count).sum::<f64>() / count as f64
+    }
+
+    fn finish_sub_block(&mut self) {
+        let frames = self.sub_block_frames as f64;
+        let mut power = 0.0;
+        for ch in 0..self.sub_block_sums.len()
+        {
+            power += self.weight(ch) * self.sub_block_sums[ch] / frames;
+            self.sub_block_sums[ch] = 0.0;
+        }
+        self.sub_block_position = 0;
+
+        self.sub_blocks.push_back(power);
+        if self.sub_blocks.len() > SHORT_TERM_SUB_BLOCKS
+        {
+            self.sub_blocks.pop_front();
+        }
+
+        // Gating blocks of 400 ms overlap by 75%, so there's a new one with each sub-block.
+        if self.sub_blocks.len() >= MOMENTARY_SUB_BLOCKS
+        {
+            let momentary = self.mean_of_latest(MOMENTARY_SUB_BLOCKS);
+            self.integrated_blocks.add(momentary);
+            self.momentary = self.to_lufs(momentary);
+            self.momentary_max = self.momentary_max.max(self.momentary);
+
+            let floor = self.parameters.get_float("floor");
+            self.integrated = match self.integrated_blocks.gated_loudness()
+            {
+                Some(integrated) => (integrated as f32).max(floor),
+                None => floor,
+            };
+        }
+
+        if self.sub_blocks.len() >= SHORT_TERM_SUB_BLOCKS
+        {
+            let short_term = self.mean_of_latest(SHORT_TERM_SUB_BLOCKS);
+            self.range_blocks.add(short_term);
+            self.short_term = self.to_lufs(short_term);
+            self.short_term_max = self.short_term_max.max(self.short_term);
+            self.range = self.range_blocks.range().unwrap_or(0.0) as f32;
+        }
+
+        self.output.set("momentary", Value::Scalar(self.momentary));
+        self.output.set("short_term", Value::Scalar(self.short_term));
+        self.output.set("integrated", Value::Scalar(self.integrated));
+        self.output.set("range", Value::Scalar(self.range));
+        self.output.set("momentary_max", Value::Scalar(self.momentary_max));
+        self.output.set("short_term_max", Value::Scalar(self.short_term_max));
+    }
+}
+
+impl Default for Loudness {
+    fn default() -> Loudness {
+        Loudness::new()
+    }
+}
+
+impl Chainable for Loudness {
+    fn prepare(&mut self, format: &StreamFormat) {
+        if format.sample_rate > 0.0
+        {
+            self.set_sample_rate(format.sample_rate);
+        }
+        self.states = vec![[[0.0; 2]; 2]; format.channels];
+        self.sub_block_sums = vec![0.0; format.channels];
+        self.reset();
+    }
+
+    fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        if self.states.len() != buffer.len()
+        {
+            self.states = vec![[[0.0; 2]; 2]; buffer.len()];
+            self.sub_block_sums = vec![0.0; buffer.len()];
+            self.reset();
+        }
+
+        let frames = buffer.iter().map(|channel| channel.len()).min().unwrap_or(0);
+        for f in 0..frames
+        {
+            for (ch, channel) in buffer.iter().enumerate()
+            {
+                let mut x = channel[f] as f64;
+                for (c, state) in self.filter.iter().zip(self.states[ch].iter_mut())
+                {
+                    let y = c.b0 * x + state[0];
+                    state[0] = c.b1 * x - c.a1 * y + state[1];
+                    state[1] = c.b2 * x - c.a2 * y;
+                    x = y;
+                }
+                self.sub_block_sums[ch] += x * x;
+            }
+
+            self.sub_block_position += 1;
+            if self.sub_block_position >= self.sub_block_frames
+            {
+                self.finish_sub_block();
+            }
+        }
+    }
+
+    fn output(&self) -> &Output {
+        &self.output
+    }
+
+    fn parameters(&self) -> Option<&Parameters> {
+        Some(&self.parameters)
+    }
+
+    fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        Some(&mut self.parameters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analysis::analysis::{Arena, Chain};
+    use analysis::traits::Sourcable;
+    use analysis::generator_source::{Generator, Signal, Waveform};
+    use analysis::wav_source::WavSource;
+
+    use std::fs;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    // 1 kHz sines, each segment a level in dBFS lasting some seconds, as the EBU test signals are.
+    // A level of negative infinity is silence.
+    fn sines(segments: &[(f32, f32)]) -> Vec<f32> {
+        let mut samples = Vec::new();
+        for &(level, seconds) in segments.iter()
+        {
+            let mut signal = Signal::new(Waveform::Sine);
+            signal.amplitude = 10f32.powf(level / 20.0);
+            let frames = (seconds * SAMPLE_RATE as f32).round() as usize;
+            samples.extend(Generator::new(vec![signal], SAMPLE_RATE).unwrap().generate(frames).remove(0));
+        }
+        samples
+    }
+
+    // Writes the sines to a stereo 32 bit float WAV file.
+    fn write_test_signal(name: &str, segments: &[(f32, f32)]) -> String {
+        let samples = sines(segments);
+
+        let data_size = samples.len() as u32 * 2 * 4;
+        let mut bytes = Vec::new();
+        bytes.extend(b"RIFF".iter().cloned());
+        bytes.extend(le_u32(4 + 8 + 16 + 8 + data_size).iter().cloned());
+        bytes.extend(b"WAVEfmt ".iter().cloned());
+        bytes.extend(le_u32(16).iter().cloned());
+        bytes.extend([3, 0, 2, 0].iter().cloned());
+        bytes.extend(le_u32(SAMPLE_RATE).iter().cloned());
+        bytes.extend(le_u32(SAMPLE_RATE * 2 * 4).iter().cloned());
+        bytes.extend([8, 0, 32, 0].iter().cloned());
+        bytes.extend(b"data".iter().cloned());
+        bytes.extend(le_u32(data_size).iter().cloned());
+        for sample in samples.iter()
+        {
+            let sample = le_u32(sample.to_bits());
+            bytes.extend(sample.iter().cloned());
+            bytes.extend(sample.iter().cloned());
+        }
+
+        let path = std::env::temp_dir().join(format!("raa_loudness_{}_{}.wav", name, std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn le_u32(value: u32) -> [u8; 4] {
+        [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+    }
+
+    // Plays the file through a chain to a loudness meter, returning the integrated loudness and loudness range.
+    fn measure(path: &str) -> (f32, f32) {
+        let arena = Arc::new(RwLock::new(Arena::new()));
+        let source = Arc::new(RwLock::new(WavSource::new(path.to_string(), vec![0, 1])));
+        let source_id = arena.write().unwrap().add_sourcable(source.clone());
+        let meter = Arc::new(RwLock::new(Loudness::new()));
+        let meter_id = arena.write().unwrap().add_chainable(meter.clone());
+
+        let mut chain = Chain::new(arena.clone());
+        chain.set_source(source_id);
+        chain.add_node(meter_id);
+        let chain = Arc::new(RwLock::new(chain));
+        chain.write().unwrap().start(chain.clone());
+
+        while !source.read().unwrap().is_finished()
+        {
+            assert!(source.read().unwrap().get_and_clear_error().is_none());
+            thread::sleep(Duration::from_millis(10));
+        }
+        chain.write().unwrap().stop();
+        let _ = fs::remove_file(path);
+
+        let meter = meter.read().unwrap();
+        (meter.integrated(), meter.range())
+    }
+
+    // Feeds the sines to a meter as stereo in 10 ms blocks, calling `check` after each with the seconds fed so far.
+    fn meter<F>(segments: &[(f32, f32)], mut check: F) -> Loudness where F: FnMut(f32, &Loudness) {
+        let samples = sines(segments);
+        let mut meter = Loudness::new();
+        meter.prepare(&StreamFormat::new(SAMPLE_RATE as f32, 2));
+
+        let block = SAMPLE_RATE as usize / 100;
+        for (index, chunk) in samples.chunks(block).enumerate()
+        {
+            meter.update(&vec![chunk.to_vec(), chunk.to_vec()]);
+            check(((index + 1) * block) as f32 / SAMPLE_RATE as f32, &meter);
+        }
+        meter
+    }
+
+    #[test]
+    fn tech_3341_case_1() {
+        let (integrated, _) = measure(&write_test_signal("3341_1", &[(-23.0, 20.0)]));
+        assert!((integrated + 23.0).abs() <= 0.1, "{}", integrated);
+    }
+
+    #[test]
+    fn tech_3341_case_3() {
+        let (integrated, _) = measure(&write_test_signal("3341_3", &[(-36.0, 10.0), (-23.0, 60.0), (-36.0, 10.0)]));
+        assert!((integrated + 23.0).abs() <= 0.1, "{}", integrated);
+    }
+
+    #[test]
+    fn tech_3342_case_1() {
+        let (_, range) = measure(&write_test_signal("3342_1", &[(-20.0, 20.0), (-30.0, 20.0)]));
+        assert!((range - 10.0).abs() <= 1.0, "{}", range);
+    }
+
+    #[test]
+    fn tech_3341_case_2() {
+        let (integrated, _) = measure(&write_test_signal("3341_2", &[(-33.0, 20.0)]));
+        assert!((integrated + 33.0).abs() <= 0.1, "{}", integrated);
+    }
+
+    #[test]
+    fn tech_3341_case_4() {
+        let segments = [(-72.0, 10.0), (-36.0, 10.0), (-23.0, 60.0), (-36.0, 10.0), (-72.0, 10.0)];
+        let (integrated, _) = measure(&write_test_signal("3341_4", &segments));
+        assert!((integrated + 23.0).abs() <= 0.1, "{}", integrated);
+    }
+
+    #[test]
+    fn tech_3341_case_5() {
+        let (integrated, _) = measure(&write_test_signal("3341_5", &[(-26.0, 20.0), (-20.0, 20.1), (-26.0, 20.0)]));
+        assert!((integrated + 23.0).abs() <= 0.1, "{}", integrated);
+    }
+
+    #[test]
+    fn steady_sine_reads_the_same_momentary_and_short_term() {
+        meter(&[(-23.0, 5.0)], |seconds, meter| {
+            if seconds >= 0.4
+            {
+                assert!((meter.momentary() + 23.0).abs() <= 0.1, "{} s: {}", seconds, meter.momentary());
+            }
+            if seconds >= 3.0
+            {
+                assert!((meter.short_term() + 23.0).abs() <= 0.1, "{} s: {}", seconds, meter.short_term());
+            }
+        });
+    }
+
+    #[test]
+    fn alternating_bursts_keep_the_short_term_constant() {
+        // As Tech 3341 case 9: every 3 s window has 1.34 s at -20 dBFS and 1.66 s at -30 dBFS, which is -23 LUFS.
+        let mut segments = Vec::new();
+        for _ in 0..5
+        {
+            segments.push((-20.0, 1.34));
+            segments.push((-30.0, 1.66));
+        }
+        meter(&segments, |seconds, meter| {
+            if seconds >= 3.0
+            {
+                assert!((meter.short_term() + 23.0).abs() <= 0.1, "{} s: {}", seconds, meter.short_term());
+            }
+        });
+    }
+
+    #[test]
+    fn bursts_set_the_maximums() {
+        // As Tech 3341 case 10: a burst as long as the window between silences reads -23 LUFS at its loudest.
+        let silence = f32::NEG_INFINITY;
+        let short_term = meter(&[(silence, 2.0), (-23.0, 3.0), (silence, 2.0)], |_, _| {});
+        assert!((short_term.short_term_max() + 23.0).abs() <= 0.1, "{}", short_term.short_term_max());
+
+        let momentary = meter(&[(silence, 1.0), (-23.0, 0.4), (silence, 1.0)], |_, _| {});
+        assert!((momentary.momentary_max() + 23.0).abs() <= 0.1, "{}", momentary.momentary_max());
+        assert!(momentary.short_term_max() < -26.0, "{}", momentary.short_term_max());
+    }
+
+    #[test]
+    fn tech_3342_case_2() {
+        let (_, range) = measure(&write_test_signal("3342_2", &[(-20.0, 20.0), (-15.0, 20.0)]));
+        assert!((range - 5.0).abs() <= 1.0, "{}", range);
+    }
+
+    #[test]
+    fn tech_3342_case_3() {
+        let (_, range) = measure(&write_test_signal("3342_3", &[(-40.0, 20.0), (-20.0, 20.0)]));
+        assert!((range - 20.0).abs() <= 1.0, "{}", range);
+    }
+
+    #[test]
+    fn tech_3342_case_4() {
+        let segments = [(-50.0, 20.0), (-35.0, 20.0), (-20.0, 20.0), (-35.0, 20.0), (-50.0, 20.0)];
+        let (_, range) = measure(&write_test_signal("3342_4", &segments));
+        assert!((range - 15.0).abs() <= 1.0, "{}", range);
+    }
+}
@@ -1,5 +1,28 @@
+#[allow(clippy::module_inception)]
 pub mod analysis;
 pub mod traits;
 pub mod pa_source;
 pub mod soundio_source;
-pub mod rms;
\ No newline at end of file
+pub mod rms;
+pub mod wav_source;
+pub mod generator_source;
+pub mod fft;
+pub mod biquad;
+pub mod gain;
+pub mod graph;
+pub mod spectral_difference;
+pub mod db;
+pub mod parameters;
+pub mod device;
+pub mod stream;
+pub mod ring_buffer;
+pub mod capture;
+pub mod reframer;
+pub mod peak;
+pub mod output;
+pub mod loudness;
+pub mod pitch;
+pub mod onset;
+pub mod beat;
+pub mod spectral_features;
+pub mod interpolation;
\ No newline at end of file
@@ -0,0 +1,380 @@
+use analysis::traits::Chainable;
+use analysis::parameters::{Parameters, ParameterError, ParameterValue};
+use analysis::output::{Output, Value};
+use analysis::stream::{StreamFormat, BlockContext};
+use analysis::fft::FFT;
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+// Time constant of the peak the detection function is normalized by, in seconds.
+const NORMALIZATION_SECONDS: f32 = 10.0;
+// Keeps the normalization from amplifying what little is left once the audio goes quiet.
+const MIN_NORMALIZATION_PEAK: f32 = 1e-6;
+
+/// Function of the spectrum that rises at onsets. Bello et al. 2005 compares them.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DetectionFunction {
+    /// Rise of the energy of the frame. Cheap, but only finds onsets that are louder than what came before.
+    Energy = 0,
+    /// Energy weighted by frequency, Masri 1996. Good for percussive onsets.
+    HighFrequencyContent = 1,
+    /// Sum of the rises of the magnitude of each bin. A good default for most material.
+    SpectralFlux = 2,
+    /// Distance of each bin from where its magnitude and phase were heading, Duxbury et al. 2003.
+    /// Also finds soft, pitched onsets.
+    ComplexDomain = 3,
+}
+
+impl DetectionFunction {
+    pub fn from_id(id: u8) -> Option<DetectionFunction> {
+        match id
+        {
+            0 => Some(DetectionFunction::Energy),
+            1 => Some(DetectionFunction::HighFrequencyContent),
+            2 => Some(DetectionFunction::SpectralFlux),
+            3 => Some(DetectionFunction::ComplexDomain),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DetectionFunction> {
+        match name
+        {
+            "energy" => Some(DetectionFunction::Energy),
+            "hfc" => Some(DetectionFunction::HighFrequencyContent),
+            "flux" => Some(DetectionFunction::SpectralFlux),
+            "complex" => Some(DetectionFunction::ComplexDomain),
+            _ => None,
+        }
+    }
+}
+
+/// A detected onset.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct OnsetEvent {
+    /// Stream position of the start of the frame the onset was found in.
+    pub frame: u64,
+    /// The same in seconds from when the source started.
+    pub time: f64,
+    /// Value of the normalized detection function at the onset.
+    pub strength: f32,
+}
+
+// Spectrum of the previous frames of a channel.
+struct Channel {
+    magnitudes: Vec<f32>,
+    phases: Vec<f32>,
+    previous_phases: Vec<f32>,
+    energy: f32,
+}
+
+impl Channel {
+    fn new(bins: usize) -> Channel {
+        Channel {
+            magnitudes: vec![0.0; bins],
+            phases: vec![0.0; bins],
+            previous_phases: vec![0.0; bins],
+            energy: 0.0,
+        }
+    }
+}
+
+// Wraps a phase to -PI..PI.
+fn wrap(phase: f32) -> f32 {
+    phase - 2.0 * PI * ((phase + PI) / (2.0 * PI)).floor()
+}
+
+/// Onsets of notes and hits in the audio, from all channels together.
+/// Audio is transformed in frames of `size` samples, a new frame every `hop` samples, and each frame gets a value of the
+/// detection function. Onsets are peaks of it, normalized by its recent peak, that rise above an adaptive threshold.
+/// Output is "detection", the normalized detection function of the latest frame, "threshold", what it must exceed to
+/// be an onset, "onset", the strength of the onset found in the latest frame or 0 if there was none, and "last_onset",
+/// the time in seconds of the latest onset so far or -1 before the first. Onsets are found a frame late, as a peak is
+/// only known to be one when the next frame is lower. The onsets themselves are in `onsets`.
+/// Parameters are "function", the id of a `DetectionFunction`, "delta" and "multiplier", the threshold being delta plus
+/// the multiplier times the median of the detection function over the latest "window" milliseconds, "min_interval" in
+/// milliseconds between onsets, "silence", the level in dBFS under which frames can't be onsets, and "compression",
+/// which takes the magnitudes to log(1 + compression * magnitude) so that quiet onsets count too. 0 leaves them linear.
+pub struct Onset {
+    parameters: Parameters,
+    sample_rate: f32,
+
+    // Transforms the frames, Hann windowed.
+    fft: FFT,
+
+    channels: Vec<Channel>,
+    peak: f32,
+    // Detection function of the latest frames and where they started, newest last.
+    history: VecDeque<(f32, u64)>,
+    last_onset: Option<u64>,
+    // Stream position for updates that don't come with a block context.
+    position: u64,
+
+    detection: f32,
+    threshold: f32,
+    onsets: Vec<OnsetEvent>,
+    output: Output,
+}
+
+impl Onset {
+    pub fn new(size: usize) -> Onset {
+        assert!(size.is_power_of_two() && size >= 2, "Onset frame size must be a power of two");
+
+        let mut parameters = Parameters::new();
+        parameters.add_float("function", DetectionFunction::SpectralFlux as u8 as f32,
+                             0.0, DetectionFunction::ComplexDomain as u8 as f32);
+        parameters.add_float("delta", 0.05, 0.0, 1.0);
+        parameters.add_float("multiplier", 1.0, 0.0, 10.0);
+        parameters.add_float("window", 250.0, 10.0, 5000.0);
+        parameters.add_float("min_interval", 50.0, 0.0, 5000.0);
+        parameters.add_float("silence", -60.0, -120.0, 0.0);
+        parameters.add_float("compression", 100.0, 0.0, 10_000.0);
+
+        let mut fft = FFT::new(size);
+        fft.set_hop(size / 2).unwrap();
+
+        Onset {
+            parameters,
+            sample_rate: 44_100.0,
+
+            fft,
+
+            channels: Vec::new(),
+            peak: 0.0,
+            history: VecDeque::new(),
+            last_onset: None,
+            position: 0,
+
+            detection: 0.0,
+            threshold: 0.0,
+            onsets: Vec::new(),
+            output: Output::new(),
+        }
+    }
+
+    /// Amount of new samples between consecutive frames, at least 1. Half the size by default.
+    pub fn set_hop(&mut self, hop: usize) -> Result<(), ParameterError> {
+        self.fft.set_hop(hop)
+    }
+
+    pub fn set_function(&mut self, function: DetectionFunction) -> Result<(), ParameterError> {
+        self.parameters.set_float("function", function as u8 as f32)
+    }
+
+    /// Both are checked before either is set, so that an error leaves the threshold as it was.
+    pub fn set_threshold(&mut self, delta: f32, multiplier: f32) -> Result<(), ParameterError> {
+        self.parameters.check("delta", ParameterValue::Float(delta))?;
+        self.parameters.check("multiplier", ParameterValue::Float(multiplier))?;
+        self.parameters.set_float("delta", delta)?;
+        self.parameters.set_float("multiplier", multiplier)
+    }
+
+    pub fn set_min_interval_ms(&mut self, min_interval: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("min_interval", min_interval)
+    }
+
+    pub fn function(&self) -> DetectionFunction {
+        DetectionFunction::from_id(self.parameters.get_float("function").round() as u8)
+            .unwrap_or(DetectionFunction::SpectralFlux)
+    }
+
+    pub fn size(&self) -> usize {
+        self.fft.size()
+    }
+
+    pub fn hop(&self) -> usize {
+        self.fft.hop()
+    }
+
+    /// Normalized detection function of the latest frame.
+    pub fn detection(&self) -> f32 {
+        self.detection
+    }
+
+    pub fn threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    /// Onsets found in the latest update.
+    pub fn onsets(&self) -> &Vec<OnsetEvent> {
+        &self.onsets
+    }
+
+    /// Time of the latest onset so far in seconds from when the source started, if there's been one.
+    pub fn last_onset_time(&self) -> Option<f64> {
+        self.last_onset.map(|frame| frame as f64 / self.sample_rate as f64)
+    }
+
+    fn reset(&mut self) {
+        self.channels = Vec::new();
+        self.peak = 0.0;
+        self.history.clear();
+        self.last_onset = None;
+    }
+
+    // Detection function of a frame, summed over the channels, and whether the frame is loud enough to be an onset.
+    fn detect(&mut self, buffer: &[Vec<f32>]) -> (f32, bool) {
+        let size = self.fft.size();
+        let bins = self.fft.bins();
+        if self.channels.len() != buffer.len()
+        {
+            self.channels = (0..buffer.len()).map(|_| Channel::new(bins)).collect();
+        }
+
+        let function = self.function();
+        let compression = self.parameters.get_float("compression");
+        let silence = 10f32.powf(self.parameters.get_float("silence") / 20.0);
+
+        let frame: Vec<Vec<f32>> = buffer.iter().map(|channel| channel[channel.len().saturating_sub(size)..].to_vec()).collect();
+        self.fft.update(&frame);
+
+        let mut detection = 0.0f32;
+        let mut loud = false;
+        for (ch, samples) in frame.iter().enumerate()
+        {
+            let square_sum: f32 = samples.iter().map(|x| x * x).sum();
+            if !samples.is_empty() && (square_sum / samples.len() as f32).sqrt() >= silence
+            {
+                loud = true;
+            }
+
+            let channel = &mut self.channels[ch];
+            let (magnitudes, phases) = (self.fft.magnitudes(ch), self.fft.phases(ch));
+            let mut energy = 0.0f32;
+            for k in 0..bins
+            {
+                let magnitude = magnitudes[k];
+                let magnitude = if compression > 0.0 { (1.0 + compression * magnitude).ln() } else { magnitude };
+                let phase = phases[k];
+                energy += magnitude * magnitude;
+
+                detection += match function
+                {
+                    DetectionFunction::Energy => 0.0,
+                    DetectionFunction::HighFrequencyContent => k as f32 * magnitude * magnitude / bins as f32,
+                    DetectionFunction::SpectralFlux => (magnitude - channel.magnitudes[k]).max(0.0),
+                    DetectionFunction::ComplexDomain => {
+                        // Where the bin would be if it kept its magnitude and the rate its phase turns at.
+                        let target_phase = wrap(2.0 * channel.phases[k] - channel.previous_phases[k]);
+                        let (target_real, target_imag) = (channel.magnitudes[k] * target_phase.cos(),
+                                                          channel.magnitudes[k] * target_phase.sin());
+                        let (actual_real, actual_imag) = (magnitude * phase.cos(), magnitude * phase.sin());
+                        ((actual_real - target_real).powi(2) + (actual_imag - target_imag).powi(2)).sqrt()
+                    },
+                };
+
+                channel.magnitudes[k] = magnitude;
+                channel.previous_phases[k] = channel.phases[k];
+                channel.phases[k] = phase;
+            }
+
+            if function == DetectionFunction::Energy
+            {
+                detection += (energy - channel.energy).max(0.0);
+            }
+            channel.energy = energy;
+        }
+
+        (detection, loud)
+    }
+
+    fn analyse(&mut self, buffer: &[Vec<f32>], frame: u64) {
+        self.onsets = Vec::new();
+        let length = buffer.iter().map(|channel| channel.len()).max().unwrap_or(0);
+        if length == 0
+        {
+            return;
+        }
+        // Framed audio ends with the frame to analyse.
+        let frame = frame + length.saturating_sub(self.fft.size()) as u64;
+
+        let (detection, loud) = self.detect(buffer);
+
+        // Normalize by the recent peak, so that the threshold works the same for any detection function and level.
+        let hop_seconds = self.fft.hop() as f32 / self.sample_rate;
+        self.peak = (self.peak * (-hop_seconds / NORMALIZATION_SECONDS).exp()).max(detection);
+        self.detection = if loud { detection / self.peak.max(MIN_NORMALIZATION_PEAK) } else { 0.0 };
+
+        let window = ((self.parameters.get_float("window") / 1000.0 / hop_seconds).round() as usize).max(3);
+        self.history.push_back((self.detection, frame));
+        while self.history.len() > window
+        {
+            self.history.pop_front();
+        }
+
+        let mut values: Vec<f32> = self.history.iter().map(|&(value, _)| value).collect();
+        values.sort_by(|a, b| a.total_cmp(b));
+        let median = values[values.len() / 2];
+        self.threshold = self.parameters.get_float("delta") + self.parameters.get_float("multiplier") * median;
+
+        // The frame before the latest is an onset if it's a peak above the threshold.
+        let count = self.history.len();
+        if count >= 3
+        {
+            let (previous, _) = self.history[count - 3];
+            let (candidate, candidate_frame) = self.history[count - 2];
+            let (next, _) = self.history[count - 1];
+
+            let min_interval = (self.parameters.get_float("min_interval") / 1000.0 * self.sample_rate) as u64;
+            let apart = self.last_onset.map_or(true, |last| candidate_frame >= last + min_interval);
+
+            if candidate > previous && candidate >= next && candidate > self.threshold && apart
+            {
+                self.last_onset = Some(candidate_frame);
+                self.onsets.push(OnsetEvent {
+                    frame: candidate_frame,
+                    time: candidate_frame as f64 / self.sample_rate as f64,
+                    strength: candidate,
+                });
+            }
+        }
+
+        self.output.set("detection", Value::Scalar(self.detection));
+        self.output.set("threshold", Value::Scalar(self.threshold));
+        self.output.set("onset", Value::Scalar(self.onsets.last().map_or(0.0, |onset| onset.strength)));
+        self.output.set("last_onset", Value::Scalar(self.last_onset_time().map_or(-1.0, |time| time as f32)));
+    }
+}
+
+impl Chainable for Onset {
+    fn prepare(&mut self, format: &StreamFormat) {
+        if format.sample_rate > 0.0
+        {
+            self.sample_rate = format.sample_rate;
+        }
+        self.reset();
+    }
+
+    fn process(&mut self, buffer: &Vec<Vec<f32>>, context: &BlockContext) {
+        if context.sample_rate > 0.0
+        {
+            self.sample_rate = context.sample_rate;
+        }
+        self.position = context.frame + self.fft.hop() as u64;
+        self.analyse(buffer, context.frame);
+    }
+
+    /// Expects a frame of `size` samples, e.g. from the chain's framing.
+    fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        let position = self.position;
+        self.position += self.fft.hop() as u64;
+        self.analyse(buffer, position);
+    }
+
+    fn output(&self) -> &Output {
+        &self.output
+    }
+
+    fn framing(&self) -> Option<(usize, usize)> {
+        self.fft.framing()
+    }
+
+    fn parameters(&self) -> Option<&Parameters> {
+        Some(&self.parameters)
+    }
+
+    fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        Some(&mut self.parameters)
+    }
+}
@@ -0,0 +1,180 @@
+/// A single result of a node.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    /// One value for the whole stream.
+    Scalar(f32),
+    /// One value per channel.
+    Channels(Vec<f32>),
+    /// Several values that belong together, e.g. a spectrum.
+    Vector(Vec<f32>),
+    /// One vector per channel, e.g. the spectrum of each channel.
+    ChannelVectors(Vec<Vec<f32>>),
+}
+
+impl Value {
+    /// Amount of numbers in the value.
+    pub fn len(&self) -> usize {
+        match *self
+        {
+            Value::Scalar(_) => 1,
+            Value::Channels(ref values) | Value::Vector(ref values) => values.len(),
+            Value::ChannelVectors(ref vectors) => vectors.iter().map(|vector| vector.len()).sum(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn flatten_into(&self, flat: &mut Vec<f32>) {
+        match *self
+        {
+            Value::Scalar(value) => flat.push(value),
+            Value::Channels(ref values) | Value::Vector(ref values) => flat.extend(values.iter().cloned()),
+            Value::ChannelVectors(ref vectors) => {
+                for vector in vectors.iter()
+                {
+                    flat.extend(vector.iter().cloned());
+                }
+            }
+        }
+    }
+}
+
+/// Results of a node's latest update, each labelled, in the order the node set them.
+/// Nodes set the same labels in the same order every update, so that the layout of a node's output is fixed.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Output {
+    values: Vec<(&'static str, Value)>,
+    updates: u64,
+}
+
+impl Output {
+    pub fn new() -> Output {
+        Output { values: Vec::new(), updates: 0 }
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.updates += 1;
+    }
+
+    /// Counts the changes to the output. A node that has nothing new leaves its output be, so whoever reads the output
+    /// can tell from the count whether it has changed since the last time.
+    pub fn updates(&self) -> u64 {
+        self.updates
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Sets the value of a label, replacing the old one, or adding it after the others if there's none.
+    pub fn set(&mut self, label: &'static str, value: Value) {
+        match self.values.iter().position(|&(existing, _)| existing == label)
+        {
+            Some(index) => self.values[index].1 = value,
+            None => self.values.push((label, value)),
+        }
+        self.updates += 1;
+    }
+
+    pub fn get(&self, label: &str) -> Option<&Value> {
+        self.values.iter().find(|&&(existing, _)| existing == label).map(|(_, value)| value)
+    }
+
+    /// Labels and values in order.
+    pub fn values(&self) -> &Vec<(&'static str, Value)> {
+        &self.values
+    }
+
+    pub fn labels(&self) -> Vec<&'static str> {
+        self.values.iter().map(|&(label, _)| label).collect()
+    }
+
+    /// Value of the label if it's a scalar.
+    pub fn scalar(&self, label: &str) -> Option<f32> {
+        match self.get(label)
+        {
+            Some(&Value::Scalar(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Values of the label if it's per channel.
+    pub fn channels(&self, label: &str) -> Option<&Vec<f32>> {
+        match self.get(label)
+        {
+            Some(Value::Channels(values)) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Values of the label if it's a vector.
+    pub fn vector(&self, label: &str) -> Option<&Vec<f32>> {
+        match self.get(label)
+        {
+            Some(Value::Vector(values)) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Vectors of the label if it has one per channel.
+    pub fn channel_vectors(&self, label: &str) -> Option<&Vec<Vec<f32>>> {
+        match self.get(label)
+        {
+            Some(Value::ChannelVectors(vectors)) => Some(vectors),
+            _ => None,
+        }
+    }
+
+    /// Values as channels of audio, which is what analyzers pass on to the nodes connected to them.
+    /// Each channel of the per-channel values is a channel, with its values of each label in order, e.g. the spectrum
+    /// of each channel of an FFT. Values for the whole stream follow as a channel of their own.
+    pub fn signal(&self) -> Vec<Vec<f32>> {
+        let channels = self.values.iter().map(|(_, value)| match *value
+        {
+            Value::Channels(ref values) => values.len(),
+            Value::ChannelVectors(ref vectors) => vectors.len(),
+            _ => 0,
+        }).max().unwrap_or(0);
+
+        let mut signal = vec![Vec::new(); channels];
+        let mut stream = Vec::new();
+        for (_, value) in self.values.iter()
+        {
+            match *value
+            {
+                Value::Channels(ref values) => {
+                    for (channel, &value) in signal.iter_mut().zip(values.iter())
+                    {
+                        channel.push(value);
+                    }
+                },
+                Value::ChannelVectors(ref vectors) => {
+                    for (channel, vector) in signal.iter_mut().zip(vectors.iter())
+                    {
+                        channel.extend(vector.iter().cloned());
+                    }
+                },
+                _ => value.flatten_into(&mut stream),
+            }
+        }
+
+        if !stream.is_empty()
+        {
+            signal.push(stream);
+        }
+        signal
+    }
+
+    /// All values one after another, in order.
+    pub fn flatten(&self) -> Vec<f32> {
+        let mut flat = Vec::with_capacity(self.values.iter().map(|(_, value)| value.len()).sum());
+        for (_, value) in self.values.iter()
+        {
+            value.flatten_into(&mut flat);
+        }
+        flat
+    }
+}
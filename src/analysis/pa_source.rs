@@ -2,15 +2,21 @@
 extern crate portaudio as pa;
 
 use analysis::traits::Sourcable;
-use analysis::analysis::Chain;
+use analysis::analysis::Chains;
+use analysis::device::{DeviceInfo, SampleFormat, SourceConfig};
+use analysis::stream::StreamFormat;
+use analysis::capture::{Capture, StreamStats};
+use std::collections::HashMap;
+
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::collections::HashMap;
+use std::sync::Mutex;
 
-const SAMPLE_RATE: f64 = 44_100.0;
-const FRAMES: u32 = 256;
 const INTERLEAVED: bool = true;
 
+// PortAudio can only be asked about single sample rates, so these are checked for device info.
+const COMMON_SAMPLE_RATES: [f64; 8] = [8_000.0, 16_000.0, 22_050.0, 32_000.0, 44_100.0, 48_000.0, 88_200.0, 96_000.0];
+
 lazy_static! {
     static ref PORTAUDIO: pa::PortAudio = {
         let pa = pa::PortAudio::new();
@@ -23,98 +29,155 @@ lazy_static! {
     };
 }
 
+// Description of a device, in the form get_devices gives it.
+fn device_info(device_index: pa::DeviceIndex) -> DeviceInfo
+{
+    let input_info = PORTAUDIO.device_info(device_index).unwrap();
+    let backend = PORTAUDIO.host_api_info(input_info.host_api).unwrap().name.to_string();
+
+    let mut info = DeviceInfo::new(input_info.name.to_string(), input_info.max_input_channels);
+    for &sample_rate in COMMON_SAMPLE_RATES.iter()
+    {
+        let input_params = pa::StreamParameters::<f32>::new(device_index, input_info.max_input_channels,
+                                                            INTERLEAVED, input_info.default_low_input_latency);
+        if PORTAUDIO.is_input_format_supported(input_params, sample_rate).is_ok()
+        {
+            info.sample_rates.push((sample_rate as i32, sample_rate as i32));
+        }
+    }
+    info.default_sample_rate = input_info.default_sample_rate as i32;
+    // Samples are always read as floats, PortAudio converts to them.
+    info.formats = vec![SampleFormat::F32LE];
+    info.is_default = PORTAUDIO.default_input_device().ok() == Some(device_index);
+    info.latency = (input_info.default_low_input_latency, input_info.default_high_input_latency);
+    info.backend = backend;
+    info
+}
+
+// PortAudio streams can be used from any thread, just not from several at once, which the source's mutex sees to.
+struct PAStream(pa::Stream<pa::NonBlocking, pa::Input<f32>>);
+unsafe impl Send for PAStream {}
+
+/// Input from a PortAudio device. The devices are the ones present when PortAudio was initialized, see `get_devices`.
 pub struct PASource {
     device: u32,
     channels: Vec<i32>,
+    config: SourceConfig,
 
-    stream: Option<pa::Stream<pa::NonBlocking, pa::Input<f32>>>,
+    // Locked, as the source is shared between threads and readers of it may check on the stream at the same time.
+    stream: Mutex<Option<PAStream>>,
+    chains: Chains,
+    // Runs the chains on the analysis thread. Kept after stopping, for the stats.
+    capture: Option<Capture>,
 
-    error: String
+    error: Arc<RwLock<String>>
 }
 
 impl PASource {
     pub fn new(device: String, channels: Vec<i32>) -> PASource {
         PASource {
             device: device.parse::<u32>().unwrap(),
-            channels: channels,
+            channels,
+            config: SourceConfig::new(),
 
-            stream: Option::None,
+            stream: Mutex::new(None),
+            chains: Chains::new(),
+            capture: Option::None,
 
-            error: "".to_string()
+            error: Arc::new(RwLock::new("".to_string()))
         }
     }
+
+    /// Used the next time the source is started.
+    pub fn set_config(&mut self, config: SourceConfig) {
+        self.config = config;
+    }
 }
 
 impl Sourcable for PASource {
-    fn start(&mut self, chain: Arc<RwLock<Chain>>) -> () {
-        let device_info = PORTAUDIO.device_info(pa::DeviceIndex { 0: self.device }).unwrap();
+    fn start(&mut self) {
+        let config = match self.config.negotiate(&device_info(pa::DeviceIndex(self.device)))
+        {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Error configuring stream: {}", e);
+                *self.error.write().unwrap() = e;
+                return;
+            }
+        };
+
+        self.chains.set_format(StreamFormat::new(config.sample_rate as f32, self.channels.len()));
+
+        let device_info = PORTAUDIO.device_info(pa::DeviceIndex(self.device)).unwrap();
 
-        let input_params = pa::StreamParameters::<f32>::new(pa::DeviceIndex { 0: self.device },
+        let input_params = pa::StreamParameters::<f32>::new(pa::DeviceIndex(self.device),
                                                             device_info.max_input_channels,
                                                             INTERLEAVED,
-                                                            0.0f64);
+                                                            config.latency);
 
+        // A second of audio can wait for the analysis thread.
         let channels = self.channels.to_vec();
-        let audio_callback = move |pa::InputStreamCallbackArgs { buffer, frames, .. }| {
-            // Unleave
-            let mut unleaved_buffer:Vec<Vec<f32>> = Vec::new();
+        let (capture, mut writer) = Capture::start(self.chains.clone(), channels.len(), config.sample_rate as usize);
+        self.capture = Some(capture);
 
-            // Initialize with empty arrays for each channel
-            for _ in 0..channels.len()
+        let device_channels = device_info.max_input_channels as usize;
+        let audio_callback = move |pa::InputStreamCallbackArgs { buffer, frames, flags, .. }| {
+            if flags.contains(pa::stream::callback_flags::INPUT_OVERFLOW)
             {
-                unleaved_buffer.push(Vec::new());
+                writer.counters().overrun();
             }
-            // Iterate through the whole interleaved buffer, moving it to unleaved buffer.
-            let mut i = 0i32;
-            while i < buffer.len() as i32
+            if flags.contains(pa::stream::callback_flags::INPUT_UNDERFLOW)
             {
-                // Iterate through all the channels we want.
-                for j in 0..channels.len()
-                {
-                    // Since 'i' points to 1st channel, we'll take element i + channel index (starting from 0) from the interleaved buffer.
-                    unleaved_buffer[j].push(buffer[i as usize + channels[j] as usize]);
-                }
-
-                // Increase index to next set of channels. I.e. index points to 1st interleaved channel for each sample frame.
-                i += device_info.max_input_channels;
+                writer.counters().underrun();
             }
 
-            chain.write().unwrap().source_cb(unleaved_buffer, frames);
+            // Frame f of the channel we want starts at f * device_channels in the interleaved buffer.
+            writer.write_frames(frames, |f, ch| buffer[f * device_channels + channels[ch] as usize]);
 
-            if chain.write().unwrap().running == true
-            {
-                pa::Continue
-            }
-            else {
-                pa::Complete
-            }
+            pa::Continue
         };
 
 
-        let settings = pa::InputStreamSettings::new(input_params, SAMPLE_RATE, FRAMES);
+        let settings = pa::InputStreamSettings::new(input_params, config.sample_rate as f64, config.frames);
         let mut stream = PORTAUDIO.open_non_blocking_stream(settings, audio_callback).unwrap();
         println!("Starting stream for realz..");
 
         let _ = stream.start();
 
-        self.stream = Option::Some(stream);
+        *self.stream.lock().unwrap() = Some(PAStream(stream));
+    }
+
+    fn stop(&mut self) {
+        if let Some(mut stream) = self.stream.lock().unwrap().take()
+        {
+            let _ = stream.0.stop();
+        }
+        if let Some(ref capture) = self.capture
+        {
+            capture.stop();
+        }
     }
 
-    fn stop(&mut self) -> () {}
+    fn chains(&self) -> &Chains {
+        &self.chains
+    }
 
     fn is_active(&self) -> bool
     {
-        match self.stream
+        match *self.stream.lock().unwrap()
         {
-            Some(ref stream) => return stream.is_active().unwrap(),
-            None => return false
+            Some(ref stream) => stream.0.is_active().unwrap(),
+            None => false
         }
     }
 
-    fn get_devices() -> Result<HashMap<String, (String, i32)>, ()> where Self: Sized
+    fn get_devices() -> Result<HashMap<String, DeviceInfo>, ()> where Self: Sized
     {
         let mut devices = HashMap::new();
 
+        // PortAudio only enumerates devices when it's initialized, so devices plugged in later don't show up here.
+        // Enumerating again would mean terminating PortAudio, which closes the streams of every PortAudio source.
+        // Hot-plugging is left to SoundioSource, which is what the server lists devices with.
         let default_host = PORTAUDIO.default_host_api().unwrap();
 
         for i in 0..PORTAUDIO.host_api_info(default_host).unwrap().device_count {
@@ -127,15 +190,30 @@ impl Sourcable for PASource {
                 continue;
             }
 
-            devices.insert(device_index.0.to_string(),
-                           (input_info.name.to_string(), input_info.max_input_channels));
+            devices.insert(device_index.0.to_string(), device_info(device_index));
         }
 
-        return Ok(devices);
+        Ok(devices)
+    }
+
+    fn stats(&self) -> StreamStats
+    {
+        match self.capture
+        {
+            Some(ref capture) => capture.stats(),
+            None => StreamStats::default(),
+        }
     }
 
     fn get_and_clear_error(&self) -> Option<String>
     {
-        return None;
+        let error_clone = self.error.read().unwrap().clone();
+        if error_clone.is_empty()
+        {
+            return None;
+        }
+        *self.error.write().unwrap() = "".to_string();
+
+        Some(error_clone)
     }
 }
@@ -0,0 +1,194 @@
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ParameterValue {
+    Float(f32),
+    Boolean(bool),
+}
+
+#[derive(Clone, Debug)]
+pub struct Parameter {
+    pub name: String,
+    pub value: ParameterValue,
+    pub default: ParameterValue,
+    /// Allowed range of float parameters. Unused for booleans.
+    pub min: f32,
+    pub max: f32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ParameterError {
+    UnknownParameter(String),
+    /// A float was given for a boolean parameter or the other way around.
+    WrongType(String),
+    /// Name, the rejected value and the allowed range.
+    OutOfRange(String, f32, f32, f32),
+}
+
+impl fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self
+        {
+            ParameterError::UnknownParameter(ref name) => write!(f, "Unknown parameter {}", name),
+            ParameterError::WrongType(ref name) => write!(f, "Wrong type of value for parameter {}", name),
+            ParameterError::OutOfRange(ref name, value, min, max) =>
+                write!(f, "Value {} for parameter {} is out of range {} to {}", value, name, min, max),
+        }
+    }
+}
+
+fn mark_changed(changed: &mut Vec<String>, name: &str) {
+    if !changed.iter().any(|changed| changed == name)
+    {
+        changed.push(name.to_string());
+    }
+}
+
+/// Named parameters of a node, that can be changed while audio is running.
+/// Nodes pick up changes with `take_changed` when they next update.
+pub struct Parameters {
+    parameters: Vec<Parameter>,
+    // Names of the parameters changed since the node last checked.
+    changed: Vec<String>,
+}
+
+impl Parameters {
+    pub fn new() -> Parameters {
+        Parameters {
+            parameters: Vec::new(),
+            changed: Vec::new(),
+        }
+    }
+
+    pub fn add_float(&mut self, name: &str, default: f32, min: f32, max: f32) {
+        self.parameters.push(Parameter {
+            name: name.to_string(),
+            value: ParameterValue::Float(default),
+            default: ParameterValue::Float(default),
+            min,
+            max,
+        });
+    }
+
+    pub fn add_boolean(&mut self, name: &str, default: bool) {
+        self.parameters.push(Parameter {
+            name: name.to_string(),
+            value: ParameterValue::Boolean(default),
+            default: ParameterValue::Boolean(default),
+            min: 0.0,
+            max: 1.0,
+        });
+    }
+
+    /// All parameters in the order they were added.
+    pub fn list(&self) -> &Vec<Parameter> {
+        &self.parameters
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Parameter> {
+        self.parameters.iter().find(|parameter| parameter.name == name)
+    }
+
+    /// Value of a float parameter. Panics if the node didn't add one with this name.
+    pub fn get_float(&self, name: &str) -> f32 {
+        match self.get(name).map(|parameter| parameter.value)
+        {
+            Some(ParameterValue::Float(value)) => value,
+            _ => panic!("No float parameter {}", name),
+        }
+    }
+
+    /// Value of a boolean parameter. Panics if the node didn't add one with this name.
+    pub fn get_boolean(&self, name: &str) -> bool {
+        match self.get(name).map(|parameter| parameter.value)
+        {
+            Some(ParameterValue::Boolean(value)) => value,
+            _ => panic!("No boolean parameter {}", name),
+        }
+    }
+
+    pub fn set_float(&mut self, name: &str, value: f32) -> Result<(), ParameterError> {
+        self.set(name, ParameterValue::Float(value))
+    }
+
+    pub fn set_boolean(&mut self, name: &str, value: bool) -> Result<(), ParameterError> {
+        self.set(name, ParameterValue::Boolean(value))
+    }
+
+    /// Checks that `set` would accept the value, without setting it.
+    /// For setters of several parameters, so that they can check all the values before setting any.
+    pub fn check(&self, name: &str, value: ParameterValue) -> Result<(), ParameterError> {
+        let parameter = match self.get(name)
+        {
+            Some(parameter) => parameter,
+            None => return Err(ParameterError::UnknownParameter(name.to_string())),
+        };
+
+        match (parameter.value, value)
+        {
+            (ParameterValue::Float(_), ParameterValue::Float(new_value)) => {
+                if !(new_value >= parameter.min && new_value <= parameter.max)
+                {
+                    return Err(ParameterError::OutOfRange(name.to_string(), new_value, parameter.min, parameter.max));
+                }
+            },
+            (ParameterValue::Boolean(_), ParameterValue::Boolean(_)) => (),
+            _ => return Err(ParameterError::WrongType(name.to_string())),
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, name: &str, value: ParameterValue) -> Result<(), ParameterError> {
+        self.check(name, value)?;
+        let parameter = self.parameters.iter_mut().find(|parameter| parameter.name == name).unwrap();
+
+        if parameter.value != value
+        {
+            parameter.value = value;
+            mark_changed(&mut self.changed, name);
+        }
+
+        Ok(())
+    }
+
+    /// Changes the allowed range of a float parameter, clamping its value into the new range.
+    pub fn set_range(&mut self, name: &str, min: f32, max: f32) {
+        if let Some(parameter) = self.parameters.iter_mut().find(|parameter| parameter.name == name)
+        {
+            parameter.min = min;
+            parameter.max = max;
+            if let ParameterValue::Float(value) = parameter.value
+            {
+                let clamped = value.max(min).min(max);
+                if clamped != value
+                {
+                    parameter.value = ParameterValue::Float(clamped);
+                    mark_changed(&mut self.changed, name);
+                }
+            }
+        }
+    }
+
+    /// Sets every parameter back to its default value.
+    pub fn reset(&mut self) {
+        for parameter in self.parameters.iter_mut()
+        {
+            if parameter.value != parameter.default
+            {
+                parameter.value = parameter.default;
+                mark_changed(&mut self.changed, &parameter.name);
+            }
+        }
+    }
+
+    /// Names of the parameters changed since the last call.
+    pub fn take_changed(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.changed)
+    }
+}
+
+impl Default for Parameters {
+    fn default() -> Parameters {
+        Parameters::new()
+    }
+}
@@ -0,0 +1,368 @@
+use analysis::traits::Chainable;
+use analysis::parameters::{Parameters, ParameterError};
+use analysis::stream::{StreamFormat, BlockContext};
+use analysis::output::{Output, Value};
+
+// Polyphase filter for 4x oversampling from ITU-R BS.1770-4, Annex 2. One row per phase.
+// The taps are written as listed there, which is more digits than an f32 literal takes.
+const TRUE_PEAK_TAPS: usize = 12;
+const TRUE_PEAK_FILTER: [[f64; TRUE_PEAK_TAPS]; 4] = [
+    [0.001708984375, 0.010986328125, -0.0196533203125, 0.033203125, -0.0594482421875, 0.1373291015625,
+     0.97216796875, -0.102294921875, 0.047607421875, -0.026611328125, 0.014892578125, -0.00830078125],
+    [-0.0291748046875, 0.029296875, -0.0517578125, 0.089111328125, -0.16650390625, 0.465087890625,
+     0.77978515625, -0.2003173828125, 0.1015625, -0.0582275390625, 0.0330810546875, -0.0189208984375],
+    [-0.0189208984375, 0.0330810546875, -0.0582275390625, 0.1015625, -0.2003173828125, 0.77978515625,
+     0.465087890625, -0.16650390625, 0.089111328125, -0.0517578125, 0.029296875, -0.0291748046875],
+    [-0.00830078125, 0.014892578125, -0.026611328125, 0.047607421875, -0.102294921875, 0.97216796875,
+     0.1373291015625, -0.0594482421875, 0.033203125, -0.0196533203125, 0.010986328125, 0.001708984375],
+];
+
+/// Levels of a single channel, peaks in dBFS.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChannelPeak {
+    /// Highest sample in the latest block, or since the peaks were last taken, from `Peak::take_peaks`.
+    pub sample_peak: f32,
+    /// Highest value between the samples, from 4x oversampling. Of the latest block, or since the peaks were last taken.
+    pub true_peak: f32,
+    /// Highest true peak lately, held for a while and then decaying.
+    pub held_peak: f32,
+    /// Longest run of consecutive full-scale samples in the latest block, or since the peaks were last taken.
+    pub full_scale_run: u32,
+    /// True if the channel has clipped since the clipping was last taken with `Peak::take_peaks`.
+    pub clipping: bool,
+}
+
+// Per-channel state.
+struct Channel {
+    // Latest samples for the true peak filter, newest last.
+    history: [f32; TRUE_PEAK_TAPS],
+    // Current run of full-scale samples, which may continue into the next block.
+    run: u32,
+    // Linear held peak and how many more frames it's held before decaying.
+    held: f32,
+    hold_left: u64,
+
+    // Linear peaks and longest run since the peaks were last taken.
+    taken_sample_peak: f32,
+    taken_true_peak: f32,
+    taken_run: u32,
+
+    peak: ChannelPeak,
+}
+
+impl Channel {
+    fn new(floor: f32) -> Channel {
+        Channel {
+            history: [0.0; TRUE_PEAK_TAPS],
+            run: 0,
+            held: 0.0,
+            hold_left: 0,
+
+            taken_sample_peak: 0.0,
+            taken_true_peak: 0.0,
+            taken_run: 0,
+
+            peak: ChannelPeak {
+                sample_peak: floor,
+                true_peak: floor,
+                held_peak: floor,
+                full_scale_run: 0,
+                clipping: false,
+            },
+        }
+    }
+}
+
+/// Sample peak, true peak per ITU-R BS.1770, peak hold and clipping of each channel.
+/// Output is "sample_peak", "true_peak" and "held_peak" in dBFS, "full_scale_run", the longest run of full-scale samples,
+/// and "clipping", 1.0 if the channel is clipping, otherwise 0.0, each with one value per channel.
+/// Parameters are "hold" in milliseconds, "decay" in dB per second, "clip_level" in dBFS,
+/// "clip_samples", the amount of consecutive samples at or above the clip level that counts as clipping, and "floor".
+pub struct Peak {
+    parameters: Parameters,
+    sample_rate: f32,
+
+    channels: Vec<Channel>,
+
+    output: Output,
+}
+
+impl Peak {
+    pub fn new() -> Peak {
+        let mut parameters = Parameters::new();
+        parameters.add_float("hold", 1000.0, 0.0, 60_000.0);
+        parameters.add_float("decay", 20.0, 0.0, 1000.0);
+        parameters.add_float("clip_level", -0.01, -20.0, 0.0);
+        parameters.add_float("clip_samples", 3.0, 1.0, 1000.0);
+        parameters.add_float("floor", -96.0, -200.0, 0.0);
+
+        Peak {
+            parameters,
+            sample_rate: 44_100.0,
+
+            channels: Vec::new(),
+
+            output: Output::new(),
+        }
+    }
+
+    /// How long the held peak stays before it starts to decay.
+    pub fn set_hold_ms(&mut self, hold: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("hold", hold)
+    }
+
+    /// How fast the held peak falls after the hold time, in dB per second.
+    pub fn set_decay(&mut self, decay: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("decay", decay)
+    }
+
+    /// Samples at or above this level in dBFS count as full scale.
+    pub fn set_clip_level(&mut self, clip_level: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("clip_level", clip_level)
+    }
+
+    /// Amount of consecutive full-scale samples that counts as clipping.
+    pub fn set_clip_samples(&mut self, clip_samples: u32) -> Result<(), ParameterError> {
+        self.parameters.set_float("clip_samples", clip_samples as f32)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn channel(&self, channel: usize) -> ChannelPeak {
+        self.channels[channel].peak
+    }
+
+    pub fn peaks(&self) -> Vec<ChannelPeak> {
+        self.channels.iter().map(|channel| channel.peak).collect()
+    }
+
+    /// True if any channel has clipped since the peaks were last taken.
+    pub fn is_clipping(&self) -> bool {
+        self.channels.iter().any(|channel| channel.peak.clipping)
+    }
+
+    /// Levels of each channel since the peaks were last taken, starting over from there.
+    /// Like clipping, which stays flagged until taken, this way whoever polls the node doesn't miss the peaks of
+    /// the blocks between polls.
+    pub fn take_peaks(&mut self) -> Vec<ChannelPeak> {
+        let mut peaks = Vec::with_capacity(self.channels.len());
+        for ch in 0..self.channels.len()
+        {
+            let mut peak = self.channels[ch].peak;
+            peak.sample_peak = self.to_db(self.channels[ch].taken_sample_peak);
+            peak.true_peak = self.to_db(self.channels[ch].taken_true_peak);
+            peak.full_scale_run = self.channels[ch].taken_run;
+            peaks.push(peak);
+
+            let channel = &mut self.channels[ch];
+            channel.taken_sample_peak = 0.0;
+            channel.taken_true_peak = 0.0;
+            channel.taken_run = 0;
+            channel.peak.clipping = false;
+        }
+        peaks
+    }
+
+    fn to_db(&self, level: f32) -> f32 {
+        let floor = self.parameters.get_float("floor");
+        if level <= 0.0
+        {
+            return floor;
+        }
+
+        let db = 20.0 * level.log10();
+        if db < floor { floor } else { db }
+    }
+}
+
+impl Default for Peak {
+    fn default() -> Peak {
+        Peak::new()
+    }
+}
+
+impl Chainable for Peak {
+    fn prepare(&mut self, format: &StreamFormat) {
+        if format.sample_rate > 0.0
+        {
+            self.sample_rate = format.sample_rate;
+        }
+        self.channels = Vec::new();
+    }
+
+    fn process(&mut self, buffer: &Vec<Vec<f32>>, context: &BlockContext) {
+        if context.sample_rate > 0.0
+        {
+            self.sample_rate = context.sample_rate;
+        }
+        self.update(buffer);
+    }
+
+    fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        let floor = self.parameters.get_float("floor");
+        if self.channels.len() != buffer.len()
+        {
+            self.channels = (0..buffer.len()).map(|_| Channel::new(floor)).collect();
+        }
+
+        let clip_level = 10f32.powf(self.parameters.get_float("clip_level") / 20.0);
+        let clip_samples = self.parameters.get_float("clip_samples") as u32;
+        let hold_frames = (self.parameters.get_float("hold") / 1000.0 * self.sample_rate) as u64;
+        let decay = self.parameters.get_float("decay");
+
+        for (ch, samples) in buffer.iter().enumerate()
+        {
+            let frames = samples.len() as u64;
+            let mut sample_peak = 0f32;
+            let mut true_peak = 0f32;
+            let mut longest_run = 0u32;
+
+            {
+                let channel = &mut self.channels[ch];
+                for &sample in samples.iter()
+                {
+                    let level = sample.abs();
+                    sample_peak = sample_peak.max(level);
+
+                    // Shift the sample in and interpolate the 4 points up to it.
+                    for i in 0..TRUE_PEAK_TAPS - 1
+                    {
+                        channel.history[i] = channel.history[i + 1];
+                    }
+                    channel.history[TRUE_PEAK_TAPS - 1] = sample;
+                    for phase in TRUE_PEAK_FILTER.iter()
+                    {
+                        let mut value = 0f32;
+                        for (tap, coefficient) in phase.iter().enumerate()
+                        {
+                            value += *coefficient as f32 * channel.history[TRUE_PEAK_TAPS - 1 - tap];
+                        }
+                        true_peak = true_peak.max(value.abs());
+                    }
+                    // The true peak is never lower than the samples themselves.
+                    true_peak = true_peak.max(level);
+
+                    if level >= clip_level
+                    {
+                        channel.run += 1;
+                        longest_run = longest_run.max(channel.run);
+                    }
+                    else
+                    {
+                        channel.run = 0;
+                    }
+                }
+
+                // Hold new peaks, let old ones decay once their hold time is over.
+                if true_peak >= channel.held
+                {
+                    channel.held = true_peak;
+                    channel.hold_left = hold_frames;
+                }
+                else if channel.hold_left >= frames
+                {
+                    channel.hold_left -= frames;
+                }
+                else
+                {
+                    let decay_seconds = (frames - channel.hold_left) as f32 / self.sample_rate;
+                    channel.hold_left = 0;
+                    channel.held *= 10f32.powf(-decay * decay_seconds / 20.0);
+                }
+
+                channel.taken_sample_peak = channel.taken_sample_peak.max(sample_peak);
+                channel.taken_true_peak = channel.taken_true_peak.max(true_peak);
+                channel.taken_run = channel.taken_run.max(longest_run);
+
+                channel.peak.full_scale_run = longest_run;
+                if longest_run >= clip_samples
+                {
+                    channel.peak.clipping = true;
+                }
+            }
+
+            let held_peak = self.to_db(self.channels[ch].held);
+            let sample_peak = self.to_db(sample_peak);
+            let true_peak = self.to_db(true_peak);
+
+            let peak = &mut self.channels[ch].peak;
+            peak.sample_peak = sample_peak;
+            peak.true_peak = true_peak;
+            peak.held_peak = held_peak;
+        }
+
+        let peaks = self.peaks();
+        self.output.set("sample_peak", Value::Channels(peaks.iter().map(|peak| peak.sample_peak).collect()));
+        self.output.set("true_peak", Value::Channels(peaks.iter().map(|peak| peak.true_peak).collect()));
+        self.output.set("held_peak", Value::Channels(peaks.iter().map(|peak| peak.held_peak).collect()));
+        self.output.set("full_scale_run", Value::Channels(peaks.iter().map(|peak| peak.full_scale_run as f32).collect()));
+        self.output.set("clipping", Value::Channels(peaks.iter().map(|peak| if peak.clipping { 1.0 } else { 0.0 }).collect()));
+    }
+
+    fn output(&self) -> &Output {
+        &self.output
+    }
+
+    fn parameters(&self) -> Option<&Parameters> {
+        Some(&self.parameters)
+    }
+
+    fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        Some(&mut self.parameters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analysis::generator_source::{Generator, Signal, Waveform};
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn generate(waveform: Waveform, amplitude: f32, frequency: f32, frames: usize) -> Vec<Vec<f32>> {
+        let mut signal = Signal::new(waveform);
+        signal.amplitude = amplitude;
+        signal.frequency = frequency;
+        Generator::new(vec![signal], SAMPLE_RATE).unwrap().generate(frames)
+    }
+
+    fn measure(buffer: &Vec<Vec<f32>>) -> ChannelPeak {
+        let mut peak = Peak::new();
+        peak.prepare(&StreamFormat::new(SAMPLE_RATE as f32, buffer.len()));
+        peak.process(buffer, &BlockContext::new(SAMPLE_RATE as f32, 0));
+        peak.take_peaks()[0]
+    }
+
+    #[test]
+    fn full_scale_sine_is_0_dbtp() {
+        // 48 samples per cycle, so the crests are on samples.
+        let peak = measure(&generate(Waveform::Sine, 1.0, 1000.0, SAMPLE_RATE as usize / 10));
+        assert!(peak.sample_peak.abs() < 0.001, "{}", peak.sample_peak);
+        assert!(peak.true_peak.abs() < 0.1, "{}", peak.true_peak);
+        // Single full-scale samples aren't clipping.
+        assert_eq!(peak.full_scale_run, 1);
+        assert!(!peak.clipping);
+    }
+
+    #[test]
+    fn finds_the_peak_between_samples() {
+        // BS.1770 Annex 2: a full-scale sine at a quarter of the sample rate, sampled 45 degrees off its crests,
+        // has all its samples at -3 dBFS, while its true peak is 0 dBTP.
+        // sin(n pi / 2 + pi / 4) is the sum of the sine and the sine a sample later, over the square root of 2.
+        let sine = generate(Waveform::Sine, 1.0, SAMPLE_RATE as f32 / 4.0, SAMPLE_RATE as usize / 10 + 1).remove(0);
+        let shifted: Vec<f32> = sine.windows(2).map(|pair| (pair[0] + pair[1]) * ::std::f32::consts::FRAC_1_SQRT_2).collect();
+
+        let peak = measure(&vec![shifted]);
+        assert!((peak.sample_peak + 3.01).abs() < 0.01, "{}", peak.sample_peak);
+        assert!(peak.true_peak.abs() < 0.2, "{}", peak.true_peak);
+    }
+
+    #[test]
+    fn clips_on_runs_of_full_scale_samples() {
+        let peak = measure(&generate(Waveform::Square, 1.0, 1000.0, SAMPLE_RATE as usize / 10));
+        assert!(peak.full_scale_run >= 3);
+        assert!(peak.clipping);
+    }
+}
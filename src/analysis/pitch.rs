@@ -0,0 +1,404 @@
+use analysis::traits::Chainable;
+use analysis::parameters::{Parameters, ParameterError, ParameterValue};
+use analysis::output::{Output, Value};
+use analysis::stream::{StreamFormat, BlockContext};
+use analysis::interpolation::parabolic;
+
+// Of the maxima of the McLeod normalized square difference, the first one at least this high relative to the highest is picked.
+const MCLEOD_CUTOFF: f32 = 0.93;
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// How the pitch is detected.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PitchMethod {
+    /// YIN, de Cheveigné and Kawahara 2002. Confidence is one minus the dip of the cumulative mean normalized difference.
+    Yin = 0,
+    /// McLeod pitch method, McLeod and Wyvill 2005. Confidence is the clarity of the normalized square difference.
+    McLeod = 1,
+}
+
+impl PitchMethod {
+    pub fn from_id(id: u8) -> Option<PitchMethod> {
+        match id
+        {
+            0 => Some(PitchMethod::Yin),
+            1 => Some(PitchMethod::McLeod),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PitchMethod> {
+        match name
+        {
+            "yin" => Some(PitchMethod::Yin),
+            "mcleod" => Some(PitchMethod::McLeod),
+            _ => None,
+        }
+    }
+}
+
+/// Name of a MIDI note with its octave, e.g. "A4" for 69.
+pub fn note_name(note: i32) -> String {
+    let octave = (note as f32 / 12.0).floor() as i32;
+    format!("{}{}", NOTE_NAMES[(note - octave * 12) as usize], octave - 1)
+}
+
+// Period in samples and confidence by YIN, looking for periods from `min_period` to `max_period`.
+fn yin(samples: &[f32], min_period: usize, max_period: usize, threshold: f32) -> Option<(f32, f32)> {
+    let window = samples.len() - max_period;
+
+    // Cumulative mean normalized difference.
+    let mut difference = vec![1.0f32; max_period + 1];
+    let mut sum = 0.0f32;
+    for period in 1..max_period + 1
+    {
+        let mut d = 0.0f32;
+        for j in 0..window
+        {
+            let delta = samples[j] - samples[j + period];
+            d += delta * delta;
+        }
+        sum += d;
+        difference[period] = if sum > 0.0 { d * period as f32 / sum } else { 1.0 };
+    }
+
+    // First dip under the threshold, down to its bottom, or the deepest dip if none is under it.
+    let mut best = None;
+    let mut period = min_period;
+    while period <= max_period
+    {
+        if difference[period] < threshold
+        {
+            while period < max_period && difference[period + 1] < difference[period]
+            {
+                period += 1;
+            }
+            best = Some(period);
+            break;
+        }
+        period += 1;
+    }
+    let best = match best
+    {
+        Some(best) => best,
+        None => (min_period..max_period + 1).fold(min_period, |best, period| {
+            if difference[period] < difference[best] { period } else { best }
+        }),
+    };
+
+    let confidence = (1.0 - difference[best]).clamp(0.0, 1.0);
+    Some((parabolic(&difference, best), confidence))
+}
+
+// Period in samples and clarity by the McLeod pitch method.
+fn mcleod(samples: &[f32], min_period: usize, max_period: usize) -> Option<(f32, f32)> {
+    let size = samples.len();
+
+    // Normalized square difference.
+    let mut nsdf = vec![0.0f32; max_period + 2];
+    for period in 0..(max_period + 2).min(size)
+    {
+        let mut correlation = 0.0f32;
+        let mut energy = 0.0f32;
+        for j in 0..size - period
+        {
+            correlation += samples[j] * samples[j + period];
+            energy += samples[j] * samples[j] + samples[j + period] * samples[j + period];
+        }
+        nsdf[period] = if energy > 0.0 { 2.0 * correlation / energy } else { 0.0 };
+    }
+
+    // Highest point of each positive lobe after the first negative one.
+    let mut maxima: Vec<usize> = Vec::new();
+    let mut period = 1;
+    while period < nsdf.len() && nsdf[period] > 0.0
+    {
+        period += 1;
+    }
+    let mut lobe: Option<usize> = None;
+    while period < nsdf.len() - 1
+    {
+        if nsdf[period] > 0.0
+        {
+            if lobe.map_or(true, |max| nsdf[period] > nsdf[max])
+            {
+                lobe = Some(period);
+            }
+        }
+        else if let Some(max) = lobe.take()
+        {
+            maxima.push(max);
+        }
+        period += 1;
+    }
+    if let Some(max) = lobe
+    {
+        maxima.push(max);
+    }
+
+    maxima.retain(|&max| max >= min_period && max <= max_period);
+    let highest = maxima.iter().fold(0.0f32, |highest, &max| highest.max(nsdf[max]));
+    if highest <= 0.0
+    {
+        return None;
+    }
+
+    maxima.iter()
+        .find(|&&max| nsdf[max] >= MCLEOD_CUTOFF * highest)
+        .map(|&max| (parabolic(&nsdf, max), nsdf[max].clamp(0.0, 1.0)))
+}
+
+/// Pitch of a single channel.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChannelPitch {
+    /// Fundamental frequency in Hz, 0.0 if there's no pitch confident enough.
+    pub frequency: f32,
+    /// How sure the detector is of the pitch, from 0.0 to 1.0.
+    pub confidence: f32,
+    /// Nearest MIDI note, 69 being A4.
+    pub note: i32,
+    /// How far the frequency is from the nearest note, from -50 to 50 cents.
+    pub cents: f32,
+}
+
+impl ChannelPitch {
+    fn unpitched(confidence: f32) -> ChannelPitch {
+        ChannelPitch { frequency: 0.0, confidence, note: 0, cents: 0.0 }
+    }
+
+    /// Name of the nearest note, e.g. "A4". None if there's no pitch.
+    pub fn note_name(&self) -> Option<String> {
+        if self.frequency > 0.0 { Some(note_name(self.note)) } else { None }
+    }
+}
+
+/// Monophonic pitch of each channel.
+/// Output is "frequency" in Hz, "confidence", "note", the nearest MIDI note, and "cents" off that note, each per channel.
+/// Frequency, note and cents are 0 when the confidence is below the threshold.
+/// Parameters are "min_frequency" and "max_frequency" in Hz, "threshold", the confidence needed to report a pitch,
+/// "hop" in milliseconds between detections, "reference", the frequency of A4, and "method", the id of a `PitchMethod`.
+pub struct Pitch {
+    parameters: Parameters,
+    sample_rate: f32,
+
+    pitches: Vec<ChannelPitch>,
+    output: Output,
+}
+
+impl Pitch {
+    pub fn new() -> Pitch {
+        let mut parameters = Parameters::new();
+        parameters.add_float("min_frequency", 50.0, 20.0, 2000.0);
+        parameters.add_float("max_frequency", 1500.0, 50.0, 8000.0);
+        parameters.add_float("threshold", 0.85, 0.0, 1.0);
+        parameters.add_float("hop", 20.0, 1.0, 1000.0);
+        parameters.add_float("reference", 440.0, 400.0, 480.0);
+        parameters.add_float("method", PitchMethod::Yin as u8 as f32, 0.0, PitchMethod::McLeod as u8 as f32);
+
+        Pitch {
+            parameters,
+            sample_rate: 44_100.0,
+
+            pitches: Vec::new(),
+            output: Output::new(),
+        }
+    }
+
+    /// Range of fundamental frequencies to look for. The lower the minimum, the more audio each detection needs.
+    /// Both are checked before either is set, so that an error leaves the range as it was.
+    pub fn set_range(&mut self, min_frequency: f32, max_frequency: f32) -> Result<(), ParameterError> {
+        self.parameters.check("min_frequency", ParameterValue::Float(min_frequency))?;
+        self.parameters.check("max_frequency", ParameterValue::Float(max_frequency))?;
+
+        self.parameters.set_float("min_frequency", min_frequency)?;
+        self.parameters.set_float("max_frequency", max_frequency)
+    }
+
+    pub fn set_threshold(&mut self, threshold: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("threshold", threshold)
+    }
+
+    pub fn set_hop_ms(&mut self, hop: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("hop", hop)
+    }
+
+    pub fn set_reference(&mut self, reference: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("reference", reference)
+    }
+
+    pub fn set_method(&mut self, method: PitchMethod) -> Result<(), ParameterError> {
+        self.parameters.set_float("method", method as u8 as f32)
+    }
+
+    pub fn method(&self) -> PitchMethod {
+        PitchMethod::from_id(self.parameters.get_float("method").round() as u8).unwrap_or(PitchMethod::Yin)
+    }
+
+    pub fn pitches(&self) -> &Vec<ChannelPitch> {
+        &self.pitches
+    }
+
+    pub fn channel(&self, channel: usize) -> ChannelPitch {
+        self.pitches[channel]
+    }
+
+    // Longest period looked for, in samples.
+    fn max_period(&self) -> usize {
+        (self.sample_rate / self.parameters.get_float("min_frequency")).ceil() as usize
+    }
+
+    fn detect(&self, samples: &[f32]) -> ChannelPitch {
+        let min_frequency = self.parameters.get_float("min_frequency");
+        let max_frequency = self.parameters.get_float("max_frequency").max(min_frequency);
+        let threshold = self.parameters.get_float("threshold");
+
+        // Both methods compare the audio with itself up to the longest period later, so need twice that.
+        let max_period = self.max_period().min(samples.len() / 2);
+        let min_period = ((self.sample_rate / max_frequency).floor() as usize).max(2);
+        if max_period <= min_period
+        {
+            return ChannelPitch::unpitched(0.0);
+        }
+
+        let samples = &samples[samples.len() - 2 * max_period..];
+        let detected = match self.method()
+        {
+            PitchMethod::Yin => yin(samples, min_period, max_period, 1.0 - threshold),
+            PitchMethod::McLeod => mcleod(samples, min_period, max_period),
+        };
+
+        let (period, confidence) = match detected
+        {
+            Some(detected) => detected,
+            None => return ChannelPitch::unpitched(0.0),
+        };
+
+        let frequency = self.sample_rate / period;
+        if confidence < threshold || frequency < min_frequency || frequency > max_frequency
+        {
+            return ChannelPitch::unpitched(confidence);
+        }
+
+        let semitones = 12.0 * (frequency / self.parameters.get_float("reference")).log2() + 69.0;
+        let note = semitones.round();
+        ChannelPitch {
+            frequency,
+            confidence,
+            note: note as i32,
+            cents: (semitones - note) * 100.0,
+        }
+    }
+}
+
+impl Default for Pitch {
+    fn default() -> Pitch {
+        Pitch::new()
+    }
+}
+
+impl Chainable for Pitch {
+    fn prepare(&mut self, format: &StreamFormat) {
+        if format.sample_rate > 0.0
+        {
+            self.sample_rate = format.sample_rate;
+        }
+        self.pitches = Vec::new();
+    }
+
+    fn process(&mut self, buffer: &Vec<Vec<f32>>, context: &BlockContext) {
+        if context.sample_rate > 0.0
+        {
+            self.sample_rate = context.sample_rate;
+        }
+        self.update(buffer);
+    }
+
+    fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        self.pitches = buffer.iter().map(|channel| self.detect(channel)).collect();
+
+        self.output.set("frequency", Value::Channels(self.pitches.iter().map(|pitch| pitch.frequency).collect()));
+        self.output.set("confidence", Value::Channels(self.pitches.iter().map(|pitch| pitch.confidence).collect()));
+        self.output.set("note", Value::Channels(self.pitches.iter().map(|pitch| pitch.note as f32).collect()));
+        self.output.set("cents", Value::Channels(self.pitches.iter().map(|pitch| pitch.cents).collect()));
+    }
+
+    fn output(&self) -> &Output {
+        &self.output
+    }
+
+    /// Enough audio for the longest period looked for, a new frame every hop.
+    fn framing(&self) -> Option<(usize, usize)> {
+        let hop = (self.parameters.get_float("hop") / 1000.0 * self.sample_rate).round() as usize;
+        Some((2 * self.max_period(), hop.max(1)))
+    }
+
+    fn parameters(&self) -> Option<&Parameters> {
+        Some(&self.parameters)
+    }
+
+    fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        Some(&mut self.parameters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analysis::generator_source::{Generator, Signal, Waveform};
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn generate(waveform: Waveform, amplitude: f32, frequency: f32, frames: usize) -> Vec<Vec<f32>> {
+        let mut signal = Signal::new(waveform);
+        signal.amplitude = amplitude;
+        signal.frequency = frequency;
+        Generator::new(vec![signal], SAMPLE_RATE).unwrap().generate(frames)
+    }
+
+    fn detect(method: PitchMethod, buffer: &Vec<Vec<f32>>) -> ChannelPitch {
+        let mut pitch = Pitch::new();
+        pitch.set_method(method).unwrap();
+        pitch.prepare(&StreamFormat::new(SAMPLE_RATE as f32, buffer.len()));
+        pitch.process(buffer, &BlockContext::new(SAMPLE_RATE as f32, 0));
+        pitch.channel(0)
+    }
+
+    fn assert_a4(method: PitchMethod) {
+        let pitch = detect(method, &generate(Waveform::Sine, 0.5, 440.0, SAMPLE_RATE as usize / 10));
+        assert!((pitch.frequency - 440.0).abs() < 0.5, "{:?}", pitch);
+        assert_eq!(pitch.note, 69);
+        assert!(pitch.cents.abs() < 2.0, "{:?}", pitch);
+        assert_eq!(pitch.note_name(), Some("A4".to_string()));
+    }
+
+    #[test]
+    fn yin_finds_a4() {
+        assert_a4(PitchMethod::Yin);
+    }
+
+    #[test]
+    fn mcleod_finds_a4() {
+        assert_a4(PitchMethod::McLeod);
+    }
+
+    #[test]
+    fn silence_has_no_pitch() {
+        let pitch = detect(PitchMethod::Yin, &vec![vec![0.0; SAMPLE_RATE as usize / 10]]);
+        assert_eq!(pitch.frequency, 0.0);
+        assert_eq!(pitch.note_name(), None);
+    }
+
+    #[test]
+    fn range_is_unchanged_when_either_end_is_out_of_range() {
+        let mut pitch = Pitch::new();
+        assert!(pitch.set_range(100.0, 10_000.0).is_err());
+        assert!(pitch.set_range(5.0, 1000.0).is_err());
+        assert_eq!(pitch.parameters.get_float("min_frequency"), 50.0);
+        assert_eq!(pitch.parameters.get_float("max_frequency"), 1500.0);
+
+        pitch.set_range(100.0, 1000.0).unwrap();
+        assert_eq!(pitch.parameters.get_float("min_frequency"), 100.0);
+        assert_eq!(pitch.parameters.get_float("max_frequency"), 1000.0);
+    }
+}
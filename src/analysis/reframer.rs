@@ -0,0 +1,104 @@
+use analysis::stream::BlockContext;
+use analysis::parameters::ParameterError;
+
+/// Checks a frame size and hop for nodes to return from `framing`, which the chain can't frame audio with if 0.
+pub fn check_framing(size: usize, hop: usize) -> Result<(), ParameterError> {
+    if size == 0
+    {
+        return Err(ParameterError::OutOfRange("size".to_string(), 0.0, 1.0, usize::MAX as f32));
+    }
+    if hop == 0
+    {
+        return Err(ParameterError::OutOfRange("hop".to_string(), 0.0, 1.0, usize::MAX as f32));
+    }
+    Ok(())
+}
+
+/// Collects blocks of any size into frames of a fixed size, a new frame starting every `hop` frames.
+/// Used by chains for nodes that want fixed-size frames, whatever the device's buffer size is.
+pub struct Reframer {
+    size: usize,
+    hop: usize,
+
+    // Samples waiting to be framed, per channel, and the stream position of the first one.
+    pending: Vec<Vec<f32>>,
+    position: u64,
+    // Incoming samples still to be skipped, when the hop is longer than the frame.
+    skip: usize,
+}
+
+impl Reframer {
+    /// Both the size and the hop must be at least 1, see `check_framing`.
+    pub fn new(size: usize, hop: usize) -> Result<Reframer, ParameterError> {
+        check_framing(size, hop)?;
+
+        Ok(Reframer {
+            size,
+            hop,
+
+            pending: Vec::new(),
+            position: 0,
+            skip: 0,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn hop(&self) -> usize {
+        self.hop
+    }
+
+    /// Throws away the pending samples.
+    pub fn reset(&mut self) {
+        self.pending = Vec::new();
+        self.skip = 0;
+    }
+
+    /// Adds a block, returning the frames that are now complete, each with a context giving its position.
+    pub fn push(&mut self, buffer: &[Vec<f32>], context: &BlockContext) -> Vec<(Vec<Vec<f32>>, BlockContext)> {
+        // Start over if the amount of channels changes.
+        if self.pending.len() != buffer.len()
+        {
+            self.pending = vec![Vec::new(); buffer.len()];
+            self.skip = 0;
+        }
+
+        if self.skip == 0 && self.pending.iter().all(|channel| channel.is_empty())
+        {
+            self.position = context.frame;
+        }
+
+        let incoming = if !buffer.is_empty() { buffer[0].len() } else { 0 };
+        let skipped = self.skip.min(incoming);
+        self.skip -= skipped;
+        for (pending, channel) in self.pending.iter_mut().zip(buffer.iter())
+        {
+            pending.extend(channel.iter().skip(skipped).cloned());
+        }
+
+        let mut frames = Vec::new();
+        while !self.pending.is_empty() && self.pending[0].len() >= self.size
+        {
+            let size = self.size;
+            let frame: Vec<Vec<f32>> = self.pending.iter().map(|channel| channel.iter().take(size).cloned().collect()).collect();
+
+            let mut frame_context = *context;
+            frame_context.frame = self.position;
+            frames.push((frame, frame_context));
+
+            // With a hop longer than the frame, the samples in between are skipped, also from the blocks to come.
+            let drained = self.hop.min(self.pending[0].len());
+            for channel in self.pending.iter_mut()
+            {
+                let channel_drained = drained.min(channel.len());
+                channel.drain(..channel_drained);
+            }
+            self.skip = self.hop - drained;
+            self.position += self.hop as u64;
+        }
+
+        frames
+    }
+}
@@ -0,0 +1,105 @@
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Samples are written at `write` and read at `read`. Both only ever grow, wrapping around the capacity.
+// Only the producer moves `write` and only the consumer moves `read`, so neither needs a lock.
+struct Shared<T> {
+    buffer: Box<[UnsafeCell<T>]>,
+    write: AtomicUsize,
+    read: AtomicUsize,
+}
+
+// The producer and consumer never touch the same samples at the same time.
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn len(&self) -> usize {
+        self.write.load(Ordering::Acquire).wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+}
+
+/// Creates a single-producer, single-consumer ring buffer holding up to `capacity` samples, or other values.
+/// Neither end allocates or locks, so the producer can be used from an audio callback.
+pub fn ring_buffer<T: Copy + Default>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0, "Ring buffer capacity must be at least 1");
+
+    let buffer: Vec<UnsafeCell<T>> = (0..capacity).map(|_| UnsafeCell::new(T::default())).collect();
+    let shared = Arc::new(Shared {
+        buffer: buffer.into_boxed_slice(),
+        write: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+    });
+
+    (Producer { shared: shared.clone() }, Consumer { shared })
+}
+
+pub struct Producer<T = f32> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Copy> Producer<T> {
+    pub fn capacity(&self) -> usize {
+        self.shared.buffer.len()
+    }
+
+    /// Amount of samples that can be written before the buffer is full.
+    pub fn free(&self) -> usize {
+        self.capacity() - self.shared.len()
+    }
+
+    /// Writes `count` samples, getting each from `sample` by its index.
+    /// Writes as many as fit and returns how many that was.
+    pub fn write_with<F>(&mut self, count: usize, mut sample: F) -> usize where F: FnMut(usize) -> T {
+        let capacity = self.capacity();
+        let count = count.min(self.free());
+        let write = self.shared.write.load(Ordering::Relaxed);
+
+        for i in 0..count
+        {
+            unsafe { *self.shared.buffer[write.wrapping_add(i) % capacity].get() = sample(i); }
+        }
+
+        self.shared.write.store(write.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    pub fn write(&mut self, samples: &[T]) -> usize {
+        self.write_with(samples.len(), |i| samples[i])
+    }
+}
+
+pub struct Consumer<T = f32> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Copy> Consumer<T> {
+    pub fn capacity(&self) -> usize {
+        self.shared.buffer.len()
+    }
+
+    /// Amount of samples waiting to be read.
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads up to `samples.len()` samples, returning how many were read.
+    pub fn read(&mut self, samples: &mut [T]) -> usize {
+        let capacity = self.capacity();
+        let count = samples.len().min(self.len());
+        let read = self.shared.read.load(Ordering::Relaxed);
+
+        for (i, sample) in samples.iter_mut().take(count).enumerate()
+        {
+            *sample = unsafe { *self.shared.buffer[read.wrapping_add(i) % capacity].get() };
+        }
+
+        self.shared.read.store(read.wrapping_add(count), Ordering::Release);
+        count
+    }
+}
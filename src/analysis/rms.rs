@@ -1,38 +1,414 @@
 use analysis::traits::Chainable;
+use analysis::parameters::{Parameters, ParameterError};
+use analysis::output::{Output, Value};
+use analysis::reframer::check_framing;
+use analysis::stream::{StreamFormat, BlockContext};
 
+// Scales the rectified average of a sine to its RMS, as VU meters are calibrated for sines.
+const VU_SCALE: f32 = 1.110_720_7;
+// VU meters reach 99% of a steady tone in 300 ms. With two equal one-pole stages, that is about 6.64 time constants.
+const VU_TIME_CONSTANT: f32 = 0.300 / 6.638;
+
+/// How the meter reading follows the signal.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Ballistics {
+    /// RMS over the window, smoothed by the attack and release.
+    None = 0,
+    /// Volume unit meter, IEC 60268-17. Rectified average reaching 99% of a steady tone in 300 ms.
+    VU = 1,
+    /// Peak programme meter, IEC 60268-10 Type I. 5 ms integration time, falls 20 dB in 1.7 s.
+    PPMTypeI = 2,
+    /// Peak programme meter, IEC 60268-10 Type II. 10 ms integration time, falls 24 dB in 2.8 s.
+    PPMTypeII = 3,
+}
+
+impl Ballistics {
+    pub fn from_id(id: u8) -> Option<Ballistics> {
+        match id
+        {
+            0 => Some(Ballistics::None),
+            1 => Some(Ballistics::VU),
+            2 => Some(Ballistics::PPMTypeI),
+            3 => Some(Ballistics::PPMTypeII),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Ballistics> {
+        match name
+        {
+            "none" => Some(Ballistics::None),
+            "vu" => Some(Ballistics::VU),
+            "ppm1" => Some(Ballistics::PPMTypeI),
+            "ppm2" => Some(Ballistics::PPMTypeII),
+            _ => None,
+        }
+    }
+
+    // Attack time constant in seconds and fall in dB per second of the PPMs.
+    // The time constants make a 5 kHz burst as long as the integration time read 2 dB below a steady tone.
+    fn ppm(&self) -> Option<(f32, f32)> {
+        match *self
+        {
+            Ballistics::PPMTypeI => Some((0.00133, 20.0 / 1.7)),
+            Ballistics::PPMTypeII => Some((0.00266, 24.0 / 2.8)),
+            _ => None,
+        }
+    }
+}
+
+// Coefficient of a one-pole filter with the given time constant in seconds, per sample.
+fn one_pole(time_constant: f32, sample_rate: f32) -> f32 {
+    if time_constant <= 0.0
+    {
+        1.0
+    }
+    else
+    {
+        1.0 - (-1.0 / (time_constant * sample_rate)).exp()
+    }
+}
+
+// Per-channel state.
+struct Channel {
+    // Squares of the latest samples within the window, and their sum.
+    squares: Vec<f32>,
+    position: usize,
+    filled: usize,
+    square_sum: f64,
+
+    // Smoothed reading, and the first stage of the VU meter.
+    level: f32,
+    vu_stage: f32,
+}
+
+impl Channel {
+    fn new(window: usize) -> Channel {
+        Channel {
+            squares: vec![0.0; window],
+            position: 0,
+            filled: 0,
+            square_sum: 0.0,
+
+            level: 0.0,
+            vu_stage: 0.0,
+        }
+    }
+
+    // Moves the window along by a sample, returning the RMS of the window.
+    fn push_window(&mut self, sample: f32) -> f32 {
+        let square = sample * sample;
+        self.square_sum += square as f64 - self.squares[self.position] as f64;
+        self.squares[self.position] = square;
+        self.position = (self.position + 1) % self.squares.len();
+        self.filled = (self.filled + 1).min(self.squares.len());
+
+        (self.square_sum.max(0.0) / self.filled as f64).sqrt() as f32
+    }
+}
+
+/// RMS level of each channel. Output is "rms", one linear level per channel.
+/// By default the level is that of each block. Parameters are "window" in milliseconds, to measure over that much
+/// of the latest audio instead, "attack" and "release" in milliseconds, time constants to smooth the level with,
+/// and "ballistics", the id of a `Ballistics` to read the level like a VU meter or PPM instead.
 pub struct RMS {
-    buffer: Vec<f32>,
+    parameters: Parameters,
+    framing: Option<(usize, usize)>,
+    sample_rate: f32,
+
+    channels: Vec<Channel>,
+
+    rms: Vec<f32>,
+    output: Output,
 }
 
 impl RMS {
     pub fn new() -> RMS {
-        RMS { buffer: Vec::new() }
+        let mut parameters = Parameters::new();
+        parameters.add_float("window", 0.0, 0.0, 10_000.0);
+        parameters.add_float("attack", 0.0, 0.0, 10_000.0);
+        parameters.add_float("release", 0.0, 0.0, 10_000.0);
+        parameters.add_float("ballistics", Ballistics::None as u8 as f32, 0.0, Ballistics::PPMTypeII as u8 as f32);
+
+        RMS {
+            parameters,
+            framing: None,
+            sample_rate: 44_100.0,
+
+            channels: Vec::new(),
+
+            rms: Vec::new(),
+            output: Output::new(),
+        }
+    }
+
+    /// Measures frames of `size` frames, a new one every `hop` frames, instead of whatever blocks the source gives.
+    /// Both must be at least 1.
+    pub fn set_framing(&mut self, size: usize, hop: usize) -> Result<(), ParameterError> {
+        check_framing(size, hop)?;
+        self.framing = Some((size, hop));
+        Ok(())
+    }
+
+    /// Measures the latest `window` milliseconds of audio, whatever the size of the blocks. 0 measures each block.
+    pub fn set_window_ms(&mut self, window: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("window", window)
+    }
+
+    /// Time constants for smoothing rising and falling levels, in milliseconds. 0 doesn't smooth.
+    pub fn set_attack_ms(&mut self, attack: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("attack", attack)
+    }
+
+    pub fn set_release_ms(&mut self, release: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("release", release)
+    }
+
+    /// Meters with standard ballistics ignore the window, attack and release.
+    pub fn set_ballistics(&mut self, ballistics: Ballistics) -> Result<(), ParameterError> {
+        self.parameters.set_float("ballistics", ballistics as u8 as f32)
+    }
+
+    pub fn ballistics(&self) -> Ballistics {
+        Ballistics::from_id(self.parameters.get_float("ballistics").round() as u8).unwrap_or(Ballistics::None)
+    }
+
+    /// Linear RMS level of each channel.
+    pub fn rms(&self) -> &Vec<f32> {
+        &self.rms
+    }
+
+    /// RMS level of all channels together.
+    pub fn overall(&self) -> f32 {
+        if self.rms.is_empty()
+        {
+            return 0.0;
+        }
+
+        let square_sum: f32 = self.rms.iter().map(|rms| rms * rms).sum();
+        (square_sum / self.rms.len() as f32).sqrt()
+    }
+
+    fn window_frames(&self) -> usize {
+        (self.parameters.get_float("window") / 1000.0 * self.sample_rate).round() as usize
+    }
+
+    fn reset(&mut self, channels: usize) {
+        let window = self.window_frames().max(1);
+        self.channels = (0..channels).map(|_| Channel::new(window)).collect();
+    }
+
+    // Reading of a single channel with the standard ballistics.
+    fn meter(&mut self, ch: usize, samples: &[f32], ballistics: Ballistics) -> f32 {
+        let sample_rate = self.sample_rate;
+        let channel = &mut self.channels[ch];
+
+        match ballistics.ppm()
+        {
+            Some((attack, fall)) => {
+                let attack = one_pole(attack, sample_rate);
+                let release = 10f32.powf(-fall / sample_rate / 20.0);
+                for &sample in samples.iter()
+                {
+                    let level = sample.abs();
+                    if level > channel.level
+                    {
+                        channel.level += attack * (level - channel.level);
+                    }
+                    else
+                    {
+                        channel.level *= release;
+                    }
+                }
+            },
+            None => {
+                let coefficient = one_pole(VU_TIME_CONSTANT, sample_rate);
+                for &sample in samples.iter()
+                {
+                    channel.vu_stage += coefficient * (sample.abs() * VU_SCALE - channel.vu_stage);
+                    channel.level += coefficient * (channel.vu_stage - channel.level);
+                }
+            },
+        }
+
+        channel.level
+    }
+
+    // RMS of a single channel over the window or the block, smoothed.
+    fn measure(&mut self, ch: usize, samples: &[f32], window: bool) -> f32 {
+        let sample_rate = self.sample_rate;
+        let attack = self.parameters.get_float("attack") / 1000.0;
+        let release = self.parameters.get_float("release") / 1000.0;
+        let channel = &mut self.channels[ch];
+
+        if window
+        {
+            let attack = one_pole(attack, sample_rate);
+            let release = one_pole(release, sample_rate);
+            for &sample in samples.iter()
+            {
+                let rms = channel.push_window(sample);
+                channel.level += if rms > channel.level { attack } else { release } * (rms - channel.level);
+            }
+        }
+        else if !samples.is_empty()
+        {
+            let square_sum: f32 = samples.iter().map(|sample| sample * sample).sum();
+            let rms = (square_sum / samples.len() as f32).sqrt();
+
+            // Smooth once per block, as much as the samples of the block would have been.
+            let frames = samples.len() as f32;
+            let attack = 1.0 - (1.0 - one_pole(attack, sample_rate)).powf(frames);
+            let release = 1.0 - (1.0 - one_pole(release, sample_rate)).powf(frames);
+            channel.level += if rms > channel.level { attack } else { release } * (rms - channel.level);
+        }
+
+        channel.level
+    }
+}
+
+impl Default for RMS {
+    fn default() -> RMS {
+        RMS::new()
     }
 }
 
 impl Chainable for RMS {
+    fn prepare(&mut self, format: &StreamFormat) {
+        if format.sample_rate > 0.0
+        {
+            self.sample_rate = format.sample_rate;
+        }
+        self.reset(format.channels);
+    }
+
+    fn process(&mut self, buffer: &Vec<Vec<f32>>, context: &BlockContext) {
+        if context.sample_rate > 0.0 && context.sample_rate != self.sample_rate
+        {
+            self.sample_rate = context.sample_rate;
+            self.reset(buffer.len());
+        }
+        self.update(buffer);
+    }
+
     fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        // Other meters' readings don't carry over.
+        let changed = self.parameters.take_changed();
+        if changed.iter().any(|name| name == "window" || name == "ballistics") || self.channels.len() != buffer.len()
+        {
+            self.reset(buffer.len());
+        }
 
-        let mut rms = 0f32;
-        for i in 0..buffer.len()
+        let ballistics = self.ballistics();
+        let window = self.window_frames() > 0;
+
+        self.rms = Vec::with_capacity(buffer.len());
+        for (ch, samples) in buffer.iter().enumerate()
         {
-            let mut square_sum = 0.0f32;
-            for x in 0..buffer[i].len() {
-                square_sum += buffer[i][x] * buffer[i][x];
+            let level = if ballistics == Ballistics::None
+            {
+                self.measure(ch, samples, window)
             }
+            else
+            {
+                self.meter(ch, samples, ballistics)
+            };
+            self.rms.push(level);
+        }
 
-            let square_mean = square_sum * 1.0f32 / buffer.len() as f32;
+        self.output.set("rms", Value::Channels(self.rms.clone()));
+    }
 
-            rms += f32::sqrt(square_mean);
-        }
+    fn output(&self) -> &Output {
+        &self.output
+    }
+
+    fn framing(&self) -> Option<(usize, usize)> {
+        self.framing
+    }
+
+    fn parameters(&self) -> Option<&Parameters> {
+        Some(&self.parameters)
+    }
+
+    fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        Some(&mut self.parameters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analysis::generator_source::{Generator, Signal, Waveform};
 
-        rms /= buffer.len() as f32;
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn generate(waveform: Waveform, amplitude: f32, frequency: f32, frames: usize) -> Vec<Vec<f32>> {
+        let mut signal = Signal::new(waveform);
+        signal.amplitude = amplitude;
+        signal.frequency = frequency;
+        Generator::new(vec![signal], SAMPLE_RATE).unwrap().generate(frames)
+    }
+
+    fn prepared() -> RMS {
+        let mut rms = RMS::new();
+        rms.prepare(&StreamFormat::new(SAMPLE_RATE as f32, 1));
+        rms
+    }
+
+    #[test]
+    fn sine_is_amplitude_over_root_two() {
+        let mut rms = prepared();
+        // Ten whole periods.
+        rms.update(&generate(Waveform::Sine, 0.5, 1000.0, 480));
+        assert!((rms.rms()[0] - 0.5 / 2f32.sqrt()).abs() < 1e-4, "{}", rms.rms()[0]);
+    }
+
+    #[test]
+    fn square_is_amplitude() {
+        let mut rms = prepared();
+        rms.update(&generate(Waveform::Square, 0.25, 100.0, 4800));
+        assert!((rms.rms()[0] - 0.25).abs() < 1e-5, "{}", rms.rms()[0]);
+    }
+
+    #[test]
+    fn impulse_is_one_over_root_length() {
+        let mut rms = prepared();
+        rms.update(&generate(Waveform::Impulse, 1.0, 0.0, 400));
+        assert!((rms.rms()[0] - 1.0 / 20.0).abs() < 1e-6, "{}", rms.rms()[0]);
+    }
+
+    #[test]
+    fn window_measures_latest_audio() {
+        let mut rms = prepared();
+        rms.set_window_ms(10.0).unwrap();
+        // Blocks that aren't whole periods, but a window of ten whole periods.
+        let mut generator = {
+            let mut signal = Signal::new(Waveform::Sine);
+            signal.frequency = 1000.0;
+            Generator::new(vec![signal], SAMPLE_RATE).unwrap()
+        };
+        for _ in 0..20
+        {
+            rms.update(&generator.generate(123));
+        }
+        assert!((rms.rms()[0] - 1.0 / 2f32.sqrt()).abs() < 1e-3, "{}", rms.rms()[0]);
+    }
 
-        self.buffer = Vec::new();
-        self.buffer.push(rms);
+    #[test]
+    fn attack_smooths_a_step_by_its_time_constant() {
+        let mut rms = prepared();
+        rms.set_attack_ms(10.0).unwrap();
+        // After a time constant, a step reaches 1 - 1/e of its level.
+        rms.update(&generate(Waveform::Square, 1.0, 100.0, 480));
+        assert!((rms.rms()[0] - (1.0 - (-1f32).exp())).abs() < 1e-3, "{}", rms.rms()[0]);
     }
 
-    fn output(&self) -> &Vec<f32> {
-        &self.buffer
+    #[test]
+    fn vu_meter_reads_the_rms_of_a_steady_sine() {
+        let mut rms = prepared();
+        rms.set_ballistics(Ballistics::VU).unwrap();
+        rms.update(&generate(Waveform::Sine, 1.0, 1000.0, SAMPLE_RATE as usize));
+        assert!((rms.rms()[0] - 1.0 / 2f32.sqrt()).abs() < 1e-2, "{}", rms.rms()[0]);
     }
 }
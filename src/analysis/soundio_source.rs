@@ -2,13 +2,21 @@ extern crate soundio;
 
 use std::collections::HashMap;
 use analysis::traits::Sourcable;
-use analysis::traits::Chainable;
-use analysis::analysis::Chain;
+use analysis::analysis::Chains;
+use analysis::device::{DeviceInfo, ChannelLayout, SampleFormat, SourceConfig};
+use analysis::stream::StreamFormat;
+use analysis::capture::{Capture, StreamStats};
 
-use std;
 
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::sync::Mutex;
+use std::sync::{Condvar, mpsc};
+use std::thread;
+use std::time::Duration;
+
+// Work for the device thread.
+type DeviceJob = Box<dyn FnOnce() + Send>;
 
 lazy_static! {
     static ref SOUNDIO_CTX: soundio::Context<'static> = {
@@ -25,166 +33,414 @@ lazy_static! {
         }
         return ctx;
     };
+
+    // Input devices as of the latest libsoundio events, kept up to date by the device thread.
+    static ref DEVICES: RwLock<HashMap<String, DeviceInfo>> = RwLock::new(HashMap::new());
+    // How many times the device thread has listed the devices, so that others can wait for the next time.
+    static ref DEVICE_SCANS: (Mutex<u64>, Condvar) = (Mutex::new(0), Condvar::new());
+    // Queue of the device thread, which is started the first time anything needs libsoundio.
+    static ref DEVICE_JOBS: Mutex<mpsc::Sender<DeviceJob>> = {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || device_thread(receiver));
+        Mutex::new(sender)
+    };
 }
 
-pub struct SoundioSource<'a> {
-    device: String,
-    channels: Vec<i32>,
+// libsoundio's context may only be used from one thread at a time, so this is the only thread that uses it.
+// It lists the devices after every event, which is either a device change or a `wakeup` for the queued jobs.
+fn device_thread(jobs: mpsc::Receiver<DeviceJob>)
+{
+    loop {
+        scan_devices();
+        SOUNDIO_CTX.wait_events();
+        while let Ok(job) = jobs.try_recv()
+        {
+            job();
+        }
+    }
+}
 
-    stream: Option<soundio::InStream<'a>>,
+// Lists the input devices into DEVICES. Only called on the device thread.
+fn scan_devices()
+{
+    // Devices plugged in or removed since the last scan only show up after the events are flushed.
+    SOUNDIO_CTX.flush_events();
 
-    error: Arc<RwLock<String>>
-}
+    let default_id = SOUNDIO_CTX.default_input_device().map(|dev| dev.id()).ok();
+    match SOUNDIO_CTX.input_devices()
+    {
+        Ok(input_devices) => {
+            let mut devices = HashMap::new();
+            for mut dev in input_devices {
+                // Raw devices have the same ID as the shared ones. Prefer the shared device, as it can be opened by others too.
+                if dev.is_raw() && devices.contains_key(&dev.id())
+                {
+                    continue;
+                }
 
-impl<'a> SoundioSource<'a> {
-    pub fn new (device: String, channels: Vec<i32>) -> SoundioSource<'a> {
-        SoundioSource {
-            device: device,
-            channels: channels,
+                devices.insert(dev.id(), device_info(&mut dev, &default_id));
+            }
+            *DEVICES.write().unwrap() = devices;
+        },
+        Err(e) => println!("Error listing devices, keeping the previous list: {}", e),
+    }
 
-            stream: Option::None,
+    let (ref scans, ref scanned) = *DEVICE_SCANS;
+    *scans.lock().unwrap() += 1;
+    scanned.notify_all();
+}
 
-            error: Arc::new(RwLock::new("".to_string()))
+// Starts the device thread if it isn't running yet and waits until it has listed the devices once.
+// Until then the context may not exist, and the first thread to use it would create it.
+fn wait_for_first_scan()
+{
+    let _ = &*DEVICE_JOBS;
+    let (ref scans, ref scanned) = *DEVICE_SCANS;
+    let mut count = scans.lock().unwrap();
+    while *count == 0
+    {
+        count = scanned.wait(count).unwrap();
+    }
+}
+
+// Runs a job on the device thread, waiting for its result.
+fn on_device_thread<T, F>(job: F) -> T where T: Send + 'static, F: FnOnce() -> T + Send + 'static
+{
+    wait_for_first_scan();
+
+    let (sender, receiver) = mpsc::channel();
+    DEVICE_JOBS.lock().unwrap().send(Box::new(move || { let _ = sender.send(job()); })).unwrap();
+    loop {
+        // Waking the context up is the one call libsoundio allows from other threads. A wakeup that comes just before
+        // the device thread starts waiting is lost, so it's repeated until the job is done.
+        SOUNDIO_CTX.wakeup();
+        match receiver.recv_timeout(Duration::from_millis(100))
+        {
+            Ok(result) => return result,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => panic!("The device thread stopped."),
         }
     }
 }
 
-impl<'a> Sourcable for SoundioSource<'a> {
-    fn start(&mut self, chain: Arc<RwLock<Chain>>) -> () {
+fn sample_format(format: soundio::Format) -> Option<SampleFormat>
+{
+    match format
+    {
+        soundio::Format::S8 => Some(SampleFormat::S8),
+        soundio::Format::U8 => Some(SampleFormat::U8),
+        soundio::Format::S16LE => Some(SampleFormat::S16LE),
+        soundio::Format::S16BE => Some(SampleFormat::S16BE),
+        soundio::Format::U16LE => Some(SampleFormat::U16LE),
+        soundio::Format::U16BE => Some(SampleFormat::U16BE),
+        soundio::Format::S24LE => Some(SampleFormat::S24LE),
+        soundio::Format::S24BE => Some(SampleFormat::S24BE),
+        soundio::Format::U24LE => Some(SampleFormat::U24LE),
+        soundio::Format::U24BE => Some(SampleFormat::U24BE),
+        soundio::Format::S32LE => Some(SampleFormat::S32LE),
+        soundio::Format::S32BE => Some(SampleFormat::S32BE),
+        soundio::Format::U32LE => Some(SampleFormat::U32LE),
+        soundio::Format::U32BE => Some(SampleFormat::U32BE),
+        soundio::Format::Float32LE => Some(SampleFormat::F32LE),
+        soundio::Format::Float32BE => Some(SampleFormat::F32BE),
+        soundio::Format::Float64LE => Some(SampleFormat::F64LE),
+        soundio::Format::Float64BE => Some(SampleFormat::F64BE),
+        _ => None,
+    }
+}
 
-        let channels = self.channels.to_vec();
-        let audio_callback = move |stream: &mut soundio::InStreamReader| {
-            // Unleave
-            let mut unleaved_buffer:Vec<Vec<f32>> = Vec::new();
+fn soundio_format(format: SampleFormat) -> soundio::Format
+{
+    match format
+    {
+        SampleFormat::S8 => soundio::Format::S8,
+        SampleFormat::U8 => soundio::Format::U8,
+        SampleFormat::S16LE => soundio::Format::S16LE,
+        SampleFormat::S16BE => soundio::Format::S16BE,
+        SampleFormat::U16LE => soundio::Format::U16LE,
+        SampleFormat::U16BE => soundio::Format::U16BE,
+        SampleFormat::S24LE => soundio::Format::S24LE,
+        SampleFormat::S24BE => soundio::Format::S24BE,
+        SampleFormat::U24LE => soundio::Format::U24LE,
+        SampleFormat::U24BE => soundio::Format::U24BE,
+        SampleFormat::S32LE => soundio::Format::S32LE,
+        SampleFormat::S32BE => soundio::Format::S32BE,
+        SampleFormat::U32LE => soundio::Format::U32LE,
+        SampleFormat::U32BE => soundio::Format::U32BE,
+        SampleFormat::F32LE => soundio::Format::Float32LE,
+        SampleFormat::F32BE => soundio::Format::Float32BE,
+        SampleFormat::F64LE => soundio::Format::Float64LE,
+        SampleFormat::F64BE => soundio::Format::Float64BE,
+    }
+}
 
-            // Initialize with empty arrays for each channel
-            for _ in 0..channels.len()
-            {
-                unleaved_buffer.push(Vec::new());
-            }
-            // Iterate through the whole interleaved buffer, moving it to unleaved buffer.
-            let mut frames_left = stream.frame_count_max();
-            loop {
-                if let Err(e) = stream.begin_read(frames_left) {
-                    println!("Error reading from stream: {}", e);
-                    return;
-                }
+// Formats the source can read, best first. Native floats need no conversion, after that the more bits the better.
+const READABLE_FORMATS: [SampleFormat; 10] = [
+    SampleFormat::F32LE, SampleFormat::F32BE,
+    SampleFormat::F64LE, SampleFormat::F64BE,
+    SampleFormat::S32LE, SampleFormat::S32BE,
+    SampleFormat::S24LE, SampleFormat::S24BE,
+    SampleFormat::S16LE, SampleFormat::S16BE,
+];
+
+// Reads a sample as a float from -1.0 to 1.0. The stream does the byte swapping for the other endianness.
+// Only NaNs and infinities are replaced with silence, tiny and denormal values are kept as they are.
+fn read_sample(stream: &soundio::InStreamReader, format: SampleFormat, channel: usize, frame: usize) -> f32
+{
+    let value = match format
+    {
+        SampleFormat::S16LE | SampleFormat::S16BE => stream.sample::<i16>(channel, frame) as f32 / 32_768.0,
+        // 24 bit samples are in the low bits of 32 bit ones, and the high byte isn't necessarily their sign.
+        SampleFormat::S24LE | SampleFormat::S24BE => {
+            let value = stream.sample::<i32>(channel, frame);
+            ((value << 8) >> 8) as f32 / 8_388_608.0
+        },
+        SampleFormat::S32LE | SampleFormat::S32BE => (stream.sample::<i32>(channel, frame) as f64 / 2_147_483_648.0) as f32,
+        SampleFormat::F32LE | SampleFormat::F32BE => stream.sample::<f32>(channel, frame),
+        SampleFormat::F64LE | SampleFormat::F64BE => stream.sample::<f64>(channel, frame) as f32,
+        _ => 0.0,
+    };
 
-                for f in 0..stream.frame_count() {
-                    for ch_i in 0..channels.len() {
-                        if f%5000 == 0
-                        {
-                            //println!("{}", stream.sample::<i32>(channels[ch_i] as usize, f));
-                        }
-
-                        let mut value = (stream.sample::<i32>(channels[ch_i] as usize, f) as f32)/std::i32::MAX as f32;
-                        if !value.is_normal()
-                        {
-                            value = 0.0f32;
-                        }
-                        unleaved_buffer[ch_i].push(value);
-                    }
-                }
+    if value.is_finite() { value } else { 0.0 }
+}
 
-                frames_left -= stream.frame_count();
-                if frames_left <= 0 {
-                    break;
-                }
+// Description of a device, in the form get_devices gives it.
+fn device_info(dev: &mut soundio::Device, default_id: &Option<String>) -> DeviceInfo
+{
+    dev.sort_channel_layouts();
+    let layouts: Vec<ChannelLayout> = dev.layouts().iter().map(|layout| ChannelLayout {
+        name: layout.name.clone(),
+        channels: layout.channels.iter().map(|channel| format!("{:?}", channel)).collect(),
+    }).collect();
+
+    let mut info = DeviceInfo::new(dev.name(), layouts.iter().map(|layout| layout.channels.len() as i32).max().unwrap_or(0));
+    info.sample_rates = dev.sample_rates().iter().map(|range| (range.min, range.max)).collect();
+    info.default_sample_rate = dev.sample_rate_current();
+    info.formats = dev.formats().into_iter().filter_map(sample_format).collect();
+    info.layouts = layouts;
+    info.is_default = !dev.is_raw() && Some(dev.id()) == *default_id;
+    info.is_raw = dev.is_raw();
+    let latency = dev.software_latency();
+    info.latency = (latency.min, latency.max);
+    info.backend = format!("{:?}", SOUNDIO_CTX.current_backend());
+    info
+}
+
+// libsoundio streams can be used from any thread, just not from several at once, which the source's mutex sees to.
+struct SoundioStream<'a>(soundio::InStream<'a>);
+unsafe impl<'a> Send for SoundioStream<'a> {}
+
+// Opens and starts a stream of the given device and channels. Runs on the device thread, as it uses the context.
+fn open_stream(device_id: String, channels: Vec<i32>, config: SourceConfig, chains: Chains, error: Arc<RwLock<String>>)
+    -> Result<(SoundioStream<'static>, Capture, SourceConfig), String>
+{
+    println!("Going to get default input device..");
+    // pa::input<f32>, pa::NonBlocking
+    let mut devices = SOUNDIO_CTX.input_devices().map_err(|e| format!("Error listing devices: {}", e))?;
+    let mut input_dev = &mut SOUNDIO_CTX.default_input_device().map_err(|_| "Error getting default input device".to_string())?;
+    for device in devices.iter_mut()
+    {
+        if device.id() == device_id
+        {
+            println!("Found a match: {}", device.id());
+            input_dev = device;
+            break;
+        }
+    }
 
-                stream.end_read();
+    // Only offer the formats we can read, best first, so that the device's best format is used by default.
+    let default_id = SOUNDIO_CTX.default_input_device().map(|dev| dev.id()).ok();
+    let mut info = device_info(input_dev, &default_id);
+    info.formats = READABLE_FORMATS.iter().filter(|format| info.formats.contains(format)).cloned().collect();
+
+    let config = config.negotiate(&info)?;
+    let sample_rate = config.sample_rate;
+    let format = config.format.unwrap();
+    chains.set_format(StreamFormat::new(sample_rate as f32, channels.len()));
+
+    // A second of audio can wait for the analysis thread.
+    let (capture, mut writer) = Capture::start(chains, channels.len(), sample_rate as usize);
+    let overflow_counters = capture.counters().clone();
+
+    let audio_callback = move |stream: &mut soundio::InStreamReader| {
+        // Iterate through the whole interleaved buffer, moving the channels we want to the ring buffer.
+        let mut frames_left = stream.frame_count_max();
+        loop {
+            if let Err(e) = stream.begin_read(frames_left) {
+                println!("Error reading from stream: {}", e);
+                return;
             }
-            match chain.try_write() {
-                Ok(lock) => lock.source_cb(unleaved_buffer, stream.frame_count()),
-                Err(e) => println!("Error writing to chain: {:?}", e)
+
+            let frame_count = stream.frame_count();
+            writer.write_frames(frame_count, |f, ch| read_sample(stream, format, channels[ch] as usize, f));
+
+            frames_left -= frame_count;
+            stream.end_read();
+
+            // libsoundio had less audio than it asked us to read, like PortAudio's input underflow.
+            if frame_count == 0 && frames_left > 0 {
+                writer.counters().underrun();
+                break;
             }
 
-            return ();
-        };
-
-        let error_capture =  self.error.clone();
-        let error_callback = move |error: soundio::Error| {
-            println!("Error: {:?}", error);
-            let mut write_lock = error_capture.write().unwrap();
-            *write_lock = error.to_string();
-        };
-
-        let soundio_format = soundio::Format::S16LE;
-        println!("Going to get default input device..");
-        // pa::input<f32>, pa::NonBlocking
-        let mut devices = SOUNDIO_CTX.input_devices().unwrap();
-        let mut input_dev = &mut SOUNDIO_CTX.default_input_device().map_err(|_| "Error getting default input device".to_string()).unwrap();
-        for device in devices.iter_mut()
-        {
-            if device.id() == self.device
-            {
-                println!("Found a match: {}", device.id());
-                input_dev = device;
+            if frames_left == 0 {
                 break;
             }
         }
+    };
+    let overflow_callback = move || {
+        overflow_counters.overrun();
+    };
+
+    let error_capture = error;
+    let error_callback = move |error: soundio::Error| {
+        println!("Error: {:?}", error);
+        let mut write_lock = error_capture.write().unwrap();
+        *write_lock = error.to_string();
+    };
+
+    let layout = input_dev.layouts()[0].clone();
+    println!("Got default input device: {:?}", input_dev.name());
+    println!("Aim: {:?}", input_dev.aim());
+    println!("Formats: {:?}", input_dev.formats());
+    println!("Using format: {:?}", format);
+    println!("Sample rates: {:?}", input_dev.sample_rates());
+    println!("Layouts: {:?}", input_dev.layouts());
+    let mut stream = input_dev.open_instream(
+        sample_rate,
+        soundio_format(format),
+        layout,
+        config.latency,
+        audio_callback,
+        Some(overflow_callback),
+        Some(error_callback),
+    ).map_err(|e| format!("Error opening stream: {}", e))?;
+    println!("Starting soundio stream..");
+
+    stream.start().map_err(|e| format!("Error starting stream: {}", e))?;
+
+    Ok((SoundioStream(stream), capture, config))
+}
+
+pub struct SoundioSource<'a> {
+    device: String,
+    channels: Vec<i32>,
+    config: SourceConfig,
+
+    // Locked, as the source is shared between threads and readers of it may check on the stream at the same time.
+    stream: Mutex<Option<SoundioStream<'a>>>,
+    chains: Chains,
+    // Runs the chains on the analysis thread. Kept after stopping, for the stats.
+    capture: Option<Capture>,
 
-        let sample_rate = input_dev.nearest_sample_rate(44100);
+    error: Arc<RwLock<String>>
+}
 
-        input_dev.sort_channel_layouts();
-        let layout = input_dev.layouts()[0].clone();
-        println!("Got default input device: {:?}", input_dev.name());
-        println!("Aim: {:?}", input_dev.aim());
-        println!("Formats: {:?}", input_dev.formats());
-        println!("Sample rates: {:?}", input_dev.sample_rates());
-        println!("Layouts: {:?}", input_dev.layouts());
-        let mut stream = input_dev.open_instream(
-            sample_rate,
-            soundio_format,
-            layout,
-            (1.0/20.0 * sample_rate as f64) / sample_rate as f64,
-            audio_callback,
-            None::<fn()>,
-            Some(error_callback),
-        ).unwrap();
-        println!("Starting soundio stream..");
+impl<'a> SoundioSource<'a> {
+    pub fn new (device: String, channels: Vec<i32>) -> SoundioSource<'a> {
+        SoundioSource {
+            device,
+            channels,
+            config: SourceConfig::new(),
 
-        stream.start().unwrap();
+            stream: Mutex::new(None),
+            chains: Chains::new(),
+            capture: Option::None,
 
-        self.stream = Option::Some(stream);
+            error: Arc::new(RwLock::new("".to_string()))
+        }
     }
 
-    fn stop(&mut self) -> () {
+    /// Blocks until the devices have been listed again after libsoundio reported a change in them, or another event.
+    /// The list itself is read with `get_devices`.
+    pub fn wait_for_device_changes() {
+        wait_for_first_scan();
+        let (ref scans, ref scanned) = *DEVICE_SCANS;
+        let mut count = scans.lock().unwrap();
+        let seen = *count;
+        while *count == seen
+        {
+            count = scanned.wait(count).unwrap();
+        }
+    }
+
+    /// Used the next time the source is started. libsoundio picks its own buffer sizes, so `frames` isn't used,
+    /// which the client is told of as an error if it asked for a buffer size.
+    pub fn set_config(&mut self, config: SourceConfig) {
+        self.config = config;
+    }
+}
+
+impl<'a> Sourcable for SoundioSource<'a> {
+    fn start(&mut self) {
+        let (device, channels, config, chains, error) =
+            (self.device.clone(), self.channels.to_vec(), self.config.clone(), self.chains.clone(), self.error.clone());
+        match on_device_thread(move || open_stream(device, channels, config, chains, error))
+        {
+            Ok((stream, capture, config)) => {
+                self.capture = Some(capture);
+                *self.stream.lock().unwrap() = Some(stream);
+                if config.frames > 0
+                {
+                    *self.error.write().unwrap() = format!("libsoundio picks its own buffer sizes, so {} frames per buffer is ignored. \
+                                                            Buffers follow the latency of {} s instead.", config.frames, config.latency);
+                }
+            },
+            Err(e) => {
+                println!("Error configuring stream: {}", e);
+                *self.error.write().unwrap() = e;
+            }
+        }
+    }
+
+    fn stop(&mut self) {
         println!("Stopping SoundIO source.");
-        self.stream.take().unwrap().pause(true).unwrap();
+        if let Some(mut stream) = self.stream.lock().unwrap().take()
+        {
+            stream.0.pause(true).unwrap();
+        }
+        if let Some(ref capture) = self.capture
+        {
+            capture.stop();
+        }
         println!("Stopped SoundIO Source!");
     }
 
+    fn chains(&self) -> &Chains {
+        &self.chains
+    }
+
     fn is_active(&self) -> bool
     {
-        match self.stream
-        {
-            Some(ref stream) => true,
-            None => return false
-        }
+        self.stream.lock().unwrap().is_some()
     }
 
-    fn get_devices() -> Result<HashMap<String, (String, i32)>, ()> where Self: Sized
+    fn get_devices() -> Result<HashMap<String, DeviceInfo>, ()> where Self: Sized
     {
-        let mut devices = HashMap::new();
+        // The list the device thread keeps, so that asking for the devices doesn't wait for the context.
+        wait_for_first_scan();
+        Ok(DEVICES.read().unwrap().clone())
+    }
 
-        for mut dev in SOUNDIO_CTX.input_devices().unwrap() {
-            dev.sort_channel_layouts();
-            devices.insert(dev.id(), (dev.name(), dev.layouts()[0].channels.len() as i32));
+    fn stats(&self) -> StreamStats
+    {
+        match self.capture
+        {
+            Some(ref capture) => capture.stats(),
+            None => StreamStats::default(),
         }
-
-        return Ok(devices);
     }
 
     fn get_and_clear_error(&self) -> Option<String>
     {
         let error_clone = self.error.read().unwrap().clone();
-        if error_clone.len() <= 0
+        if error_clone.is_empty()
         {
             return None;
         }
         *self.error.write().unwrap() = "".to_string();
 
-        return Some(error_clone);
+        Some(error_clone)
     }
 }
 
@@ -0,0 +1,59 @@
+use analysis::traits::Chainable;
+use analysis::output::{Output, Value};
+
+/// Compares two spectra, e.g. the outputs of two FFT nodes connected as its inputs.
+/// Output is "difference", the difference of each bin, first spectrum minus the second, and "distance".
+/// With a single input, compares the first two channels of it.
+pub struct SpectralDifference {
+    distance: f32,
+    output: Output,
+}
+
+impl SpectralDifference {
+    pub fn new() -> SpectralDifference {
+        SpectralDifference {
+            distance: 0.0,
+            output: Output::new(),
+        }
+    }
+
+    /// Euclidean distance between the latest two spectra.
+    pub fn distance(&self) -> f32 {
+        self.distance
+    }
+}
+
+impl Default for SpectralDifference {
+    fn default() -> SpectralDifference {
+        SpectralDifference::new()
+    }
+}
+
+impl Chainable for SpectralDifference {
+    fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        self.output.clear();
+        self.distance = 0.0;
+
+        if buffer.len() < 2
+        {
+            return;
+        }
+
+        let mut differences = Vec::with_capacity(buffer[0].len());
+        let mut square_sum = 0.0f32;
+        for (a, b) in buffer[0].iter().zip(buffer[1].iter())
+        {
+            let difference = a - b;
+            square_sum += difference * difference;
+            differences.push(difference);
+        }
+        self.distance = square_sum.sqrt();
+
+        self.output.set("difference", Value::Vector(differences));
+        self.output.set("distance", Value::Scalar(self.distance));
+    }
+
+    fn output(&self) -> &Output {
+        &self.output
+    }
+}
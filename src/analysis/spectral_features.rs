@@ -0,0 +1,322 @@
+use analysis::traits::Chainable;
+use analysis::parameters::{Parameters, ParameterError};
+use analysis::output::{Output, Value};
+use analysis::stream::{StreamFormat, BlockContext};
+
+// Keeps the logarithms of the flatness finite for empty bins.
+const MIN_POWER: f32 = 1e-10;
+
+/// Names of the features, both of their boolean parameters and of their outputs.
+pub const FEATURES: [&str; 7] = ["centroid", "spread", "flatness", "rolloff", "flux", "crest", "slope"];
+
+/// Timbral descriptors of the spectrum of a single channel.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChannelFeatures {
+    /// Magnitude-weighted mean frequency in Hz.
+    pub centroid: f32,
+    /// Magnitude-weighted standard deviation of the frequency around the centroid, in Hz.
+    pub spread: f32,
+    /// Geometric mean of the power spectrum over its arithmetic mean, from 0.0 for a tone to 1.0 for white noise.
+    pub flatness: f32,
+    /// Frequency in Hz below which the rolloff fraction of the energy is.
+    pub rolloff: f32,
+    /// Euclidean distance of the magnitudes from those of the previous spectrum.
+    pub flux: f32,
+    /// Highest magnitude over the mean magnitude.
+    pub crest: f32,
+    /// Slope of the line best fitting the magnitudes by frequency, over the sum of the magnitudes, per Hz.
+    pub slope: f32,
+}
+
+impl ChannelFeatures {
+    fn new() -> ChannelFeatures {
+        ChannelFeatures { centroid: 0.0, spread: 0.0, flatness: 0.0, rolloff: 0.0, flux: 0.0, crest: 0.0, slope: 0.0 }
+    }
+
+    /// Value of a feature by its name in `FEATURES`.
+    pub fn get(&self, feature: &str) -> Option<f32> {
+        match feature
+        {
+            "centroid" => Some(self.centroid),
+            "spread" => Some(self.spread),
+            "flatness" => Some(self.flatness),
+            "rolloff" => Some(self.rolloff),
+            "flux" => Some(self.flux),
+            "crest" => Some(self.crest),
+            "slope" => Some(self.slope),
+            _ => None,
+        }
+    }
+}
+
+/// Spectral centroid, spread, flatness, rolloff, flux, crest and slope of each channel, from magnitude spectra,
+/// e.g. the output of an FFT node of the same size connected as its input.
+/// Each channel of the input of `size / 2 + 1` values is a spectrum.
+/// Output is each enabled feature by its name, one value per channel.
+/// Parameters are booleans named after the features, to enable each, all enabled by default, and "rolloff_fraction".
+pub struct SpectralFeatures {
+    parameters: Parameters,
+    sample_rate: f32,
+    size: usize,
+
+    previous: Vec<Vec<f32>>,
+    features: Vec<ChannelFeatures>,
+    output: Output,
+}
+
+impl SpectralFeatures {
+    /// Features of the spectra of an FFT of `size` samples.
+    pub fn new(size: usize) -> SpectralFeatures {
+        assert!(size >= 2, "Spectral features need an FFT size of at least 2");
+
+        let mut parameters = Parameters::new();
+        for feature in FEATURES.iter()
+        {
+            parameters.add_boolean(feature, true);
+        }
+        parameters.add_float("rolloff_fraction", 0.85, 0.0, 1.0);
+
+        SpectralFeatures {
+            parameters,
+            sample_rate: 44_100.0,
+            size,
+
+            previous: Vec::new(),
+            features: Vec::new(),
+            output: Output::new(),
+        }
+    }
+
+    /// Enables or disables a feature by its name in `FEATURES`.
+    pub fn set_enabled(&mut self, feature: &str, enabled: bool) -> Result<(), ParameterError> {
+        self.parameters.set_boolean(feature, enabled)
+    }
+
+    pub fn is_enabled(&self, feature: &str) -> bool {
+        self.parameters.get_boolean(feature)
+    }
+
+    /// Fraction of the energy below the rolloff frequency, e.g. 0.85 or 0.95.
+    pub fn set_rolloff_fraction(&mut self, fraction: f32) -> Result<(), ParameterError> {
+        self.parameters.set_float("rolloff_fraction", fraction)
+    }
+
+    pub fn bins(&self) -> usize {
+        self.size / 2 + 1
+    }
+
+    /// Features of each channel. All of them are measured, the parameters only choose which are output.
+    pub fn features(&self) -> &Vec<ChannelFeatures> {
+        &self.features
+    }
+
+    pub fn channel(&self, channel: usize) -> ChannelFeatures {
+        self.features[channel]
+    }
+
+    fn measure(&self, magnitudes: &[f32], previous: Option<&Vec<f32>>) -> ChannelFeatures {
+        let mut features = ChannelFeatures::new();
+        let bins = magnitudes.len();
+        let bin_width = self.sample_rate / self.size as f32;
+
+        let magnitude_sum: f32 = magnitudes.iter().sum();
+        let power_sum: f32 = magnitudes.iter().map(|m| m * m).sum();
+
+        if let Some(previous) = previous
+        {
+            features.flux = magnitudes.iter().zip(previous.iter()).map(|(m, p)| (m - p) * (m - p)).sum::<f32>().sqrt();
+        }
+
+        if magnitude_sum <= 0.0
+        {
+            return features;
+        }
+
+        features.centroid = magnitudes.iter().enumerate().map(|(k, m)| k as f32 * bin_width * m).sum::<f32>() / magnitude_sum;
+        let variance = magnitudes.iter().enumerate()
+            .map(|(k, m)| (k as f32 * bin_width - features.centroid).powi(2) * m)
+            .sum::<f32>() / magnitude_sum;
+        features.spread = variance.sqrt();
+
+        let log_mean = magnitudes.iter().map(|m| (m * m).max(MIN_POWER).ln()).sum::<f32>() / bins as f32;
+        let mean = (power_sum / bins as f32).max(MIN_POWER);
+        features.flatness = (log_mean.exp() / mean).min(1.0);
+
+        let target = self.parameters.get_float("rolloff_fraction") * power_sum;
+        let mut cumulative = 0.0f32;
+        for (k, m) in magnitudes.iter().enumerate()
+        {
+            cumulative += m * m;
+            if cumulative >= target
+            {
+                features.rolloff = k as f32 * bin_width;
+                break;
+            }
+        }
+
+        let highest = magnitudes.iter().fold(0.0f32, |highest, &m| highest.max(m));
+        features.crest = highest / (magnitude_sum / bins as f32);
+
+        // Least squares fit of the magnitudes by frequency, Peeters 2004.
+        let n = bins as f32;
+        let frequency_sum: f32 = (0..bins).map(|k| k as f32 * bin_width).sum();
+        let frequency_square_sum: f32 = (0..bins).map(|k| (k as f32 * bin_width).powi(2)).sum();
+        let product_sum: f32 = magnitudes.iter().enumerate().map(|(k, m)| k as f32 * bin_width * m).sum();
+        let denominator = n * frequency_square_sum - frequency_sum * frequency_sum;
+        if denominator > 0.0
+        {
+            features.slope = (n * product_sum - frequency_sum * magnitude_sum) / denominator / magnitude_sum;
+        }
+
+        features
+    }
+}
+
+impl Chainable for SpectralFeatures {
+    fn prepare(&mut self, format: &StreamFormat) {
+        if format.sample_rate > 0.0
+        {
+            self.sample_rate = format.sample_rate;
+        }
+        self.previous = Vec::new();
+    }
+
+    fn process(&mut self, buffer: &Vec<Vec<f32>>, context: &BlockContext) {
+        if context.sample_rate > 0.0
+        {
+            self.sample_rate = context.sample_rate;
+        }
+        self.update(buffer);
+    }
+
+    fn update(&mut self, buffer: &Vec<Vec<f32>>) {
+        let bins = self.bins();
+        let spectra: Vec<&Vec<f32>> = buffer.iter().filter(|spectrum| spectrum.len() == bins).collect();
+        if spectra.is_empty()
+        {
+            return;
+        }
+
+        if self.previous.len() != spectra.len()
+        {
+            self.previous = Vec::new();
+            self.features = vec![ChannelFeatures::new(); spectra.len()];
+        }
+
+        let features: Vec<ChannelFeatures> = spectra.iter().enumerate()
+            .map(|(ch, spectrum)| self.measure(spectrum, self.previous.get(ch)))
+            .collect();
+        self.features = features;
+        self.previous = spectra.iter().map(|spectrum| spectrum.to_vec()).collect();
+
+        self.output.clear();
+        for feature in FEATURES.iter()
+        {
+            if self.parameters.get_boolean(feature)
+            {
+                let values = self.features.iter().map(|features| features.get(feature).unwrap_or(0.0)).collect();
+                self.output.set(feature, Value::Channels(values));
+            }
+        }
+    }
+
+    fn output(&self) -> &Output {
+        &self.output
+    }
+
+    fn parameters(&self) -> Option<&Parameters> {
+        Some(&self.parameters)
+    }
+
+    fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        Some(&mut self.parameters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analysis::fft::FFT;
+    use analysis::generator_source::{Generator, Signal, Waveform};
+
+    const SAMPLE_RATE: u32 = 48_000;
+    const SIZE: usize = 1024;
+
+    fn generate(waveform: Waveform, amplitude: f32, frequency: f32, frames: usize) -> Vec<Vec<f32>> {
+        let mut signal = Signal::new(waveform);
+        signal.amplitude = amplitude;
+        signal.frequency = frequency;
+        Generator::new(vec![signal], SAMPLE_RATE).unwrap().generate(frames)
+    }
+
+    // Magnitudes of the power averaged over the spectra of consecutive frames of the audio, as Welch's method does.
+    fn spectrum(audio: &[f32]) -> Vec<f32> {
+        let mut fft = FFT::new(SIZE);
+        fft.prepare(&StreamFormat::new(SAMPLE_RATE as f32, 1));
+
+        let mut power = vec![0.0f32; fft.bins()];
+        let frames = audio.len() / SIZE;
+        for frame in 0..frames
+        {
+            fft.update(&vec![audio[frame * SIZE..(frame + 1) * SIZE].to_vec()]);
+            for (sum, magnitude) in power.iter_mut().zip(fft.magnitudes(0).iter())
+            {
+                *sum += magnitude * magnitude;
+            }
+        }
+        power.iter().map(|sum| (sum / frames as f32).sqrt()).collect()
+    }
+
+    fn measure(spectrum: Vec<f32>) -> ChannelFeatures {
+        let mut features = SpectralFeatures::new(SIZE);
+        features.prepare(&StreamFormat::new(SAMPLE_RATE as f32, 1));
+        features.process(&vec![spectrum], &BlockContext::new(SAMPLE_RATE as f32, 0));
+        features.channel(0)
+    }
+
+    #[test]
+    fn white_noise_is_flat() {
+        // A single spectrum of noise is anything but flat bin to bin, its flatness is around 0.56. Averaged, it's flat.
+        let noise = generate(Waveform::WhiteNoise, 0.5, 0.0, 256 * SIZE).remove(0);
+        let features = measure(spectrum(&noise));
+        assert!(features.flatness > 0.95, "{}", features.flatness);
+    }
+
+    #[test]
+    fn sine_is_not_flat() {
+        // 3 kHz is exactly bin 64.
+        let sine = generate(Waveform::Sine, 0.5, 3000.0, SIZE).remove(0);
+        let features = measure(spectrum(&sine));
+        assert!(features.flatness < 0.01, "{}", features.flatness);
+        assert!((features.centroid - 3000.0).abs() < 1.0, "{}", features.centroid);
+    }
+
+    #[test]
+    fn single_bin_is_at_its_frequency() {
+        let mut magnitudes = vec![0.0f32; SIZE / 2 + 1];
+        magnitudes[100] = 0.25;
+
+        let features = measure(magnitudes);
+        let frequency = 100.0 * SAMPLE_RATE as f32 / SIZE as f32;
+        assert!((features.centroid - frequency).abs() < 1e-3, "{}", features.centroid);
+        assert!(features.spread.abs() < 1e-3, "{}", features.spread);
+        assert_eq!(features.rolloff, frequency);
+        assert!((features.crest - (SIZE / 2 + 1) as f32).abs() < 1e-3, "{}", features.crest);
+    }
+
+    #[test]
+    fn flux_is_the_distance_from_the_previous_spectrum() {
+        let mut features = SpectralFeatures::new(SIZE);
+        features.prepare(&StreamFormat::new(SAMPLE_RATE as f32, 1));
+
+        let mut magnitudes = vec![0.0f32; SIZE / 2 + 1];
+        magnitudes[10] = 0.3;
+        features.update(&vec![magnitudes.clone()]);
+        assert_eq!(features.channel(0).flux, 0.0);
+
+        magnitudes[10] = 0.0;
+        magnitudes[20] = 0.4;
+        features.update(&vec![magnitudes]);
+        assert!((features.channel(0).flux - 0.5).abs() < 1e-6, "{}", features.channel(0).flux);
+    }
+}
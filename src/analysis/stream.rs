@@ -0,0 +1,49 @@
+use std::time::Instant;
+
+/// Format of the audio a node gets, told to the node when its chain starts.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct StreamFormat {
+    pub sample_rate: f32,
+    pub channels: usize,
+}
+
+impl StreamFormat {
+    pub fn new(sample_rate: f32, channels: usize) -> StreamFormat {
+        StreamFormat {
+            sample_rate,
+            channels,
+        }
+    }
+}
+
+/// Where a block of audio is in the stream, passed to the nodes with each block.
+#[derive(Clone, Copy, Debug)]
+pub struct BlockContext {
+    pub sample_rate: f32,
+    /// Position of the block's first frame, counted from when the source started.
+    pub frame: u64,
+    /// When the block's audio arrived from the source. For live sources, when the audio callback received it.
+    pub timestamp: Instant,
+}
+
+impl BlockContext {
+    pub fn new(sample_rate: f32, frame: u64) -> BlockContext {
+        BlockContext {
+            sample_rate,
+            frame,
+            timestamp: Instant::now(),
+        }
+    }
+
+    /// Position of the block's first frame in seconds.
+    pub fn time(&self) -> f64 {
+        if self.sample_rate > 0.0
+        {
+            self.frame as f64 / self.sample_rate as f64
+        }
+        else
+        {
+            0.0
+        }
+    }
+}
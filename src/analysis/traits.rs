@@ -1,22 +1,96 @@
-use std::cell::RefCell;
-use std;
 
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::rc::Rc;
 use std::collections::HashMap;
 use analysis::analysis::Chain;
+use analysis::analysis::Chains;
+use analysis::parameters::Parameters;
+use analysis::output::Output;
+use analysis::device::DeviceInfo;
+use analysis::stream::{StreamFormat, BlockContext};
+use analysis::capture::StreamStats;
 
 
-pub trait Sourcable {
-    fn start(&mut self, chain: Arc<RwLock<Chain>>);
+// Sources and nodes are shared with the threads that drive the chains, so they must be thread safe.
+pub trait Sourcable: Send + Sync {
+    /// Starts passing audio to the attached chains.
+    fn start(&mut self);
     fn stop(&mut self);
-    fn get_devices() -> Result<HashMap<String, (String, i32)>, ()> where Self: Sized;
+    /// Chains the source passes its audio to.
+    fn chains(&self) -> &Chains;
+
+    /// Chains can be attached and detached while the source is running.
+    fn attach(&mut self, chain_id: u64, chain: Arc<RwLock<Chain>>) {
+        self.chains().attach(chain_id, chain);
+    }
+
+    fn detach(&mut self, chain_id: u64) {
+        self.chains().detach(chain_id);
+    }
+
+    /// Format of the audio the source passes on. None until the source has started.
+    fn format(&self) -> Option<StreamFormat> {
+        self.chains().format()
+    }
+
+    /// Devices the source can be created for, by device ID.
+    #[allow(clippy::result_unit_err)]
+    fn get_devices() -> Result<HashMap<String, DeviceInfo>, ()> where Self: Sized;
     fn is_active(&self) -> bool;
     fn get_and_clear_error(&self) -> Option<String>;
+
+    /// Audio lost since the source started. Sources that don't run on a real-time callback lose none.
+    fn stats(&self) -> StreamStats {
+        StreamStats::default()
+    }
 }
 
-pub trait Chainable {
+// Nodes take their audio as `&Vec` like they always have, so that nodes written against the trait keep compiling.
+#[allow(clippy::ptr_arg)]
+pub trait Chainable: Send + Sync {
     fn update(&mut self, buffer: &Vec<Vec<f32>>);
-    fn output(&self) -> &Vec<f32>;
+    /// Results of the latest update. Empty for nodes that only process audio.
+    fn output(&self) -> &Output;
+
+    /// Called when the chain starts, with the format of the audio the node is going to get.
+    fn prepare(&mut self, _format: &StreamFormat) {
+    }
+
+    /// Frame size and hop the node wants its audio in. The chain then collects the audio into frames of exactly
+    /// that size, starting a new frame every `hop` frames, and updates the node once per frame.
+    /// None for nodes that take blocks of whatever size the source gives.
+    fn framing(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    /// Called by the chain for each block. Nodes that need the block's timing override this instead of `update`.
+    fn process(&mut self, buffer: &Vec<Vec<f32>>, _context: &BlockContext) {
+        self.update(buffer);
+    }
+
+    /// Audio produced by the latest update, for nodes that process audio for the nodes after them.
+    /// Nodes that only analyse audio return None, and the nodes after them get the same audio they did.
+    fn processed(&self) -> Option<&Vec<Vec<f32>>> {
+        None
+    }
+
+    /// Parameters that can be changed while audio is running. None for nodes without parameters.
+    fn parameters(&self) -> Option<&Parameters> {
+        None
+    }
+
+    fn parameters_mut(&mut self) -> Option<&mut Parameters> {
+        None
+    }
+
+    /// Called instead of `process` for nodes with several inputs, with what each input passed on.
+    /// By default the channels of all the inputs are put one after another into a single buffer.
+    fn update_inputs(&mut self, inputs: &Vec<Vec<Vec<f32>>>, context: &BlockContext) {
+        let mut merged = Vec::new();
+        for input in inputs.iter()
+        {
+            merged.extend(input.iter().cloned());
+        }
+        self.process(&merged, context);
+    }
 }
@@ -0,0 +1,533 @@
+use analysis::traits::Sourcable;
+use analysis::analysis::Chains;
+use analysis::device::DeviceInfo;
+use analysis::stream::StreamFormat;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use std::sync::Arc;
+use std::sync::RwLock;
+
+// Amount of frames handed to the chain per callback.
+const FRAMES: usize = 256;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    data[at] as u16 | ((data[at + 1] as u16) << 8)
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    data[at] as u32 | ((data[at + 1] as u32) << 8) | ((data[at + 2] as u32) << 16) |
+    ((data[at + 3] as u32) << 24)
+}
+
+/// A decoded WAV file. Samples are de-interleaved and normalized to [-1.0, 1.0].
+pub struct WavFile {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub samples: Vec<Vec<f32>>,
+}
+
+impl WavFile {
+    pub fn open(path: &str) -> Result<WavFile, String> {
+        let mut file = File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|e| format!("Could not read {}: {}", path, e))?;
+
+        WavFile::decode(&data)
+    }
+
+    /// Decodes a whole RIFF/WAVE file from memory.
+    /// Supports 8, 16, 24 and 32-bit PCM and 32 and 64-bit IEEE float, also when wrapped in WAVE_FORMAT_EXTENSIBLE.
+    pub fn decode(data: &[u8]) -> Result<WavFile, String> {
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE"
+        {
+            return Err("Not a RIFF/WAVE file.".to_string());
+        }
+
+        let mut format: Option<(u16, usize, u32, usize)> = None;
+        let mut samples_data: Option<&[u8]> = None;
+
+        // Walk through the chunks. Each chunk is an ID, a length and the chunk data padded to even length.
+        let mut i = 12;
+        while i + 8 <= data.len()
+        {
+            let chunk_id = &data[i..i + 4];
+            let chunk_length = read_u32(data, i + 4) as usize;
+            let chunk_start = i + 8;
+            // Some writers leave the data chunk length unset when streaming, so clamp it to what we've got.
+            let chunk_end = if chunk_length > data.len() - chunk_start { data.len() } else { chunk_start + chunk_length };
+
+            if chunk_id == b"fmt "
+            {
+                if chunk_end - chunk_start < 16
+                {
+                    return Err("Format chunk is too short.".to_string());
+                }
+
+                let mut format_tag = read_u16(data, chunk_start);
+                let channels = read_u16(data, chunk_start + 2) as usize;
+                let sample_rate = read_u32(data, chunk_start + 4);
+                let bits_per_sample = read_u16(data, chunk_start + 14) as usize;
+
+                // The actual format of an extensible file is in the first two bytes of the sub-format GUID.
+                if format_tag == WAVE_FORMAT_EXTENSIBLE
+                {
+                    if chunk_end - chunk_start < 26
+                    {
+                        return Err("Extensible format chunk is too short.".to_string());
+                    }
+                    format_tag = read_u16(data, chunk_start + 24);
+                }
+
+                format = Some((format_tag, channels, sample_rate, bits_per_sample));
+            }
+            else if chunk_id == b"data"
+            {
+                samples_data = Some(&data[chunk_start..chunk_end]);
+            }
+
+            // A malformed length can point past what a usize holds, in which case there's nothing more to read.
+            i = match chunk_start.checked_add(chunk_length).and_then(|end| end.checked_add(chunk_length & 1))
+            {
+                Some(next) => next,
+                None => break,
+            };
+        }
+
+        let (format_tag, channels, sample_rate, bits_per_sample) = match format
+        {
+            Some(format) => format,
+            None => return Err("No format chunk found.".to_string()),
+        };
+        let samples_data = match samples_data
+        {
+            Some(samples_data) => samples_data,
+            None => return Err("No data chunk found.".to_string()),
+        };
+
+        if channels == 0
+        {
+            return Err("File has no channels.".to_string());
+        }
+        if sample_rate == 0
+        {
+            return Err("File has a sample rate of 0.".to_string());
+        }
+
+        let bytes_per_sample = (bits_per_sample + 7) / 8;
+        let convert: fn(&[u8]) -> f32 = match (format_tag, bytes_per_sample)
+        {
+            (WAVE_FORMAT_PCM, 1) => |b| (b[0] as f32 - 128.0) / 128.0,
+            (WAVE_FORMAT_PCM, 2) => |b| (read_u16(b, 0) as i16) as f32 / 32_768.0,
+            // Shift the 24-bit value to the top of an i32 and back to get the sign right.
+            (WAVE_FORMAT_PCM, 3) => |b| (((b[0] as i32) << 8 | (b[1] as i32) << 16 | (b[2] as i32) << 24) >> 8) as f32 / 8_388_608.0,
+            (WAVE_FORMAT_PCM, 4) => |b| (read_u32(b, 0) as i32) as f32 / 2_147_483_648.0,
+            (WAVE_FORMAT_IEEE_FLOAT, 4) => |b| f32::from_bits(read_u32(b, 0)),
+            (WAVE_FORMAT_IEEE_FLOAT, 8) => |b| f64::from_bits(read_u32(b, 0) as u64 | ((read_u32(b, 4) as u64) << 32)) as f32,
+            _ => return Err(format!("Unsupported format {} with {} bits per sample.", format_tag, bits_per_sample)),
+        };
+
+        let frame_size = bytes_per_sample * channels;
+        let frames = samples_data.len() / frame_size;
+
+        let mut samples: Vec<Vec<f32>> = Vec::new();
+        for _ in 0..channels
+        {
+            samples.push(Vec::with_capacity(frames));
+        }
+
+        for frame in samples_data.chunks(frame_size).take(frames)
+        {
+            for ch in 0..channels
+            {
+                samples[ch].push(convert(&frame[ch * bytes_per_sample..(ch + 1) * bytes_per_sample]));
+            }
+        }
+
+        Ok(WavFile {
+            sample_rate,
+            channels,
+            samples,
+        })
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples[0].len()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PlaybackMode {
+    /// Pass the file to the chain as fast as the chain consumes it.
+    Batch,
+    /// Pass the file to the chain at the file's sample rate, like a live device would.
+    Paced,
+}
+
+// State shared between the source and its playback thread.
+struct Playback {
+    // Bumped on every start and stop, so that a thread from an earlier start knows to quit.
+    generation: u64,
+    active: bool,
+    finished: bool,
+    position: usize,
+}
+
+/// Reads audio from a WAV file and passes it to the chain.
+/// Stopping pauses the playback and starting again resumes it from where it was.
+pub struct WavSource {
+    path: String,
+    channels: Vec<i32>,
+
+    mode: PlaybackMode,
+    looping: bool,
+
+    // Selected channels of the file and the file's sample rate, loaded on first start.
+    samples: Option<Arc<Vec<Vec<f32>>>>,
+    sample_rate: u32,
+
+    playback: Arc<RwLock<Playback>>,
+    chains: Chains,
+
+    error: Arc<RwLock<String>>
+}
+
+impl WavSource {
+    pub fn new(path: String, channels: Vec<i32>) -> WavSource {
+        WavSource {
+            path,
+            channels,
+
+            mode: PlaybackMode::Batch,
+            looping: false,
+
+            samples: Option::None,
+            sample_rate: 0,
+
+            playback: Arc::new(RwLock::new(Playback {
+                generation: 0,
+                active: false,
+                finished: false,
+                position: 0,
+            })),
+            chains: Chains::new(),
+
+            error: Arc::new(RwLock::new("".to_string()))
+        }
+    }
+
+    pub fn set_playback_mode(&mut self, mode: PlaybackMode) {
+        self.mode = mode;
+    }
+
+    /// When looping, playback restarts from the beginning of the file instead of finishing.
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+
+    /// True once playback has reached the end of the file. Starting again plays the file from the beginning.
+    pub fn is_finished(&self) -> bool {
+        self.playback.read().unwrap().finished
+    }
+
+    fn set_error(&self, error: String) {
+        println!("Error: {}", error);
+        *self.error.write().unwrap() = error;
+    }
+
+    fn load(&mut self) -> Result<Arc<Vec<Vec<f32>>>, String> {
+        if let Some(ref samples) = self.samples
+        {
+            return Ok(samples.clone());
+        }
+
+        let wav = WavFile::open(&self.path)?;
+
+        // Pick the channels we want, same as the live sources do.
+        let mut samples: Vec<Vec<f32>> = Vec::new();
+        for i in 0..self.channels.len()
+        {
+            let channel = self.channels[i];
+            if channel < 0 || channel as usize >= wav.channels
+            {
+                return Err(format!("Channel {} not found, file has {} channels.", channel, wav.channels));
+            }
+            samples.push(wav.samples[channel as usize].clone());
+        }
+
+        let samples = Arc::new(samples);
+        self.samples = Some(samples.clone());
+        self.sample_rate = wav.sample_rate;
+
+        Ok(samples)
+    }
+}
+
+impl Sourcable for WavSource {
+    fn start(&mut self) {
+        let samples = match self.load()
+        {
+            Ok(samples) => samples,
+            Err(e) => {
+                self.set_error(e);
+                return;
+            }
+        };
+
+        let generation;
+        {
+            let mut playback = self.playback.write().unwrap();
+            if playback.active
+            {
+                return;
+            }
+            if playback.finished
+            {
+                playback.finished = false;
+                playback.position = 0;
+            }
+            playback.generation += 1;
+            playback.active = true;
+            generation = playback.generation;
+
+            self.chains.set_format(StreamFormat::new(self.sample_rate as f32, samples.len()));
+            self.chains.set_position(playback.position as u64);
+        }
+
+        let playback = self.playback.clone();
+        let chains = self.chains.clone();
+        let error = self.error.clone();
+        let paced = self.mode == PlaybackMode::Paced;
+        let looping = self.looping;
+        let sample_rate = self.sample_rate as f64;
+        let frames = if !samples.is_empty() { samples[0].len() } else { 0 };
+        thread::spawn(move || {
+            let started = Instant::now();
+            let mut frames_played = 0u64;
+
+            loop {
+                let position = {
+                    let playback = playback.read().unwrap();
+                    if playback.generation != generation
+                    {
+                        return;
+                    }
+                    playback.position
+                };
+
+                if position >= frames
+                {
+                    if looping && frames > 0
+                    {
+                        playback.write().unwrap().position = 0;
+                        continue;
+                    }
+
+                    let mut playback = playback.write().unwrap();
+                    if playback.generation == generation
+                    {
+                        playback.active = false;
+                        playback.finished = true;
+                        *error.write().unwrap() = "End of file reached.".to_string();
+                    }
+                    return;
+                }
+
+                let end = if position + FRAMES < frames { position + FRAMES } else { frames };
+
+                let mut block: Vec<Vec<f32>> = Vec::new();
+                for ch in 0..samples.len()
+                {
+                    block.push(samples[ch][position..end].to_vec());
+                }
+
+                chains.source_cb(block, end - position);
+
+                {
+                    let mut playback = playback.write().unwrap();
+                    if playback.generation != generation
+                    {
+                        return;
+                    }
+                    playback.position = end;
+                }
+
+                // Sleep until the wall clock catches up with the audio we've played so far.
+                if paced
+                {
+                    frames_played += (end - position) as u64;
+                    let target = Duration::from_millis((frames_played as f64 * 1000.0 / sample_rate) as u64);
+                    let elapsed = started.elapsed();
+                    if target > elapsed
+                    {
+                        thread::sleep(target - elapsed);
+                    }
+                }
+            }
+        });
+    }
+
+    fn stop(&mut self) {
+        let mut playback = self.playback.write().unwrap();
+        playback.generation += 1;
+        playback.active = false;
+    }
+
+    fn chains(&self) -> &Chains {
+        &self.chains
+    }
+
+    fn is_active(&self) -> bool
+    {
+        self.playback.read().unwrap().active
+    }
+
+    fn get_devices() -> Result<HashMap<String, DeviceInfo>, ()> where Self: Sized
+    {
+        // Files aren't devices.
+        Ok(HashMap::new())
+    }
+
+    fn get_and_clear_error(&self) -> Option<String>
+    {
+        let error_clone = self.error.read().unwrap().clone();
+        if error_clone.is_empty()
+        {
+            return None;
+        }
+        *self.error.write().unwrap() = "".to_string();
+
+        Some(error_clone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        let length = data.len() as u32;
+        bytes.extend([length as u8, (length >> 8) as u8, (length >> 16) as u8, (length >> 24) as u8].iter());
+        bytes.extend(data.iter());
+        if data.len() % 2 == 1
+        {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn format_chunk(format_tag: u16, channels: u16, bits_per_sample: u16) -> Vec<u8> {
+        let block_align = channels * bits_per_sample / 8;
+        let mut data = Vec::new();
+        for &value in [format_tag, channels].iter()
+        {
+            data.extend([value as u8, (value >> 8) as u8].iter());
+        }
+        // 48 kHz and the byte rate that goes with it.
+        let byte_rate = 48_000 * block_align as u32;
+        data.extend([0x80, 0xBB, 0x00, 0x00].iter());
+        data.extend([byte_rate as u8, (byte_rate >> 8) as u8, (byte_rate >> 16) as u8, (byte_rate >> 24) as u8].iter());
+        for &value in [block_align, bits_per_sample].iter()
+        {
+            data.extend([value as u8, (value >> 8) as u8].iter());
+        }
+        data
+    }
+
+    fn wav(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.iter().cloned()).collect();
+        let mut bytes = chunk(b"RIFF", &[b"WAVE".to_vec(), body].concat());
+        bytes.truncate(8 + 4 + chunks.iter().map(|chunk| chunk.len()).sum::<usize>());
+        bytes
+    }
+
+    #[test]
+    fn decodes_8_bit_pcm() {
+        let file = WavFile::decode(&wav(&[chunk(b"fmt ", &format_chunk(WAVE_FORMAT_PCM, 1, 8)),
+                                          chunk(b"data", &[0, 128, 255, 64])])).unwrap();
+        assert_eq!(file.sample_rate, 48_000);
+        assert_eq!(file.samples, vec![vec![-1.0, 0.0, 127.0 / 128.0, -0.5]]);
+    }
+
+    #[test]
+    fn decodes_16_bit_pcm_into_channels() {
+        let file = WavFile::decode(&wav(&[chunk(b"fmt ", &format_chunk(WAVE_FORMAT_PCM, 2, 16)),
+                                          chunk(b"data", &[0x00, 0x80, 0xFF, 0x7F, 0x00, 0x40, 0xFF, 0xFF])])).unwrap();
+        assert_eq!(file.channels, 2);
+        assert_eq!(file.samples, vec![vec![-1.0, 0.5], vec![32_767.0 / 32_768.0, -1.0 / 32_768.0]]);
+    }
+
+    #[test]
+    fn decodes_24_bit_pcm_with_sign() {
+        let file = WavFile::decode(&wav(&[chunk(b"fmt ", &format_chunk(WAVE_FORMAT_PCM, 1, 24)),
+                                          chunk(b"data", &[0x00, 0x00, 0x80, 0xFF, 0xFF, 0x7F, 0xFF, 0xFF, 0xFF])]))
+            .unwrap();
+        assert_eq!(file.samples, vec![vec![-1.0, 8_388_607.0 / 8_388_608.0, -1.0 / 8_388_608.0]]);
+    }
+
+    #[test]
+    fn decodes_extensible_float() {
+        // The extensible fields follow the basic ones: their size, valid bits, channel mask and the sub-format GUID,
+        // which starts with the actual format tag.
+        let mut format = format_chunk(WAVE_FORMAT_EXTENSIBLE, 1, 32);
+        format.extend([22, 0, 32, 0, 4, 0, 0, 0, 3, 0].iter());
+        format.extend([0, 0, 0, 0, 0x10, 0, 0x80, 0, 0, 0xAA, 0, 0x38, 0x9B, 0x71].iter());
+
+        let samples: Vec<u8> = [0.25f32, -0.75].iter().flat_map(|sample| {
+            let bits = sample.to_bits();
+            vec![bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]
+        }).collect();
+        let file = WavFile::decode(&wav(&[chunk(b"fmt ", &format), chunk(b"data", &samples)])).unwrap();
+        assert_eq!(file.samples, vec![vec![0.25, -0.75]]);
+    }
+
+    #[test]
+    fn skips_padding_after_odd_chunks() {
+        let file = WavFile::decode(&wav(&[chunk(b"fmt ", &format_chunk(WAVE_FORMAT_PCM, 1, 8)),
+                                          chunk(b"LIST", &[1, 2, 3]),
+                                          chunk(b"data", &[255, 0])])).unwrap();
+        assert_eq!(file.samples, vec![vec![127.0 / 128.0, -1.0]]);
+    }
+
+    #[test]
+    fn reads_what_there_is_of_a_truncated_data_chunk() {
+        let mut data = chunk(b"data", &[0x00, 0x40, 0x00, 0xC0, 0x00]);
+        // Claims far more than there is, as streaming writers leave it.
+        data[4..8].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0x7F]);
+        data.truncate(8 + 5);
+
+        let file = WavFile::decode(&wav(&[chunk(b"fmt ", &format_chunk(WAVE_FORMAT_PCM, 1, 16)), data])).unwrap();
+        // The half sample at the end is left out.
+        assert_eq!(file.samples, vec![vec![0.5, -0.5]]);
+    }
+
+    #[test]
+    fn stops_at_chunk_lengths_past_the_end() {
+        let mut junk = chunk(b"junk", &[]);
+        junk[4..8].copy_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]);
+
+        let result = WavFile::decode(&wav(&[chunk(b"fmt ", &format_chunk(WAVE_FORMAT_PCM, 1, 16)), junk,
+                                            chunk(b"data", &[0, 0])]));
+        assert_eq!(result.err(), Some("No data chunk found.".to_string()));
+    }
+
+    #[test]
+    fn rejects_what_it_cant_decode() {
+        assert!(WavFile::decode(b"RIFF\0\0\0\0WAVE").is_err());
+        // ADPCM.
+        assert!(WavFile::decode(&wav(&[chunk(b"data", &[0, 0])])).is_err());
+        assert!(WavFile::decode(&wav(&[chunk(b"fmt ", &format_chunk(2, 1, 16)),
+                                       chunk(b"data", &[0, 0])])).is_err());
+        assert!(WavFile::decode(&wav(&[chunk(b"fmt ", &format_chunk(WAVE_FORMAT_PCM, 0, 16)),
+                                       chunk(b"data", &[0, 0])])).is_err());
+    }
+}
@@ -37,7 +37,13 @@ fn main() {
                     {
                         let rms_msg = messages::MsgRMSPacket::deserialized(data[8..].to_vec());
 
-                        println!("RMS: {:?}", rms_msg.value);
+                        println!("RMS: {:?} channels {:?}", rms_msg.value, rms_msg.channels);
+                    }
+                    else if msg_type == messages::MsgType::MSG_DB_PACKET as i32
+                    {
+                        let db_msg = messages::MsgDBPacket::deserialized(data[8..].to_vec());
+
+                        println!("dB: RMS {:?} peak {:?} channels {:?} {:?}", db_msg.rms, db_msg.peak, db_msg.channel_rms, db_msg.channel_peak);
                     }
                     else if msg_type == messages::MsgType::MSG_DEVICES_LIST as i32
                     {
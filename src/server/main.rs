@@ -3,42 +3,68 @@ use std::net::{TcpListener, TcpStream};
 use std::io::prelude::*;
 use std::{thread, time};
 
-use std::time::{Duration, Instant};
-
-mod messages;
-
-use messages::Serializable;
-use messages::MsgType;
+use std::time::Instant;
 
 extern crate raa;
 use raa::analysis;
+use raa::server::messages;
+use messages::Serializable;
+use messages::MsgType;
 use raa::analysis::traits::Sourcable;
+use raa::analysis::traits::Chainable;
+use raa::analysis::parameters::ParameterValue;
+use raa::analysis::device::SourceConfig;
 
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf, Component};
+
 
+// Directory clients can play WAV files from. Without it set, clients can't play files at all.
+const MEDIA_DIR_VARIABLE: &str = "RAA_MEDIA_DIR";
 
-fn handle_client(stream: TcpStream) {
-    // ...
+// Devices the clients can choose from.
+// `generation` is bumped whenever the devices change, so that each client knows to send the new list.
+struct DeviceList {
+    generation: u64,
+    devices: HashMap<String, analysis::device::DeviceInfo>,
 }
 
-fn send_devices(mut stream: &TcpStream) -> Result<usize, std::io::Error>
+fn list_devices() -> HashMap<String, analysis::device::DeviceInfo>
 {
-    let mut devices = analysis::soundio_source::SoundioSource::get_devices();
-    let mut device_msg = messages::MsgDevicesList::new();
-    device_msg.devices = devices.unwrap();
+    let mut devices = analysis::soundio_source::SoundioSource::get_devices().unwrap();
+    devices.extend(analysis::generator_source::GeneratorSource::get_devices().unwrap());
+    devices
+}
 
-    let mut serialized = device_msg.serialize();
-    stream.write(serialized.as_mut_slice())
+// Reads the devices again, returning true if they changed since the last time.
+// libsoundio's devices come from the list its device thread keeps, so clients asking for devices don't wait on libsoundio.
+fn refresh_devices(device_list: &Arc<RwLock<DeviceList>>) -> bool
+{
+    let devices = list_devices();
+
+    let mut device_list_borrow = device_list.write().unwrap();
+    if device_list_borrow.devices == devices
+    {
+        return false;
+    }
+
+    println!("Devices changed: {:?}", devices);
+    device_list_borrow.devices = devices;
+    device_list_borrow.generation += 1;
+    true
 }
 
-fn send_test_error(mut stream: &TcpStream) -> Result<usize, std::io::Error>
+// Sends the current devices, returning the generation of the list that was sent.
+fn send_devices(mut stream: &TcpStream, device_list: &Arc<RwLock<DeviceList>>) -> Result<u64, std::io::Error>
 {
-    let mut error_msg = messages::MsgError::new();
-    error_msg.message = "horror".to_string();
+    let device_list_borrow = device_list.read().unwrap();
+    let mut device_msg = messages::MsgDevicesList::new();
+    device_msg.devices = device_list_borrow.devices.clone();
 
-    let mut serialized = error_msg.serialize();
-    stream.write(serialized.as_mut_slice())
+    let mut serialized = device_msg.serialize();
+    stream.write(serialized.as_mut_slice()).map(|_| device_list_borrow.generation)
 }
 
 fn send_error(mut stream: &TcpStream, error: String) -> Result<usize, std::io::Error>
@@ -50,203 +76,664 @@ fn send_error(mut stream: &TcpStream, error: String) -> Result<usize, std::io::E
     stream.write(serialized.as_mut_slice())
 }
 
-fn send_rms_msg(mut stream: &TcpStream, rms: f32) -> Result<usize, std::io::Error>
+// Path of a file in the media directory. Only names relative to it are accepted, and they must not lead out of it.
+fn media_path(name: &str) -> Result<PathBuf, String>
+{
+    let directory = match std::env::var(MEDIA_DIR_VARIABLE)
+    {
+        Ok(directory) => directory,
+        Err(_) => return Err(format!("Playing files is disabled, {} is not set on the server", MEDIA_DIR_VARIABLE)),
+    };
+
+    let relative = Path::new(name);
+    let plain = relative.components().all(|component| matches!(component, Component::Normal(_)));
+    if name.is_empty() || !plain
+    {
+        return Err(format!("Invalid file name: {}", name));
+    }
+
+    // Resolve symbolic links too, so that a link in the directory can't point out of it.
+    let directory = Path::new(&directory).canonicalize().map_err(|e| format!("Media directory unavailable: {}", e))?;
+    let path = directory.join(relative).canonicalize().map_err(|_| format!("No such file: {}", name))?;
+    if !path.starts_with(&directory)
+    {
+        return Err(format!("Invalid file name: {}", name));
+    }
+    Ok(path)
+}
+
+// Device IDs starting with "file:" are played back from a WAV file in the media directory at the file's own pace, looping.
+// Device IDs like "generator:sine" synthesize a test signal on each requested channel.
+// Files play at their own sample rate, other sources use the given config.
+fn create_source(device_id: String, channels: Vec<i32>, config: SourceConfig) -> Result<Arc<RwLock<dyn Sourcable>>, String>
+{
+    if let Some(name) = device_id.strip_prefix("generator:")
+    {
+        match analysis::generator_source::Waveform::from_name(name)
+        {
+            Some(waveform) => {
+                let mut signals = Vec::new();
+                for i in 0..channels.len()
+                {
+                    let mut signal = analysis::generator_source::Signal::new(waveform);
+                    signal.amplitude = 0.5;
+                    signal.seed = i as u32 + 1;
+                    signals.push(signal);
+                }
+                let mut source = analysis::generator_source::GeneratorSource::new(signals)?;
+                if config.sample_rate > 0
+                {
+                    source.set_sample_rate(config.sample_rate as u32)?;
+                }
+                if config.frames > 0
+                {
+                    source.set_block_size(config.frames as usize)?;
+                }
+                return Ok(Arc::new(RwLock::new(source)));
+            },
+            None => println!("Unknown generator: {}", device_id),
+        }
+    }
+
+    if let Some(name) = device_id.strip_prefix("file:")
+    {
+        let path = media_path(name)?;
+        let mut source = analysis::wav_source::WavSource::new(path.to_string_lossy().into_owned(), channels);
+        source.set_playback_mode(analysis::wav_source::PlaybackMode::Paced);
+        source.set_looping(true);
+        return Ok(Arc::new(RwLock::new(source)));
+    }
+
+    let mut source = analysis::soundio_source::SoundioSource::new(device_id, channels);
+    source.set_config(config);
+    Ok(Arc::new(RwLock::new(source)))
+}
+
+// Sources are shared between all clients that ask for the same device, channels and config.
+// `sources` maps those to the source's ID in the arena.
+fn acquire_source(arena: &Arc<RwLock<analysis::analysis::Arena>>, sources: &mut HashMap<String, u64>,
+                  device_id: String, channels: Vec<i32>, config: SourceConfig) -> Result<u64, String>
+{
+    let key = format!("{} {:?} {:?}", device_id, channels, config);
+    if let Some(&id) = sources.get(&key)
+    {
+        if arena.read().unwrap().sourcables.contains_key(&id)
+        {
+            println!("Sharing source {} for {}", id, key);
+            return Ok(id);
+        }
+    }
+
+    let source = create_source(device_id, channels, config)?;
+    let id = arena.write().unwrap().add_sourcable(source);
+    sources.insert(key, id);
+    Ok(id)
+}
+
+// Removes the source from the arena once no chain is attached to it anymore.
+fn release_source(arena: &Arc<RwLock<analysis::analysis::Arena>>, sources: &mut HashMap<String, u64>, source_id: u64)
+{
+    let unused = match arena.read().unwrap().sourcables.get(&source_id)
+    {
+        Some(sourcable) => sourcable.read().unwrap().chains().is_empty(),
+        None => false,
+    };
+
+    if unused
+    {
+        println!("Removing sourcable {}..", source_id);
+        arena.write().unwrap().remove_sourcable(source_id);
+        sources.retain(|_, id| *id != source_id);
+    }
+}
+
+// Replaces the client's chain with a new one reading from the given device and running the given nodes.
+// If the device can't be used, the old chain is still stopped and the client is left without one.
+#[allow(clippy::too_many_arguments)]
+fn restart_chain(arena: &Arc<RwLock<analysis::analysis::Arena>>, shared_sources: &Arc<RwLock<HashMap<String, u64>>>,
+                 chain_ref: &mut Arc<RwLock<analysis::analysis::Chain>>, source_id: &mut Option<u64>,
+                 device_id: String, channels: Vec<i32>, config: SourceConfig, nodes: Vec<u64>) -> Result<(), String>
+{
+    let mut sources_borrow = shared_sources.write().unwrap();
+
+    println!("Stopping chain..");
+    chain_ref.write().unwrap().stop();
+
+    println!("Stopped chain!");
+    if let Some(id) = *source_id
+    {
+        release_source(arena, &mut sources_borrow, id);
+    }
+
+    *source_id = None;
+    *source_id = Some(acquire_source(arena, &mut sources_borrow, device_id, channels, config)?);
+
+    let mut chain = analysis::analysis::Chain::new(arena.clone());
+    chain.set_source(source_id.unwrap());
+    for node in nodes
+    {
+        chain.add_node(node);
+    }
+    println!("Created new chain!");
+
+    *chain_ref = Arc::new(RwLock::new(chain));
+    chain_ref.write().unwrap().start(chain_ref.clone());
+    println!("Started chain!\n");
+    Ok(())
+}
+
+// Sets a parameter of one of the client's nodes, addressed by name.
+fn set_parameter(arena: &Arc<RwLock<analysis::analysis::Arena>>, node_names: &HashMap<String, u64>,
+                 node: &str, parameter: &str, value: ParameterValue) -> Result<(), String>
+{
+    let id = match node_names.get(node)
+    {
+        Some(id) => *id,
+        None => return Err(format!("Unknown node {}", node)),
+    };
+
+    let arena_borrow = arena.read().unwrap();
+    let mut chainable = match arena_borrow.chainables.get(&id)
+    {
+        Some(chainable) => chainable.write().unwrap(),
+        None => return Err(format!("Node {} no longer exists", node)),
+    };
+
+    match chainable.parameters_mut()
+    {
+        Some(parameters) => parameters.set(parameter, value).map_err(|e| e.to_string()),
+        None => Err(format!("Node {} has no parameters", node)),
+    }
+}
+
+fn send_rms_msg(mut stream: &TcpStream, rms: &analysis::rms::RMS) -> Result<usize, std::io::Error>
 {
     let mut rms_msg = messages::MsgRMSPacket::new();
-    rms_msg.value = rms;
+    rms_msg.value = rms.overall();
+    rms_msg.channels = rms.rms().clone();
 
     let mut serialized = rms_msg.serialize();
     stream.write(serialized.as_mut_slice())
 }
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:50000").unwrap();
-    listener.set_nonblocking(true).expect("Cannot set non-blocking");
+fn send_db_msg(mut stream: &TcpStream, db: &analysis::db::DB) -> Result<usize, std::io::Error>
+{
+    let mut db_msg = messages::MsgDBPacket::new();
+    db_msg.rms = db.rms_db();
+    db_msg.peak = db.peak_db();
+    db_msg.channel_rms = db.channel_rms_db().clone();
+    db_msg.channel_peak = db.channel_peak_db().clone();
 
-    loop {
-        match listener.accept() {
-            Ok((mut stream, addr)) => {
-                let _ = stream.set_nodelay(true);
+    let mut serialized = db_msg.serialize();
+    stream.write(serialized.as_mut_slice())
+}
 
+fn send_peak_msg(mut stream: &TcpStream, peaks: Vec<analysis::peak::ChannelPeak>) -> Result<usize, std::io::Error>
+{
+    let mut peak_msg = messages::MsgPeakPacket::new();
+    peak_msg.channels = peaks;
 
-                thread::spawn(move || {
-                
-                    println!("new client: {:?}", addr);
-                    
-                    let _ = send_devices(&stream);
-                    //let _ = send_test_error(&stream);
+    let mut serialized = peak_msg.serialize();
+    stream.write(serialized.as_mut_slice())
+}
 
-                    // Ready an analysis chain to be used later on after a proper message has been received.
-                    let arena = analysis::analysis::Arena::new();
-                    let arena_rc = Arc::new(RwLock::new(arena));
-                    let mut chain = analysis::analysis::Chain::new(arena_rc.clone());
-                    let mut chain_ref = Arc::new(RwLock::new(chain));
+fn send_beat_msg(mut stream: &TcpStream, beat: &analysis::beat::Beat, beats: Vec<analysis::beat::BeatEvent>) -> Result<usize, std::io::Error>
+{
+    let mut beat_msg = messages::MsgBeatPacket::new();
+    beat_msg.bpm = beat.bpm();
+    beat_msg.confidence = beat.confidence();
+    beat_msg.phase = beat.phase();
+    beat_msg.time = beat.time();
+    beat_msg.next_beat = beat.next_beat_time().unwrap_or(-1.0);
+    beat_msg.beats = beats;
+
+    let mut serialized = beat_msg.serialize();
+    stream.write(serialized.as_mut_slice())
+}
+
+fn main() {
+    let listener = TcpListener::bind("127.0.0.1:50000").unwrap();
+    listener.set_nonblocking(true).expect("Cannot set non-blocking");
 
-                    let rms = Arc::new(RwLock::new(analysis::rms::RMS::new()));
-                    let rms_id = arena_rc.write().unwrap().add_chainable(rms);
+    // All clients share one arena, so that they can share sources too.
+    let arena_rc = Arc::new(RwLock::new(analysis::analysis::Arena::new()));
+    let shared_sources: Arc<RwLock<HashMap<String, u64>>> = Arc::new(RwLock::new(HashMap::new()));
+
+    let device_list = Arc::new(RwLock::new(DeviceList { generation: 0, devices: list_devices() }));
+
+    // Watch for devices being plugged in or removed, as libsoundio tells of them.
+    // Clients push the new list on their own threads.
+    {
+        let device_list = device_list.clone();
+        thread::spawn(move || {
+            loop {
+                analysis::soundio_source::SoundioSource::wait_for_device_changes();
+                refresh_devices(&device_list);
+            }
+        });
+    }
 
-                    let mut source_id = None;
+    loop {
+        // The listener doesn't block, so there's mostly no client waiting.
+        if let Ok((mut stream, addr)) = listener.accept()
+        {
+            let _ = stream.set_nodelay(true);
+
+            let arena_rc = arena_rc.clone();
+            let shared_sources = shared_sources.clone();
+            let device_list = device_list.clone();
+            thread::spawn(move || {
+            
+                println!("new client: {:?}", addr);
+                
+                // Generation of the device list the client has last been sent.
+                let mut devices_generation = send_devices(&stream, &device_list).unwrap_or_default();
+
+                // Ready an analysis chain to be used later on after a proper message has been received.
+                let mut chain_ref = Arc::new(RwLock::new(analysis::analysis::Chain::new(arena_rc.clone())));
+
+                // RMS packets are sent every 50 ms, each measuring the 50 ms before it whatever the buffer size.
+                // Clients can change the window, smoothing and ballistics through parameter messages.
+                let rms = Arc::new(RwLock::new(analysis::rms::RMS::new()));
+                let _ = rms.write().unwrap().set_window_ms(1000.0/20.0);
+                let rms_id = arena_rc.write().unwrap().add_chainable(rms.clone());
+
+                let db = Arc::new(RwLock::new(analysis::db::DB::new()));
+                let db_id = arena_rc.write().unwrap().add_chainable(db.clone());
+
+                let peak = Arc::new(RwLock::new(analysis::peak::Peak::new()));
+                let peak_id = arena_rc.write().unwrap().add_chainable(peak.clone());
+
+                let beat = Arc::new(RwLock::new(analysis::beat::Beat::new()));
+                let beat_id = arena_rc.write().unwrap().add_chainable(beat.clone());
+
+                // Gain applied before the analysis, adjustable through parameter messages.
+                let gain = Arc::new(RwLock::new(analysis::gain::Gain::new(0.0)));
+                let gain_id = arena_rc.write().unwrap().add_chainable(gain);
+
+                // Names the client uses to address the nodes in parameter messages.
+                let mut node_names: HashMap<String, u64> = HashMap::new();
+                node_names.insert("rms".to_string(), rms_id);
+                node_names.insert("db".to_string(), db_id);
+                node_names.insert("peak".to_string(), peak_id);
+                node_names.insert("beat".to_string(), beat_id);
+                node_names.insert("gain".to_string(), gain_id);
+
+                let mut source_id = None;
+                // Used for the sources of the streams the client starts.
+                let mut source_config = SourceConfig::new();
+
+                let mut send_rms = false;
+                let mut send_db = false;
+                let mut send_peak = false;
+                let mut send_beat = false;
+
+                // Cap to 20 outgoing messages per second
+                let mut sent_msg_instant = Instant::now();
+
+                // dB packets are sent at the rate the client asked for
+                let mut sent_db_instant = Instant::now();
+                let mut db_interval_mills = 1000/20;
+
+                // Peak packets too, except that clipping is sent as soon as it starts
+                let mut sent_peak_instant = Instant::now();
+                let mut peak_interval_mills = 1000/20;
+                // Whether the latest peak packet sent had clipping in it.
+                let mut peak_clipping_sent = false;
+
+                // Beat packets too, except that beats are sent as soon as they're tracked
+                let mut sent_beat_instant = Instant::now();
+                let mut beat_interval_mills = 1000/10;
+
+                // Buffer for whole message.
+                // Each message is prefixed by message length.
+                // As TCP is a streaming protocol, message size may vary.
+                // As such, only handle (and remove) a message when whole message is read.
+                let mut msg_buffer: Vec<u8> = Vec::new();
+
+                // Read stuff until error.
+                loop {
+                        let mut data = [0u8; 2048];
+                        match stream.read(&mut data)
+                        {
+                            Ok(read_bytes) => {
+                                if read_bytes == 0
+                                {
+                                    continue;
+                                }
 
-                    let mut send_rms = false;
+                                msg_buffer.extend(data[..read_bytes].iter().cloned());
 
-                    // Cap to 20 outgoing messages per second
-                    let mut sent_msg_instant = Instant::now();
+                                if msg_buffer.len() < 4
+                                {
+                                    continue;
+                                }
 
-                    // Buffer for whole message.
-                    // Each message is prefixed by message length.
-                    // As TCP is a streaming protocol, message size may vary.
-                    // As such, only handle (and remove) a message when whole message is read.
-                    let mut msg_buffer: Vec<u8> = Vec::new();
+                                // Length that the message should have
+                                let mut msg_length: i32 = msg_buffer[0] as i32 | ((msg_buffer[1] as i32) << 8) | ((msg_buffer[2] as i32)  << 16) | ((msg_buffer[3] as i32) << 24);
+                                
+                                println!("\nMessage length: {}", msg_length);
+                                println!("Buffer length: {}", msg_buffer.len());
 
-                    // Read stuff until error.
-                    loop {
-                            let mut data = [0u8; 2048];
-                            match stream.read(&mut data)
-                            {
-                                Ok(read_bytes) => {
-                                    if read_bytes <= 0
+                                // We have a full length message if buffer has msg_length bytes
+                                while msg_buffer.len() >= msg_length as usize
+                                {
+                                    // Remove the message's worth of bytes from the buffer.
+                                    println!("Buffer before: {}", msg_buffer.len());
+                                    let mut message_bytes: Vec<u8> = msg_buffer.drain(0..msg_length as usize).collect();
+                                    println!("Buffer after: {}", msg_buffer.len());
+                                    let msg_type = message_bytes[4] as i32 | ((message_bytes[5] as i32) << 8) | ((message_bytes[6] as i32)  << 16) | ((message_bytes[7] as i32) << 24);
+                                    println!("Message type: {}", msg_type);
+
+                                    if msg_type == MsgType::MSG_GET_DEVICES as i32
                                     {
-                                        continue;
+                                        refresh_devices(&device_list);
+                                        if let Ok(generation) = send_devices(&stream, &device_list)
+                                        {
+                                            devices_generation = generation;
+                                        }
                                     }
-
-                                    for i in 0..read_bytes
+                                    else if msg_type == MsgType::MSG_GET_RMS as i32
                                     {
-                                        msg_buffer.push(data[i]);
-                                    }
+                                        // Ignore length & type when passing message_bytes
+                                        println!("1");
+                                        let msg_length = message_bytes.len();
+                                        println!("2");
+                                        let messages_bytes_without_type = message_bytes.drain(8..msg_length).collect();
+                                        println!("Creating RMS msg..");
+                                        let rms_msg = messages::MsgStartStreamRMS::deserialized(messages_bytes_without_type);
+                                        println!("Device: {}", rms_msg.device_id);
+                                        println!("Channels: {:?}", rms_msg.channels);
+
+                                        if let Err(e) = restart_chain(&arena_rc, &shared_sources, &mut chain_ref, &mut source_id,
+                                                      rms_msg.device_id, rms_msg.channels, source_config.clone(), vec![gain_id, rms_id])
+                                        {
+                                            let _ = send_error(&stream, e);
+                                        }
 
-                                    if msg_buffer.len() < 4
-                                    {
-                                        continue;
+                                        send_rms = true;
+                                        send_db = false;
+                                        send_peak = false;
+                                        send_beat = false;
                                     }
+                                    else if msg_type == MsgType::MSG_CONFIGUREDB as i32
+                                    {
+                                        let msg_length = message_bytes.len();
+                                        let db_msg = messages::MsgConfigureDB::deserialized(message_bytes.drain(8..msg_length).collect());
+                                        println!("Device: {}", db_msg.device_id);
+                                        println!("Channels: {:?}", db_msg.channels);
+                                        println!("Floor: {} Reference: {} Rate: {}", db_msg.floor, db_msg.reference, db_msg.rate);
 
-                                    // Length that the message should have
-                                    let mut msg_length: i32 = msg_buffer[0] as i32 | ((msg_buffer[1] as i32) << 8) | ((msg_buffer[2] as i32)  << 16) | ((msg_buffer[3] as i32) << 24);
-                                    
-                                    println!("\nMessage length: {}", msg_length);
-                                    println!("Buffer length: {}", msg_buffer.len());
+                                        {
+                                            let mut db_borrow = db.write().unwrap();
+                                            if let Err(e) = db_borrow.set_floor(db_msg.floor).and(db_borrow.set_reference(db_msg.reference))
+                                            {
+                                                let _ = send_error(&stream, e.to_string());
+                                            }
+                                        }
+                                        db_interval_mills = if db_msg.rate > 0.0 { (1000.0 / db_msg.rate) as u64 } else { 1000/20 };
 
-                                    // We have a full length message if buffer has msg_length bytes
-                                    while msg_buffer.len() >= msg_length as usize
+                                        if let Err(e) = restart_chain(&arena_rc, &shared_sources, &mut chain_ref, &mut source_id,
+                                                      db_msg.device_id, db_msg.channels, source_config.clone(), vec![gain_id, db_id])
+                                        {
+                                            let _ = send_error(&stream, e);
+                                        }
+
+                                        send_rms = false;
+                                        send_db = true;
+                                        send_peak = false;
+                                        send_beat = false;
+                                    }
+                                    else if msg_type == MsgType::MSG_CONFIGURE_PEAK as i32
                                     {
-                                        // Remove the message's worth of bytes from the buffer.
-                                        println!("Buffer before: {}", msg_buffer.len());
-                                        let mut message_bytes: Vec<u8> = msg_buffer.drain(0..msg_length as usize).collect();
-                                        println!("Buffer after: {}", msg_buffer.len());
-                                        let msg_type = message_bytes[4] as i32 | ((message_bytes[5] as i32) << 8) | ((message_bytes[6] as i32)  << 16) | ((message_bytes[7] as i32) << 24);
-                                        println!("Message type: {}", msg_type);
-
-                                        if msg_type == MsgType::MSG_GET_RMS as i32
+                                        let msg_length = message_bytes.len();
+                                        let peak_msg = messages::MsgConfigurePeak::deserialized(message_bytes.drain(8..msg_length).collect());
+                                        println!("Device: {}", peak_msg.device_id);
+                                        println!("Channels: {:?}", peak_msg.channels);
+                                        println!("Hold: {} Decay: {} Clip level: {} Clip samples: {} Rate: {}",
+                                                 peak_msg.hold, peak_msg.decay, peak_msg.clip_level, peak_msg.clip_samples, peak_msg.rate);
+
                                         {
-                                            println!("Stopping RMS chain..");
-                                            chain_ref.write().unwrap().stop();
+                                            let mut peak_borrow = peak.write().unwrap();
+                                            let result = peak_borrow.set_hold_ms(peak_msg.hold)
+                                                .and(peak_borrow.set_decay(peak_msg.decay))
+                                                .and(peak_borrow.set_clip_level(peak_msg.clip_level))
+                                                .and(peak_borrow.set_clip_samples(peak_msg.clip_samples.max(0) as u32));
+                                            if let Err(e) = result
+                                            {
+                                                let _ = send_error(&stream, e.to_string());
+                                            }
+                                        }
+                                        peak_interval_mills = if peak_msg.rate > 0.0 { (1000.0 / peak_msg.rate) as u64 } else { 1000/20 };
+
+                                        if let Err(e) = restart_chain(&arena_rc, &shared_sources, &mut chain_ref, &mut source_id,
+                                                      peak_msg.device_id, peak_msg.channels, source_config.clone(), vec![gain_id, peak_id])
+                                        {
+                                            let _ = send_error(&stream, e);
+                                        }
 
-                                            println!("Stopped RMS chain!");
-                                            match source_id
+                                        send_rms = false;
+                                        send_db = false;
+                                        send_peak = true;
+                                        send_beat = false;
+                                    }
+                                    else if msg_type == MsgType::MSG_CONFIGURE_BEAT as i32
+                                    {
+                                        let msg_length = message_bytes.len();
+                                        let beat_msg = messages::MsgConfigureBeat::deserialized(message_bytes.drain(8..msg_length).collect());
+                                        println!("Device: {}", beat_msg.device_id);
+                                        println!("Channels: {:?}", beat_msg.channels);
+                                        println!("BPM: {} - {} Function: {} Rate: {}", beat_msg.min_bpm, beat_msg.max_bpm, beat_msg.function, beat_msg.rate);
+
+                                        {
+                                            let mut beat_borrow = beat.write().unwrap();
+                                            let result = match analysis::onset::DetectionFunction::from_id(beat_msg.function as u8)
                                             {
-                                                Some(id) => {
-                                                    println!("Removing sourcable..");
-                                                    match arena_rc.write() {
-                                                        Ok(mut rc) => (rc.remove_sourcable(id)),
-                                                        Err(e) => (println!("Could not write: {:?}", e))
-                                                    }
-                                                    println!("Removed sourcable!");
-                                                }
-                                                None => ()
+                                                Some(function) => beat_borrow.onset_mut().set_function(function).map_err(|e| e.to_string()),
+                                                None => Err(format!("Unknown detection function {}", beat_msg.function)),
                                             };
+                                            let result = result.and(beat_borrow.set_range(beat_msg.min_bpm, beat_msg.max_bpm).map_err(|e| e.to_string()));
+                                            if let Err(e) = result
+                                            {
+                                                let _ = send_error(&stream, e);
+                                            }
+                                        }
+                                        beat_interval_mills = if beat_msg.rate > 0.0 { (1000.0 / beat_msg.rate) as u64 } else { 1000/10 };
 
-                                            // Ignore length & type when passing message_bytes
-                                            println!("1");
-                                            let msg_length = message_bytes.len();
-                                            println!("2");
-                                            let messages_bytes_without_type = message_bytes.drain(8..msg_length).collect();
-                                            println!("Creating RMS msg..");
-                                            let rms_msg = messages::MsgStartStreamRMS::deserialized(messages_bytes_without_type);
-                                            println!("Device: {}", rms_msg.device_id);
-                                            println!("Channels: {:?}", rms_msg.channels);
-
-                                            let source = Arc::new(RwLock::new(analysis::soundio_source::SoundioSource::new(rms_msg.device_id, rms_msg.channels)));
-                                            source_id = Some(arena_rc.write().unwrap().add_sourcable(source));
-
-                                            chain = analysis::analysis::Chain::new(arena_rc.clone());
-                                            chain.set_source(source_id.unwrap());
-                                            chain.add_node(rms_id);
-                                            println!("Created new RMS chain!");
-
-                                            chain_ref = Arc::new(RwLock::new(chain));
-                                            chain_ref.write().unwrap().start(chain_ref.clone());
-                                            println!("Started RMS chain!\n");
-
-                                            send_rms = true;
+                                        if let Err(e) = restart_chain(&arena_rc, &shared_sources, &mut chain_ref, &mut source_id,
+                                                      beat_msg.device_id, beat_msg.channels, source_config.clone(), vec![gain_id, beat_id])
+                                        {
+                                            let _ = send_error(&stream, e);
                                         }
 
-                                        if msg_buffer.len() >= 4
+                                        send_rms = false;
+                                        send_db = false;
+                                        send_peak = false;
+                                        send_beat = true;
+                                    }
+                                    else if msg_type == MsgType::MSG_CONFIGURE_SOURCE as i32
+                                    {
+                                        let msg_length = message_bytes.len();
+                                        let configure_msg = messages::MsgConfigureSource::deserialized(message_bytes.drain(8..msg_length).collect());
+                                        println!("Source config: {:?}", configure_msg.config);
+                                        source_config = configure_msg.config;
+                                    }
+                                    else if msg_type == MsgType::MSG_SET_FLOAT_PARAM as i32
+                                    {
+                                        let msg_length = message_bytes.len();
+                                        let param_msg = messages::MsgSetFloatParam::deserialized(message_bytes.drain(8..msg_length).collect());
+                                        println!("Setting {}.{} to {}", param_msg.node, param_msg.parameter, param_msg.value);
+
+                                        match set_parameter(&arena_rc, &node_names, &param_msg.node, &param_msg.parameter, ParameterValue::Float(param_msg.value))
                                         {
-                                            msg_length = msg_buffer[0] as i32 | ((msg_buffer[1] as i32) << 8) | ((msg_buffer[2] as i32)  << 16) | ((msg_buffer[3] as i32) << 24);
+                                            Ok(()) => (),
+                                            Err(e) => { let _ = send_error(&stream, e); }
                                         }
                                     }
-                                },
-                                Err(e) => {
-                                    match e.kind() {
-                                        std::io::ErrorKind::WouldBlock => {},
-                                        _ => {
-                                            println!("Breaking.");
-                                            arena_rc.write().unwrap().remove_sourcable(source_id.unwrap());
-                                            arena_rc.write().unwrap().remove_chainable(rms_id);
-                                            break;
-                                        },
+                                    else if msg_type == MsgType::MSG_SET_BOOLEAN_PARAM as i32
+                                    {
+                                        let msg_length = message_bytes.len();
+                                        let param_msg = messages::MsgSetBooleanParam::deserialized(message_bytes.drain(8..msg_length).collect());
+                                        println!("Setting {}.{} to {}", param_msg.node, param_msg.parameter, param_msg.value);
+
+                                        match set_parameter(&arena_rc, &node_names, &param_msg.node, &param_msg.parameter, ParameterValue::Boolean(param_msg.value))
+                                        {
+                                            Ok(()) => (),
+                                            Err(e) => { let _ = send_error(&stream, e); }
+                                        }
                                     }
+
+                                    if msg_buffer.len() >= 4
+                                    {
+                                        msg_length = msg_buffer[0] as i32 | ((msg_buffer[1] as i32) << 8) | ((msg_buffer[2] as i32)  << 16) | ((msg_buffer[3] as i32) << 24);
+                                    }
+                                }
+                            },
+                            Err(e) => {
+                                match e.kind() {
+                                    std::io::ErrorKind::WouldBlock => {},
+                                    _ => {
+                                        println!("Breaking.");
+                                        break;
+                                    },
                                 }
                             }
-                            
-                            let elapsed_as_mills = sent_msg_instant.elapsed().as_secs() * 1000
-                                            + sent_msg_instant.elapsed().subsec_nanos() as u64 / 1000000;
-                            let arena_borrow = arena_rc.read().unwrap();
-                            match source_id
+                        }
+                        
+                        if device_list.read().unwrap().generation != devices_generation
+                        {
+                            match send_devices(&stream, &device_list)
                             {
-                                Some(id) =>
+                                Ok(generation) => devices_generation = generation,
+                                Err(e) => {
+                                    println!("Connection lost: {:?}", e);
+                                    break;
+                                }
+                            }
+                        }
+
+                        let elapsed_as_mills = sent_msg_instant.elapsed().as_secs() * 1000
+                                        + sent_msg_instant.elapsed().subsec_nanos() as u64 / 1000000;
+                        // The arena is only borrowed to take the error, not while sending it or sleeping,
+                        // so that other clients can add and remove their nodes in the meantime.
+                        let source_error = match source_id
+                        {
+                            Some(id) =>
+                            {
+                                let arena_borrow = arena_rc.read().unwrap();
+                                match arena_borrow.sourcables.get(&id)
                                 {
-                                    let sourcable = arena_borrow.sourcables[&id].read().unwrap();
-                                    
-                                    match sourcable.get_and_clear_error()
-                                    {
-                                        Some(error) => {
-                                            let _ = send_error(&stream, error);
-                                        }
-                                        None => ()
+                                    Some(sourcable) => sourcable.read().unwrap().get_and_clear_error(),
+                                    None => None
+                                }
+                            },
+                            None => None
+                        };
+                        if let Some(error) = source_error
+                        {
+                            let _ = send_error(&stream, error);
+                        }
+
+                        if send_rms && elapsed_as_mills > 1000/20
+                        {
+                            sent_msg_instant = Instant::now();
+
+                            let rms_borrow = rms.read().unwrap();
+                            if !rms_borrow.output().is_empty()
+                            {
+                                let error = send_rms_msg(&stream, &rms_borrow);
+                                match error
+                                {
+                                    Ok(_) => (),
+                                    Err(e) => {
+                                        println!("Connection lost: {:?}", e);
+                                        break;
                                     }
-                                },
-                                None => ()
+                                }
                             }
+                        }
 
-                            if send_rms == true && elapsed_as_mills > 1000/20
-                            {
-                                sent_msg_instant = Instant::now();
+                        let db_elapsed_as_mills = sent_db_instant.elapsed().as_secs() * 1000
+                                        + sent_db_instant.elapsed().subsec_nanos() as u64 / 1000000;
+                        if send_db && db_elapsed_as_mills >= db_interval_mills
+                        {
+                            sent_db_instant = Instant::now();
 
-                                if arena_borrow.chainables[&rms_id].read().unwrap().output().len() > 0
+                            let db_borrow = db.read().unwrap();
+                            if !db_borrow.output().is_empty()
+                            {
+                                match send_db_msg(&stream, &db_borrow)
                                 {
-                                    let error = send_rms_msg(&stream, arena_borrow.chainables[&rms_id].read().unwrap().output()[0]);
-                                    match error
-                                    {
-                                        Ok(_) => (),
-                                        Err(e) => {
-                                            println!("Connection lost: {:?}", e);
-                                            arena_rc.write().unwrap().remove_sourcable(source_id.unwrap());
-                                            arena_rc.write().unwrap().remove_chainable(rms_id);
-                                        }
+                                    Ok(_) => (),
+                                    Err(e) => {
+                                        println!("Connection lost: {:?}", e);
+                                        break;
                                     }
                                 }
                             }
+                        }
+
+                        let peak_elapsed_as_mills = sent_peak_instant.elapsed().as_secs() * 1000
+                                        + sent_peak_instant.elapsed().subsec_nanos() as u64 / 1000000;
+                        // Clipping is sent as soon as it starts. While it goes on, packets are sent at the configured rate.
+                        let clipping_started = !peak_clipping_sent && peak.read().unwrap().is_clipping();
+                        if send_peak && (peak_elapsed_as_mills >= peak_interval_mills || clipping_started)
+                        {
+                            sent_peak_instant = Instant::now();
+
+                            let mut peak_borrow = peak.write().unwrap();
+                            if !peak_borrow.output().is_empty()
+                            {
+                                let peaks = peak_borrow.take_peaks();
+                                peak_clipping_sent = peaks.iter().any(|channel| channel.clipping);
+                                match send_peak_msg(&stream, peaks)
+                                {
+                                    Ok(_) => (),
+                                    Err(e) => {
+                                        println!("Connection lost: {:?}", e);
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+
+                        let beat_elapsed_as_mills = sent_beat_instant.elapsed().as_secs() * 1000
+                                        + sent_beat_instant.elapsed().subsec_nanos() as u64 / 1000000;
+                        if send_beat
+                        {
+                            let mut beat_borrow = beat.write().unwrap();
+                            let beats = beat_borrow.take_beats();
+                            if !beat_borrow.output().is_empty() && (!beats.is_empty() || beat_elapsed_as_mills >= beat_interval_mills)
+                            {
+                                sent_beat_instant = Instant::now();
 
-                            let ten_millis = time::Duration::from_millis(10);
-                            thread::sleep(ten_millis);
-                    }
-                    println!("Stopped looping for data - client disconnected?");
-                    chain_ref.write().unwrap().stop();
-                });
-            },
-            Err(e) => {
-                //println!("Couldn't accept client connection, {:?}", e);
-            },
+                                match send_beat_msg(&stream, &beat_borrow, beats)
+                                {
+                                    Ok(_) => (),
+                                    Err(e) => {
+                                        println!("Connection lost: {:?}", e);
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+
+                        let ten_millis = time::Duration::from_millis(10);
+                        thread::sleep(ten_millis);
+                }
+                println!("Stopped looping for data - client disconnected?");
+                let mut sources_borrow = shared_sources.write().unwrap();
+                chain_ref.write().unwrap().stop();
+                if let Some(id) = source_id
+                {
+                    release_source(&arena_rc, &mut sources_borrow, id);
+                }
+                arena_rc.write().unwrap().remove_chainable(rms_id);
+                arena_rc.write().unwrap().remove_chainable(db_id);
+                arena_rc.write().unwrap().remove_chainable(peak_id);
+                arena_rc.write().unwrap().remove_chainable(beat_id);
+                arena_rc.write().unwrap().remove_chainable(gain_id);
+            });
         }
         let ten_millis = time::Duration::from_millis(10);
         thread::sleep(ten_millis);
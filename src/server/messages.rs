@@ -1,8 +1,12 @@
 use std::collections::HashMap;
-use std::mem::transmute;
 use std::str;
 
+use analysis::device::{DeviceInfo, ChannelLayout, SampleFormat, SourceConfig};
+use analysis::peak::ChannelPeak;
+use analysis::beat::BeatEvent;
+
 #[derive(Clone)]
+#[allow(non_camel_case_types)]
 pub enum MsgType {
     MSG_GET_RMS = 0,
     MSG_RMS_PACKET = 1,
@@ -13,6 +17,11 @@ pub enum MsgType {
     MSG_SET_BOOLEAN_PARAM = 6,
     MSG_CONFIGUREDB = 7,
     MSG_ERROR = 8,
+    MSG_CONFIGURE_SOURCE = 9,
+    MSG_CONFIGURE_PEAK = 10,
+    MSG_PEAK_PACKET = 11,
+    MSG_CONFIGURE_BEAT = 12,
+    MSG_BEAT_PACKET = 13,
 }
 
 pub trait Serializable {
@@ -20,6 +29,57 @@ pub trait Serializable {
 }
 
 
+// Reads a string prefixed with its length as u16, returning the string and the rest of the data.
+fn read_string(data: Vec<u8>) -> (String, Vec<u8>) {
+    let (length, data) = read_u16(data);
+    let string = str::from_utf8(&data[..length as usize]).unwrap().to_string();
+    (string, data[length as usize..].to_vec())
+}
+
+fn read_u16(data: Vec<u8>) -> (u16, Vec<u8>) {
+    (data[0] as u16 | ((data[1] as u16) << 8), data[2..].to_vec())
+}
+
+fn read_i32(data: Vec<u8>) -> (i32, Vec<u8>) {
+    let mut data_array = [0u8; 4];
+    data_array.copy_from_slice(&data[0..4]);
+    (i32::from_le_bytes(data_array), data[4..].to_vec())
+}
+
+fn read_f32(data: Vec<u8>) -> (f32, Vec<u8>) {
+    let mut data_array = [0u8; 4];
+    data_array.copy_from_slice(&data[0..4]);
+    (f32::from_le_bytes(data_array), data[4..].to_vec())
+}
+
+fn read_f64(data: Vec<u8>) -> (f64, Vec<u8>) {
+    let mut data_array = [0u8; 8];
+    data_array.copy_from_slice(&data[0..8]);
+    (f64::from_le_bytes(data_array), data[8..].to_vec())
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &String) {
+    write_u16(bytes, string.len() as u16);
+    bytes.extend(string.as_bytes());
+}
+
+fn write_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend(value.to_le_bytes().iter().cloned());
+}
+
+fn write_i32(bytes: &mut Vec<u8>, value: i32) {
+    bytes.extend(value.to_le_bytes().iter().cloned());
+}
+
+fn write_f32(bytes: &mut Vec<u8>, value: f32) {
+    bytes.extend(value.to_le_bytes().iter().cloned());
+}
+
+fn write_f64(bytes: &mut Vec<u8>, value: f64) {
+    bytes.extend(value.to_le_bytes().iter().cloned());
+}
+
+
 pub struct MsgGetDevices {
     pub msg_type: MsgType,
 }
@@ -30,9 +90,18 @@ impl MsgGetDevices {
     }
 }
 
+impl Default for MsgGetDevices {
+    fn default() -> MsgGetDevices {
+        MsgGetDevices::new()
+    }
+}
+
+/// Devices the client can start streams from, by device ID.
+/// Each device is sent as its ID, name and channel count. The rest of the `DeviceInfo` of each follows the whole list,
+/// in the same order, so that older clients can ignore it.
 pub struct MsgDevicesList {
     pub msg_type: MsgType,
-    pub devices: HashMap<String, (String, i32)>,
+    pub devices: HashMap<String, DeviceInfo>,
 }
 
 impl MsgDevicesList {
@@ -43,86 +112,134 @@ impl MsgDevicesList {
         }
     }
 
-    pub fn deserialized(mut data: Vec<u8>) -> MsgDevicesList {
-        let mut devices_list_msg = MsgDevicesList {
-            msg_type: MsgType::MSG_DEVICES_LIST,
-            devices: HashMap::new(),
-        };
-
-        let device_amount: i32 = data[0] as i32 | ((data[1] as i32) << 8) |
-                                 ((data[2] as i32) << 16) |
-                                 ((data[3] as i32) << 24);
+    pub fn deserialized(data: Vec<u8>) -> MsgDevicesList {
+        let mut devices_list_msg = MsgDevicesList::new();
 
+        let (device_amount, mut data) = read_i32(data);
         println!("Device amount: {}", device_amount);
 
-        data = data[4..].to_vec();
-
+        let mut devices = Vec::new();
         for _ in 0..device_amount {
-            let device_id_length: u16 = data[0] as u16 | ((data[1] as u16) << 8);
-            data = data[2..].to_vec();
-            println!("ID length: {}", device_id_length);
+            let (device_id, rest) = read_string(data);
+            let (device_name, rest) = read_string(rest);
+            let (device_channels, rest) = read_i32(rest);
+            println!("ID: {} Name: {} Channels: {}", device_id, device_name, device_channels);
 
-            let data_clone = data.clone();
-            let device_id = str::from_utf8(&data_clone[..device_id_length as usize]).unwrap();
-            println!("ID: {}", device_id);
+            devices.push((device_id, DeviceInfo::new(device_name, device_channels)));
+            data = rest;
+        }
 
-            data = data[device_id_length as usize..].to_vec();
+        for (device_id, mut info) in devices {
+            // Servers from before the extended descriptions only send the list.
+            if data.is_empty()
+            {
+                devices_list_msg.devices.insert(device_id, info);
+                continue;
+            }
 
-            let device_name_length: u16 = data[0] as u16 | ((data[1] as u16) << 8);
-            data = data[2..].to_vec();
-            println!("Name length: {}", device_name_length);
+            let rest = data;
+            info.is_default = rest[0] != 0;
+            info.is_raw = rest[1] != 0;
+            let (backend, rest) = read_string(rest[2..].to_vec());
+            info.backend = backend;
+            let (default_sample_rate, rest) = read_i32(rest);
+            info.default_sample_rate = default_sample_rate;
 
-            let data_clone = data.clone();
-            let device_name = str::from_utf8(&data_clone[..device_name_length as usize]).unwrap();
-            println!("Name: {}", device_name);
+            let (range_amount, mut rest) = read_u16(rest);
+            for _ in 0..range_amount {
+                let (min, remaining) = read_i32(rest);
+                let (max, remaining) = read_i32(remaining);
+                info.sample_rates.push((min, max));
+                rest = remaining;
+            }
 
-            data = data[device_name_length as usize..].to_vec();
+            let (format_amount, rest) = read_u16(rest);
+            // Formats this side doesn't know about are left out.
+            info.formats = rest[..format_amount as usize].iter().filter_map(|&id| SampleFormat::from_id(id)).collect();
+            let rest = rest[format_amount as usize..].to_vec();
 
-            let device_channels = data[0] as i32 | ((data[1] as i32) << 8) |
-                                  ((data[2] as i32) << 16) |
-                                  ((data[3] as i32) << 24);
-            data = data[4..].to_vec();
-            println!("Channels: {}", device_channels);
+            let (layout_amount, mut rest) = read_u16(rest);
+            info.layouts = Vec::new();
+            for _ in 0..layout_amount {
+                let (name, remaining) = read_string(rest);
+                let (channel_amount, mut remaining) = read_u16(remaining);
+                let mut channels = Vec::new();
+                for _ in 0..channel_amount {
+                    let (channel, after_channel) = read_string(remaining);
+                    channels.push(channel);
+                    remaining = after_channel;
+                }
+                info.layouts.push(ChannelLayout { name, channels });
+                rest = remaining;
+            }
+
+            let (latency_min, rest) = read_f64(rest);
+            let (latency_max, rest) = read_f64(rest);
+            info.latency = (latency_min, latency_max);
+
+            devices_list_msg.devices.insert(device_id, info);
+            data = rest;
         }
 
         devices_list_msg
     }
 }
 
+impl Default for MsgDevicesList {
+    fn default() -> MsgDevicesList {
+        MsgDevicesList::new()
+    }
+}
+
 impl Serializable for MsgDevicesList {
     fn serialize(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
 
-        let type_bytes: [u8; 4] = unsafe { transmute((self.msg_type.clone() as i32).to_le()) };
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
 
-        let device_count_bytes: [u8; 4] = unsafe { transmute((self.devices.len() as i32).to_le()) };
+        let device_count_bytes = (self.devices.len() as i32).to_le_bytes();
 
         let mut device_bytes = Vec::new();
 
-        for (id, name_and_channels) in &self.devices {
-            let id_length: [u8; 2] =
-                unsafe { transmute((id.as_bytes().len() as u16).to_le() as u16) };
-            device_bytes.extend(id_length.iter().cloned());
-            device_bytes.extend(id.as_bytes());
-            println!("Serializing device, ID: {}",
-                     id);
-            println!("ID bytes: {:?}", id_length);
-
-            let name_length: [u8; 2] =
-                unsafe { transmute((name_and_channels.0.as_bytes().len() as u16).to_le() as u16) };
-            device_bytes.extend(name_length.iter().cloned());
-            device_bytes.extend(name_and_channels.0.as_bytes());
-            println!("Name: {} Length: {}",
-                     name_and_channels.0,
-                     name_and_channels.0.as_bytes().len());
-            println!("Name bytes: {:?}", name_length);
-
-            let channel_bytes: [u8; 4] = unsafe { transmute(name_and_channels.1.to_le()) };
-            device_bytes.extend(channel_bytes.iter().cloned());
+        let devices: Vec<(&String, &DeviceInfo)> = self.devices.iter().collect();
+        for &(id, info) in devices.iter() {
+            println!("Serializing device, ID: {} Name: {}", id, info.name);
+            write_string(&mut device_bytes, id);
+            write_string(&mut device_bytes, &info.name);
+            write_i32(&mut device_bytes, info.channels);
+        }
+
+        for &(_, info) in devices.iter() {
+            device_bytes.push(if info.is_default { 1 } else { 0 });
+            device_bytes.push(if info.is_raw { 1 } else { 0 });
+            write_string(&mut device_bytes, &info.backend);
+            write_i32(&mut device_bytes, info.default_sample_rate);
+
+            write_u16(&mut device_bytes, info.sample_rates.len() as u16);
+            for &(min, max) in info.sample_rates.iter() {
+                write_i32(&mut device_bytes, min);
+                write_i32(&mut device_bytes, max);
+            }
+
+            write_u16(&mut device_bytes, info.formats.len() as u16);
+            for format in info.formats.iter() {
+                device_bytes.push(*format as u8);
+            }
+
+            write_u16(&mut device_bytes, info.layouts.len() as u16);
+            for layout in info.layouts.iter() {
+                write_string(&mut device_bytes, &layout.name);
+                write_u16(&mut device_bytes, layout.channels.len() as u16);
+                for channel in layout.channels.iter() {
+                    write_string(&mut device_bytes, channel);
+                }
+            }
+
+            write_f64(&mut device_bytes, info.latency.0);
+            write_f64(&mut device_bytes, info.latency.1);
         }
 
-        let length_bytes: [u8; 4] =
-            unsafe { transmute(((4 + 4 + 4 + device_bytes.len()) as i32).to_le()) };
+        let length_bytes = ((4 + 4 + 4 + device_bytes.len()) as i32).to_le_bytes();
         bytes.extend(length_bytes.iter().cloned());
         bytes.extend(type_bytes.iter().cloned());
         bytes.extend(device_count_bytes.iter().cloned());
@@ -190,13 +307,18 @@ impl MsgStartStreamRMS {
     }
 }
 
+impl Default for MsgStartStreamRMS {
+    fn default() -> MsgStartStreamRMS {
+        MsgStartStreamRMS::new()
+    }
+}
+
 impl Serializable for MsgStartStreamRMS {
     fn serialize(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
 
-        let type_bytes: [u8; 4] = unsafe { transmute((self.msg_type.clone() as i32).to_le()) };
-        let id_length: [u8; 2] =
-            unsafe { transmute((self.device_id.as_bytes().len() as u16).to_le() as u16) };
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let id_length = (self.device_id.len() as u16).to_le_bytes();
         let mut device_bytes = Vec::new();
         device_bytes.extend(id_length.iter().cloned());
         device_bytes.extend(self.device_id.as_bytes());
@@ -204,19 +326,17 @@ impl Serializable for MsgStartStreamRMS {
         // Device amount - make it possible to define multiple devices. For now, just 1 device supported.
 
         let mut channels_bytes = Vec::new();
-        for i in 0..self.channels.len() {
-            let channel_bytes: [u8; 4] = unsafe { transmute(self.channels[i].to_le()) };
-            channels_bytes.extend(channel_bytes.iter().cloned());
+        for channel in self.channels.iter() {
+            write_i32(&mut channels_bytes, *channel);
         }
 
-        let length_bytes: [u8; 4] =
-            unsafe { transmute((4 + 4 + 4 + device_bytes.len() as i32 + 4 + channels_bytes.len() as i32).to_le()) };
+        let length_bytes = (4 + 4 + 4 + device_bytes.len() as i32 + 4 + channels_bytes.len() as i32).to_le_bytes();
         bytes.extend(length_bytes.iter().cloned());
         bytes.extend(type_bytes.iter().cloned());
-        let device_count: [u8; 4] = unsafe { transmute((1 as i32).to_le()) };
+        let device_count = 1i32.to_le_bytes();
         bytes.extend(device_count.iter().cloned());
         bytes.extend(device_bytes.iter().cloned());
-        let channel_count: [u8; 4] = unsafe { transmute((self.channels.len() as i32).to_le()) };
+        let channel_count = (self.channels.len() as i32).to_le_bytes();
         bytes.extend(channel_count.iter().cloned());
         bytes.extend(channels_bytes.iter().cloned());
 
@@ -224,9 +344,38 @@ impl Serializable for MsgStartStreamRMS {
     }
 }
 
+// Levels of each channel are sent after the values older clients read, so that those can ignore them.
+fn read_channels(data: Vec<u8>) -> (Vec<f32>, Vec<u8>) {
+    let mut channels = Vec::new();
+    if data.len() < 4
+    {
+        return (channels, data);
+    }
+
+    let (channel_count, mut data) = read_i32(data);
+    for _ in 0..channel_count
+    {
+        let (value, rest) = read_f32(data);
+        channels.push(value);
+        data = rest;
+    }
+    (channels, data)
+}
+
+fn write_channels(bytes: &mut Vec<u8>, channels: &[f32]) {
+    write_i32(bytes, channels.len() as i32);
+    for value in channels.iter()
+    {
+        write_f32(bytes, *value);
+    }
+}
+
 pub struct MsgRMSPacket {
     pub msg_type: MsgType,
-    pub value: f32
+    /// RMS of all channels together.
+    pub value: f32,
+    /// RMS of each channel.
+    pub channels: Vec<f32>,
 }
 
 impl MsgRMSPacket {
@@ -234,37 +383,45 @@ impl MsgRMSPacket {
         MsgRMSPacket {
             msg_type: MsgType::MSG_RMS_PACKET,
             value: 0f32,
+            channels: Vec::new(),
         }
     }
 
     pub fn deserialized(mut data: Vec<u8>) -> MsgRMSPacket {
-        let mut start_msg = MsgRMSPacket {
-            msg_type: MsgType::MSG_RMS_PACKET,
-            value: 0f32,
-        };
+        let mut start_msg = MsgRMSPacket::new();
 
         let mut data_array = [0u8; 4];
         data_array[0] = data[0];
         data_array[1] = data[1];
         data_array[2] = data[2];
         data_array[3] = data[3];
-        start_msg.value = unsafe { transmute::<[u8; 4], f32>(data_array) };
+        start_msg.value = f32::from_le_bytes(data_array);
+        start_msg.channels = read_channels(data.split_off(4)).0;
 
         start_msg
     }
 }
 
+impl Default for MsgRMSPacket {
+    fn default() -> MsgRMSPacket {
+        MsgRMSPacket::new()
+    }
+}
+
 impl Serializable for MsgRMSPacket {
     fn serialize(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
 
-        let type_bytes: [u8; 4] = unsafe { transmute((self.msg_type.clone() as i32).to_le()) };
-        let value_bytes: [u8; 4] = unsafe { transmute(self.value as f32) };
-        let length_bytes: [u8; 4] = unsafe { transmute((4 + 4 + 4 as i32).to_le()) };
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let value_bytes = self.value.to_le_bytes();
+        let mut channels_bytes = Vec::new();
+        write_channels(&mut channels_bytes, &self.channels);
+        let length_bytes = (4 + 4 + 4 + channels_bytes.len() as i32).to_le_bytes();
 
         bytes.extend(length_bytes.iter().cloned());
         bytes.extend(type_bytes.iter().cloned());
         bytes.extend(value_bytes.iter().cloned());
+        bytes.extend(channels_bytes.iter().cloned());
 
         bytes
     }
@@ -301,14 +458,20 @@ impl MsgError {
     }
 }
 
+impl Default for MsgError {
+    fn default() -> MsgError {
+        MsgError::new()
+    }
+}
+
 impl Serializable for MsgError {
     fn serialize(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
 
-        let type_bytes: [u8; 4] = unsafe { transmute((self.msg_type.clone() as i32).to_le()) };
-        let message_length: [u8; 2] = unsafe { transmute ((self.message.as_bytes().len() as u16).to_le() as u16) };
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let message_length = (self.message.len() as u16).to_le_bytes();
         // Length: 4 from the length information (u32) itself, 2 from the length information of the error message, + error message length
-        let length_bytes: [u8; 4] = unsafe { transmute((4 + 4 + 2 + self.message.as_bytes().len() as i32).to_le()) };
+        let length_bytes = (4 + 4 + 2 + self.message.len() as i32).to_le_bytes();
 
         bytes.extend(length_bytes.iter().cloned());
         bytes.extend(type_bytes.iter().cloned());
@@ -317,4 +480,840 @@ impl Serializable for MsgError {
 
         bytes
     }
-}
\ No newline at end of file
+}
+
+/// Starts streaming dB packets of the given device and channels.
+pub struct MsgConfigureDB {
+    pub msg_type: MsgType,
+    pub device_id: String,
+    pub channels: Vec<i32>,
+    /// Lowest level reported, in dB.
+    pub floor: f32,
+    /// Level in dBFS that is reported as 0 dB.
+    pub reference: f32,
+    /// How many dB packets to send per second.
+    pub rate: f32,
+}
+
+impl MsgConfigureDB {
+    pub fn new() -> MsgConfigureDB {
+        MsgConfigureDB {
+            msg_type: MsgType::MSG_CONFIGUREDB,
+            device_id: "".to_string(),
+            channels: Vec::new(),
+            floor: -96.0,
+            reference: 0.0,
+            rate: 20.0,
+        }
+    }
+
+    pub fn deserialized(data: Vec<u8>) -> MsgConfigureDB {
+        let mut configure_msg = MsgConfigureDB::new();
+
+        let (device_id, data) = read_string(data);
+        configure_msg.device_id = device_id;
+
+        let (channel_count, mut data) = read_i32(data);
+        for _ in 0..channel_count
+        {
+            let (channel, rest) = read_i32(data);
+            configure_msg.channels.push(channel);
+            data = rest;
+        }
+
+        let (floor, data) = read_f32(data);
+        let (reference, data) = read_f32(data);
+        let (rate, _) = read_f32(data);
+        configure_msg.floor = floor;
+        configure_msg.reference = reference;
+        configure_msg.rate = rate;
+
+        configure_msg
+    }
+}
+
+impl Default for MsgConfigureDB {
+    fn default() -> MsgConfigureDB {
+        MsgConfigureDB::new()
+    }
+}
+
+impl Serializable for MsgConfigureDB {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let mut configure_bytes = Vec::new();
+        write_string(&mut configure_bytes, &self.device_id);
+        write_i32(&mut configure_bytes, self.channels.len() as i32);
+        for channel in self.channels.iter()
+        {
+            write_i32(&mut configure_bytes, *channel);
+        }
+        write_f32(&mut configure_bytes, self.floor);
+        write_f32(&mut configure_bytes, self.reference);
+        write_f32(&mut configure_bytes, self.rate);
+
+        let length_bytes = (4 + 4 + configure_bytes.len() as i32).to_le_bytes();
+        bytes.extend(length_bytes.iter().cloned());
+        bytes.extend(type_bytes.iter().cloned());
+        bytes.extend(configure_bytes.iter().cloned());
+
+        bytes
+    }
+}
+
+pub struct MsgDBPacket {
+    pub msg_type: MsgType,
+    /// Levels of all channels together.
+    pub rms: f32,
+    pub peak: f32,
+    /// Levels of each channel.
+    pub channel_rms: Vec<f32>,
+    pub channel_peak: Vec<f32>,
+}
+
+impl MsgDBPacket {
+    pub fn new() -> MsgDBPacket {
+        MsgDBPacket {
+            msg_type: MsgType::MSG_DB_PACKET,
+            rms: 0f32,
+            peak: 0f32,
+            channel_rms: Vec::new(),
+            channel_peak: Vec::new(),
+        }
+    }
+
+    pub fn deserialized(data: Vec<u8>) -> MsgDBPacket {
+        let mut db_msg = MsgDBPacket::new();
+
+        let mut data_array = [0u8; 4];
+        data_array.copy_from_slice(&data[0..4]);
+        db_msg.rms = f32::from_le_bytes(data_array);
+        data_array.copy_from_slice(&data[4..8]);
+        db_msg.peak = f32::from_le_bytes(data_array);
+
+        let (channel_rms, data) = read_channels(data[8..].to_vec());
+        let (channel_peak, _) = read_channels(data);
+        db_msg.channel_rms = channel_rms;
+        db_msg.channel_peak = channel_peak;
+
+        db_msg
+    }
+}
+
+impl Default for MsgDBPacket {
+    fn default() -> MsgDBPacket {
+        MsgDBPacket::new()
+    }
+}
+
+impl Serializable for MsgDBPacket {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let rms_bytes = self.rms.to_le_bytes();
+        let peak_bytes = self.peak.to_le_bytes();
+        let mut channels_bytes = Vec::new();
+        write_channels(&mut channels_bytes, &self.channel_rms);
+        write_channels(&mut channels_bytes, &self.channel_peak);
+        let length_bytes = (4 + 4 + 4 + 4 + channels_bytes.len() as i32).to_le_bytes();
+
+        bytes.extend(length_bytes.iter().cloned());
+        bytes.extend(type_bytes.iter().cloned());
+        bytes.extend(rms_bytes.iter().cloned());
+        bytes.extend(peak_bytes.iter().cloned());
+        bytes.extend(channels_bytes.iter().cloned());
+
+        bytes
+    }
+}
+
+pub struct MsgSetFloatParam {
+    pub msg_type: MsgType,
+    /// Name of the node in the client's chain, e.g. "db".
+    pub node: String,
+    pub parameter: String,
+    pub value: f32,
+}
+
+impl MsgSetFloatParam {
+    pub fn new() -> MsgSetFloatParam {
+        MsgSetFloatParam {
+            msg_type: MsgType::MSG_SET_FLOAT_PARAM,
+            node: "".to_string(),
+            parameter: "".to_string(),
+            value: 0f32,
+        }
+    }
+
+    pub fn deserialized(data: Vec<u8>) -> MsgSetFloatParam {
+        let mut param_msg = MsgSetFloatParam::new();
+
+        let (node, data) = read_string(data);
+        let (parameter, data) = read_string(data);
+        param_msg.node = node;
+        param_msg.parameter = parameter;
+
+        let mut data_array = [0u8; 4];
+        data_array.copy_from_slice(&data[0..4]);
+        param_msg.value = f32::from_le_bytes(data_array);
+
+        param_msg
+    }
+}
+
+impl Default for MsgSetFloatParam {
+    fn default() -> MsgSetFloatParam {
+        MsgSetFloatParam::new()
+    }
+}
+
+impl Serializable for MsgSetFloatParam {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let mut param_bytes = Vec::new();
+        write_string(&mut param_bytes, &self.node);
+        write_string(&mut param_bytes, &self.parameter);
+        let value_bytes = self.value.to_le_bytes();
+        param_bytes.extend(value_bytes.iter().cloned());
+
+        let length_bytes = (4 + 4 + param_bytes.len() as i32).to_le_bytes();
+        bytes.extend(length_bytes.iter().cloned());
+        bytes.extend(type_bytes.iter().cloned());
+        bytes.extend(param_bytes.iter().cloned());
+
+        bytes
+    }
+}
+
+pub struct MsgSetBooleanParam {
+    pub msg_type: MsgType,
+    /// Name of the node in the client's chain, e.g. "gain".
+    pub node: String,
+    pub parameter: String,
+    pub value: bool,
+}
+
+impl MsgSetBooleanParam {
+    pub fn new() -> MsgSetBooleanParam {
+        MsgSetBooleanParam {
+            msg_type: MsgType::MSG_SET_BOOLEAN_PARAM,
+            node: "".to_string(),
+            parameter: "".to_string(),
+            value: false,
+        }
+    }
+
+    pub fn deserialized(data: Vec<u8>) -> MsgSetBooleanParam {
+        let mut param_msg = MsgSetBooleanParam::new();
+
+        let (node, data) = read_string(data);
+        let (parameter, data) = read_string(data);
+        param_msg.node = node;
+        param_msg.parameter = parameter;
+        param_msg.value = data[0] != 0;
+
+        param_msg
+    }
+}
+
+impl Default for MsgSetBooleanParam {
+    fn default() -> MsgSetBooleanParam {
+        MsgSetBooleanParam::new()
+    }
+}
+
+impl Serializable for MsgSetBooleanParam {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let mut param_bytes = Vec::new();
+        write_string(&mut param_bytes, &self.node);
+        write_string(&mut param_bytes, &self.parameter);
+        param_bytes.push(if self.value { 1 } else { 0 });
+
+        let length_bytes = (4 + 4 + param_bytes.len() as i32).to_le_bytes();
+        bytes.extend(length_bytes.iter().cloned());
+        bytes.extend(type_bytes.iter().cloned());
+        bytes.extend(param_bytes.iter().cloned());
+
+        bytes
+    }
+}
+
+// Sent in place of a sample format to let the device pick its best one.
+const ANY_FORMAT: u8 = 255;
+
+/// Settings for the sources of the streams the client starts after this.
+pub struct MsgConfigureSource {
+    pub msg_type: MsgType,
+    pub config: SourceConfig,
+}
+
+impl MsgConfigureSource {
+    pub fn new() -> MsgConfigureSource {
+        MsgConfigureSource {
+            msg_type: MsgType::MSG_CONFIGURE_SOURCE,
+            config: SourceConfig::new(),
+        }
+    }
+
+    pub fn deserialized(data: Vec<u8>) -> MsgConfigureSource {
+        let mut configure_msg = MsgConfigureSource::new();
+
+        let (sample_rate, data) = read_i32(data);
+        configure_msg.config.sample_rate = sample_rate;
+        configure_msg.config.format = SampleFormat::from_id(data[0]);
+        let (frames, data) = read_i32(data[1..].to_vec());
+        configure_msg.config.frames = frames as u32;
+        let (latency, _) = read_f64(data);
+        configure_msg.config.latency = latency;
+
+        configure_msg
+    }
+}
+
+impl Default for MsgConfigureSource {
+    fn default() -> MsgConfigureSource {
+        MsgConfigureSource::new()
+    }
+}
+
+impl Serializable for MsgConfigureSource {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let mut config_bytes = Vec::new();
+        write_i32(&mut config_bytes, self.config.sample_rate);
+        config_bytes.push(match self.config.format
+        {
+            Some(format) => format as u8,
+            None => ANY_FORMAT,
+        });
+        write_i32(&mut config_bytes, self.config.frames as i32);
+        write_f64(&mut config_bytes, self.config.latency);
+
+        let length_bytes = (4 + 4 + config_bytes.len() as i32).to_le_bytes();
+        bytes.extend(length_bytes.iter().cloned());
+        bytes.extend(type_bytes.iter().cloned());
+        bytes.extend(config_bytes.iter().cloned());
+
+        bytes
+    }
+}
+
+/// Starts streaming peak packets of the given device and channels.
+pub struct MsgConfigurePeak {
+    pub msg_type: MsgType,
+    pub device_id: String,
+    pub channels: Vec<i32>,
+    /// How long the held peak stays, in milliseconds.
+    pub hold: f32,
+    /// How fast the held peak falls after that, in dB per second.
+    pub decay: f32,
+    /// Samples at or above this level in dBFS count as full scale.
+    pub clip_level: f32,
+    /// Amount of consecutive full-scale samples that counts as clipping.
+    pub clip_samples: i32,
+    /// How many peak packets to send per second. Clipping is sent right away when it starts.
+    pub rate: f32,
+}
+
+impl MsgConfigurePeak {
+    pub fn new() -> MsgConfigurePeak {
+        MsgConfigurePeak {
+            msg_type: MsgType::MSG_CONFIGURE_PEAK,
+            device_id: "".to_string(),
+            channels: Vec::new(),
+            hold: 1000.0,
+            decay: 20.0,
+            clip_level: -0.01,
+            clip_samples: 3,
+            rate: 20.0,
+        }
+    }
+
+    pub fn deserialized(data: Vec<u8>) -> MsgConfigurePeak {
+        let mut configure_msg = MsgConfigurePeak::new();
+
+        let (device_id, data) = read_string(data);
+        configure_msg.device_id = device_id;
+
+        let (channel_count, mut data) = read_i32(data);
+        for _ in 0..channel_count
+        {
+            let (channel, rest) = read_i32(data);
+            configure_msg.channels.push(channel);
+            data = rest;
+        }
+
+        let (hold, data) = read_f32(data);
+        let (decay, data) = read_f32(data);
+        let (clip_level, data) = read_f32(data);
+        let (clip_samples, data) = read_i32(data);
+        let (rate, _) = read_f32(data);
+        configure_msg.hold = hold;
+        configure_msg.decay = decay;
+        configure_msg.clip_level = clip_level;
+        configure_msg.clip_samples = clip_samples;
+        configure_msg.rate = rate;
+
+        configure_msg
+    }
+}
+
+impl Default for MsgConfigurePeak {
+    fn default() -> MsgConfigurePeak {
+        MsgConfigurePeak::new()
+    }
+}
+
+impl Serializable for MsgConfigurePeak {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let mut configure_bytes = Vec::new();
+        write_string(&mut configure_bytes, &self.device_id);
+        write_i32(&mut configure_bytes, self.channels.len() as i32);
+        for channel in self.channels.iter()
+        {
+            write_i32(&mut configure_bytes, *channel);
+        }
+        write_f32(&mut configure_bytes, self.hold);
+        write_f32(&mut configure_bytes, self.decay);
+        write_f32(&mut configure_bytes, self.clip_level);
+        write_i32(&mut configure_bytes, self.clip_samples);
+        write_f32(&mut configure_bytes, self.rate);
+
+        let length_bytes = (4 + 4 + configure_bytes.len() as i32).to_le_bytes();
+        bytes.extend(length_bytes.iter().cloned());
+        bytes.extend(type_bytes.iter().cloned());
+        bytes.extend(configure_bytes.iter().cloned());
+
+        bytes
+    }
+}
+
+/// Peak levels and clipping of each channel of the stream since the previous packet.
+pub struct MsgPeakPacket {
+    pub msg_type: MsgType,
+    pub channels: Vec<ChannelPeak>,
+}
+
+impl MsgPeakPacket {
+    pub fn new() -> MsgPeakPacket {
+        MsgPeakPacket {
+            msg_type: MsgType::MSG_PEAK_PACKET,
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn deserialized(data: Vec<u8>) -> MsgPeakPacket {
+        let mut peak_msg = MsgPeakPacket::new();
+
+        let (channel_count, mut data) = read_i32(data);
+        for _ in 0..channel_count
+        {
+            let (sample_peak, rest) = read_f32(data);
+            let (true_peak, rest) = read_f32(rest);
+            let (held_peak, rest) = read_f32(rest);
+            let (full_scale_run, rest) = read_i32(rest);
+            peak_msg.channels.push(ChannelPeak {
+                sample_peak,
+                true_peak,
+                held_peak,
+                full_scale_run: full_scale_run as u32,
+                clipping: rest[0] != 0,
+            });
+            data = rest[1..].to_vec();
+        }
+
+        peak_msg
+    }
+}
+
+impl Default for MsgPeakPacket {
+    fn default() -> MsgPeakPacket {
+        MsgPeakPacket::new()
+    }
+}
+
+impl Serializable for MsgPeakPacket {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let mut peak_bytes = Vec::new();
+        write_i32(&mut peak_bytes, self.channels.len() as i32);
+        for channel in self.channels.iter()
+        {
+            write_f32(&mut peak_bytes, channel.sample_peak);
+            write_f32(&mut peak_bytes, channel.true_peak);
+            write_f32(&mut peak_bytes, channel.held_peak);
+            write_i32(&mut peak_bytes, channel.full_scale_run as i32);
+            peak_bytes.push(if channel.clipping { 1 } else { 0 });
+        }
+
+        let length_bytes = (4 + 4 + peak_bytes.len() as i32).to_le_bytes();
+        bytes.extend(length_bytes.iter().cloned());
+        bytes.extend(type_bytes.iter().cloned());
+        bytes.extend(peak_bytes.iter().cloned());
+
+        bytes
+    }
+}
+
+/// Starts streaming beat packets of the given device and channels.
+pub struct MsgConfigureBeat {
+    pub msg_type: MsgType,
+    pub device_id: String,
+    pub channels: Vec<i32>,
+    /// Range of tempos to look for, in beats per minute.
+    pub min_bpm: f32,
+    pub max_bpm: f32,
+    /// ID of the onset detection function the beats are tracked from.
+    pub function: i32,
+    /// How many tempo packets to send per second. Beats are sent right away.
+    pub rate: f32,
+}
+
+impl MsgConfigureBeat {
+    pub fn new() -> MsgConfigureBeat {
+        MsgConfigureBeat {
+            msg_type: MsgType::MSG_CONFIGURE_BEAT,
+            device_id: "".to_string(),
+            channels: Vec::new(),
+            min_bpm: 60.0,
+            max_bpm: 200.0,
+            function: 2,
+            rate: 10.0,
+        }
+    }
+
+    pub fn deserialized(data: Vec<u8>) -> MsgConfigureBeat {
+        let mut configure_msg = MsgConfigureBeat::new();
+
+        let (device_id, data) = read_string(data);
+        configure_msg.device_id = device_id;
+
+        let (channel_count, mut data) = read_i32(data);
+        for _ in 0..channel_count
+        {
+            let (channel, rest) = read_i32(data);
+            configure_msg.channels.push(channel);
+            data = rest;
+        }
+
+        let (min_bpm, data) = read_f32(data);
+        let (max_bpm, data) = read_f32(data);
+        let (function, data) = read_i32(data);
+        let (rate, _) = read_f32(data);
+        configure_msg.min_bpm = min_bpm;
+        configure_msg.max_bpm = max_bpm;
+        configure_msg.function = function;
+        configure_msg.rate = rate;
+
+        configure_msg
+    }
+}
+
+impl Default for MsgConfigureBeat {
+    fn default() -> MsgConfigureBeat {
+        MsgConfigureBeat::new()
+    }
+}
+
+impl Serializable for MsgConfigureBeat {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let mut configure_bytes = Vec::new();
+        write_string(&mut configure_bytes, &self.device_id);
+        write_i32(&mut configure_bytes, self.channels.len() as i32);
+        for channel in self.channels.iter()
+        {
+            write_i32(&mut configure_bytes, *channel);
+        }
+        write_f32(&mut configure_bytes, self.min_bpm);
+        write_f32(&mut configure_bytes, self.max_bpm);
+        write_i32(&mut configure_bytes, self.function);
+        write_f32(&mut configure_bytes, self.rate);
+
+        let length_bytes = (4 + 4 + configure_bytes.len() as i32).to_le_bytes();
+        bytes.extend(length_bytes.iter().cloned());
+        bytes.extend(type_bytes.iter().cloned());
+        bytes.extend(configure_bytes.iter().cloned());
+
+        bytes
+    }
+}
+
+/// Tempo of the stream and the beats since the previous packet.
+/// Times are in seconds from when the source started, so clients can line the beats up with the next one predicted
+/// and with how far the stream is.
+pub struct MsgBeatPacket {
+    pub msg_type: MsgType,
+    pub bpm: f32,
+    pub confidence: f32,
+    /// How far the stream is into the current beat, from 0 to 1.
+    pub phase: f32,
+    /// Time of the latest audio analysed.
+    pub time: f64,
+    /// Predicted time of the next beat, negative if the tempo isn't known yet.
+    pub next_beat: f64,
+    pub beats: Vec<BeatEvent>,
+}
+
+impl MsgBeatPacket {
+    pub fn new() -> MsgBeatPacket {
+        MsgBeatPacket {
+            msg_type: MsgType::MSG_BEAT_PACKET,
+            bpm: 0.0,
+            confidence: 0.0,
+            phase: 0.0,
+            time: 0.0,
+            next_beat: -1.0,
+            beats: Vec::new(),
+        }
+    }
+
+    pub fn deserialized(data: Vec<u8>) -> MsgBeatPacket {
+        let mut beat_msg = MsgBeatPacket::new();
+
+        let (bpm, data) = read_f32(data);
+        let (confidence, data) = read_f32(data);
+        let (phase, data) = read_f32(data);
+        let (time, data) = read_f64(data);
+        let (next_beat, data) = read_f64(data);
+        beat_msg.bpm = bpm;
+        beat_msg.confidence = confidence;
+        beat_msg.phase = phase;
+        beat_msg.time = time;
+        beat_msg.next_beat = next_beat;
+
+        let (beat_count, mut data) = read_i32(data);
+        for _ in 0..beat_count
+        {
+            let (time, rest) = read_f64(data);
+            let (bpm, rest) = read_f32(rest);
+            let (confidence, rest) = read_f32(rest);
+            let (phase, rest) = read_f32(rest);
+            let (index, rest) = read_i32(rest);
+            beat_msg.beats.push(BeatEvent {
+                // Clients only need the time, the frame isn't sent.
+                frame: 0,
+                time,
+                bpm,
+                confidence,
+                phase,
+                index: index as u64,
+            });
+            data = rest;
+        }
+
+        beat_msg
+    }
+}
+
+impl Default for MsgBeatPacket {
+    fn default() -> MsgBeatPacket {
+        MsgBeatPacket::new()
+    }
+}
+
+impl Serializable for MsgBeatPacket {
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let type_bytes = (self.msg_type.clone() as i32).to_le_bytes();
+        let mut beat_bytes = Vec::new();
+        write_f32(&mut beat_bytes, self.bpm);
+        write_f32(&mut beat_bytes, self.confidence);
+        write_f32(&mut beat_bytes, self.phase);
+        write_f64(&mut beat_bytes, self.time);
+        write_f64(&mut beat_bytes, self.next_beat);
+        write_i32(&mut beat_bytes, self.beats.len() as i32);
+        for beat in self.beats.iter()
+        {
+            write_f64(&mut beat_bytes, beat.time);
+            write_f32(&mut beat_bytes, beat.bpm);
+            write_f32(&mut beat_bytes, beat.confidence);
+            write_f32(&mut beat_bytes, beat.phase);
+            write_i32(&mut beat_bytes, beat.index as i32);
+        }
+
+        let length_bytes = (4 + 4 + beat_bytes.len() as i32).to_le_bytes();
+        bytes.extend(length_bytes.iter().cloned());
+        bytes.extend(type_bytes.iter().cloned());
+        bytes.extend(beat_bytes.iter().cloned());
+
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the length the message starts with and returns the rest after the type, as the server reads it.
+    fn body(msg: &dyn Serializable) -> Vec<u8> {
+        let bytes = msg.serialize();
+        let (length, _) = read_i32(bytes.clone());
+        assert_eq!(length as usize, bytes.len());
+        bytes[8..].to_vec()
+    }
+
+    #[test]
+    fn devices_list_round_trips() {
+        let mut default_device = DeviceInfo::new("Built-in Microphone".to_string(), 2);
+        default_device.sample_rates = vec![(44_100, 44_100), (48_000, 96_000)];
+        default_device.default_sample_rate = 48_000;
+        default_device.formats = vec![SampleFormat::F32LE, SampleFormat::S16LE];
+        default_device.layouts = vec![
+            ChannelLayout { name: "Stereo".to_string(), channels: vec!["FrontLeft".to_string(), "FrontRight".to_string()] },
+            ChannelLayout::numbered(1),
+        ];
+        default_device.is_default = true;
+        default_device.latency = (0.005, 2.0);
+        default_device.backend = "PulseAudio".to_string();
+
+        let mut raw_device = DeviceInfo::new("USB Interface".to_string(), 8);
+        raw_device.is_raw = true;
+        raw_device.backend = "Alsa".to_string();
+
+        let mut msg = MsgDevicesList::new();
+        msg.devices.insert("default".to_string(), default_device);
+        msg.devices.insert("hw:1,0".to_string(), raw_device);
+
+        let read = MsgDevicesList::deserialized(body(&msg));
+        assert_eq!(read.devices, msg.devices);
+    }
+
+    #[test]
+    fn devices_list_reads_servers_without_descriptions() {
+        // Servers from before the descriptions only sent the amount of devices and each one's ID, name and channels.
+        let mut data = Vec::new();
+        write_i32(&mut data, 1);
+        write_string(&mut data, &"hw:0,0".to_string());
+        write_string(&mut data, &"Line In".to_string());
+        write_i32(&mut data, 2);
+
+        let read = MsgDevicesList::deserialized(data);
+        assert_eq!(read.devices["hw:0,0"], DeviceInfo::new("Line In".to_string(), 2));
+    }
+
+    #[test]
+    fn configure_db_round_trips() {
+        let mut msg = MsgConfigureDB::new();
+        msg.device_id = "hw:1,0".to_string();
+        msg.channels = vec![0, 3];
+        msg.floor = -120.0;
+        msg.reference = -18.0;
+        msg.rate = 30.0;
+
+        let read = MsgConfigureDB::deserialized(body(&msg));
+        assert_eq!(read.device_id, msg.device_id);
+        assert_eq!(read.channels, msg.channels);
+        assert_eq!(read.floor, msg.floor);
+        assert_eq!(read.reference, msg.reference);
+        assert_eq!(read.rate, msg.rate);
+    }
+
+    #[test]
+    fn db_packet_round_trips() {
+        let mut msg = MsgDBPacket::new();
+        msg.rms = -20.5;
+        msg.peak = -3.25;
+        msg.channel_rms = vec![-21.0, -20.0];
+        msg.channel_peak = vec![-4.0, -3.25];
+
+        let read = MsgDBPacket::deserialized(body(&msg));
+        assert_eq!(read.rms, msg.rms);
+        assert_eq!(read.peak, msg.peak);
+        assert_eq!(read.channel_rms, msg.channel_rms);
+        assert_eq!(read.channel_peak, msg.channel_peak);
+    }
+
+    #[test]
+    fn configure_peak_round_trips() {
+        let mut msg = MsgConfigurePeak::new();
+        msg.device_id = "hw:1,0".to_string();
+        msg.channels = vec![1];
+        msg.hold = 500.0;
+        msg.decay = 12.0;
+        msg.clip_level = -0.1;
+        msg.clip_samples = 5;
+        msg.rate = 25.0;
+
+        let read = MsgConfigurePeak::deserialized(body(&msg));
+        assert_eq!(read.device_id, msg.device_id);
+        assert_eq!(read.channels, msg.channels);
+        assert_eq!(read.hold, msg.hold);
+        assert_eq!(read.decay, msg.decay);
+        assert_eq!(read.clip_level, msg.clip_level);
+        assert_eq!(read.clip_samples, msg.clip_samples);
+        assert_eq!(read.rate, msg.rate);
+    }
+
+    #[test]
+    fn peak_packet_round_trips() {
+        let mut msg = MsgPeakPacket::new();
+        msg.channels = vec![
+            ChannelPeak { sample_peak: -6.0, true_peak: -5.5, held_peak: -5.5, full_scale_run: 0, clipping: false },
+            ChannelPeak { sample_peak: 0.0, true_peak: 0.7, held_peak: 0.7, full_scale_run: 12, clipping: true },
+        ];
+
+        let read = MsgPeakPacket::deserialized(body(&msg));
+        assert_eq!(read.channels, msg.channels);
+    }
+
+    #[test]
+    fn configure_beat_round_trips() {
+        let mut msg = MsgConfigureBeat::new();
+        msg.device_id = "hw:1,0".to_string();
+        msg.channels = vec![0, 1];
+        msg.min_bpm = 80.0;
+        msg.max_bpm = 160.0;
+        msg.function = 3;
+        msg.rate = 20.0;
+
+        let read = MsgConfigureBeat::deserialized(body(&msg));
+        assert_eq!(read.device_id, msg.device_id);
+        assert_eq!(read.channels, msg.channels);
+        assert_eq!(read.min_bpm, msg.min_bpm);
+        assert_eq!(read.max_bpm, msg.max_bpm);
+        assert_eq!(read.function, msg.function);
+        assert_eq!(read.rate, msg.rate);
+    }
+
+    #[test]
+    fn beat_packet_round_trips() {
+        let mut msg = MsgBeatPacket::new();
+        msg.bpm = 128.0;
+        msg.confidence = 0.75;
+        msg.phase = 0.25;
+        msg.time = 12.5;
+        msg.next_beat = 12.84375;
+        // The frame isn't sent, so it reads back as 0.
+        msg.beats = vec![
+            BeatEvent { frame: 0, time: 11.90625, bpm: 127.5, confidence: 0.7, phase: 0.0, index: 24 },
+            BeatEvent { frame: 0, time: 12.375, bpm: 128.0, confidence: 0.75, phase: -0.03125, index: 25 },
+        ];
+
+        let read = MsgBeatPacket::deserialized(body(&msg));
+        assert_eq!(read.bpm, msg.bpm);
+        assert_eq!(read.confidence, msg.confidence);
+        assert_eq!(read.phase, msg.phase);
+        assert_eq!(read.time, msg.time);
+        assert_eq!(read.next_beat, msg.next_beat);
+        assert_eq!(read.beats, msg.beats);
+    }
+}